@@ -0,0 +1,11 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+/// The clocks read on each recorded poll: the TSC at the start and end, and the monotonic
+/// clock at the end
+fn clocks(c: &mut Criterion) {
+    c.bench_function("tsc_now", |b| b.iter(pollcatch::__bench::tsc_now));
+    c.bench_function("nanotime", |b| b.iter(pollcatch::__bench::nanotime));
+}
+
+criterion_group!(benches, clocks);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Pretends that a profiling signal arrived during every poll, so every poll is recorded
+struct Sampled;
+
+impl Future for Sampled {
+    type Output = ();
+
+    fn poll(self: std::pin::Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        pollcatch::write_timestamp_pthread_key(1);
+        Poll::Pending
+    }
+}
+
+fn poll(c: &mut Criterion) {
+    pollcatch::enable_poll_timing(Box::new(std::io::sink())).unwrap();
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let mut future = pin!(pollcatch::PollTimingFuture::new(
+        std::future::pending::<()>()
+    ));
+    c.bench_function("poll", |b| {
+        b.iter(|| future.as_mut().poll(&mut cx));
+    });
+
+    let mut future = pin!(pollcatch::PollTimingFuture::new(Sampled));
+    c.bench_function("poll_recorded", |b| {
+        b.iter(|| future.as_mut().poll(&mut cx));
+    });
+}
+
+/// A future that completes right away, bare and wrapped, for the overhead of the wrapper on
+/// the whole life of a future rather than a single poll
+fn poll_ready(c: &mut Criterion) {
+    pollcatch::enable_poll_timing(Box::new(std::io::sink())).unwrap();
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut group = c.benchmark_group("poll_ready");
+    group.bench_function("bare", |b| {
+        b.iter(|| pin!(std::future::ready(())).poll(&mut cx));
+    });
+    group.bench_function("timed", |b| {
+        b.iter(|| pin!(pollcatch::PollTimingFuture::new(std::future::ready(()))).poll(&mut cx));
+    });
+    group.finish();
+}
+
+/// A service whose futures never complete
+struct Pending;
+
+impl Service<()> for Pending {
+    type Response = ();
+    type Error = ();
+    type Future = std::future::Pending<Result<(), ()>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        std::future::pending()
+    }
+}
+
+/// With a long poll span, every poll reads the monotonic clock twice, which is where the
+/// `vdso` feature makes a difference
+fn poll_long_poll_span(c: &mut Criterion) {
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut service = pollcatch::PollTimingLayer::new()
+        .with_long_poll_span(u64::MAX)
+        .layer(Pending);
+    let mut future = pin!(service.call(()));
+    c.bench_function("poll_long_poll_span", |b| {
+        b.iter(|| future.as_mut().poll(&mut cx));
+    });
+}
+
+criterion_group!(benches, poll, poll_ready, poll_long_poll_span);
+criterion_main!(benches);
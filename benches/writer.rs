@@ -0,0 +1,33 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+const EVENTS: u64 = 10_000;
+
+/// How fast the writer thread turns poll events into PR records, which bounds the rate of
+/// recorded polls it keeps up with
+fn write_events(c: &mut Criterion) {
+    let mut group = c.benchmark_group("writer");
+    group.throughput(Throughput::Elements(EVENTS));
+    group.bench_function("write_poll_events", |b| {
+        b.iter_batched(
+            || {
+                let (tx, rx) = std::sync::mpsc::channel();
+                for i in 0..EVENTS {
+                    tx.send(pollcatch::Event::Poll {
+                        start: i,
+                        end: i + 1000,
+                        clock_end: i + 500,
+                        tid: 1,
+                    })
+                    .unwrap();
+                }
+                rx
+            },
+            |rx| pollcatch::__bench::writer_fn(rx, Box::new(std::io::sink())).unwrap(),
+            BatchSize::SmallInput,
+        );
+    });
+    group.finish();
+}
+
+criterion_group!(benches, write_events);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+//! Feeds arbitrary bytes to `read_event` until it runs out of records or errors. Run with
+//! `cargo +nightly fuzz run read_event` from `decoder/`, starting from the seeds in
+//! `fuzz/corpus/read_event`.
+
+#![no_main]
+
+// the decoder is a binary crate, so the parser is compiled in directly
+#[allow(dead_code)]
+#[path = "../../src/pr_builder.rs"]
+mod pr_builder;
+#[allow(dead_code)]
+#[path = "../../src/pr_parser.rs"]
+mod pr_parser;
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut cursor = Cursor::new(data);
+    while let Ok(Some(_)) = pr_parser::read_event(&mut cursor) {}
+});
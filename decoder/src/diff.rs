@@ -0,0 +1,189 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::Duration,
+};
+
+use crate::{GroupStats, Sample, StackFrame};
+
+/// How the long polls of a stack changed from one recording to another
+pub struct StackDiff {
+    pub frames: Vec<StackFrame>,
+    pub before: Option<GroupStats>,
+    pub after: Option<GroupStats>,
+    /// The fraction of each recording the stack's long polls took
+    pub before_fraction: f64,
+    pub after_fraction: f64,
+}
+
+impl StackDiff {
+    /// How many times as much of the recording the stack took after as before, infinite for
+    /// stacks only in the later recording
+    pub fn factor(&self) -> f64 {
+        self.after_fraction / self.before_fraction
+    }
+}
+
+/// The fraction of a recording lasting `duration` that `total` is. Recordings without a
+/// duration count as lasting a second.
+fn fraction(total: Duration, duration: Duration) -> f64 {
+    if duration.is_zero() {
+        total.as_secs_f64()
+    } else {
+        total.as_secs_f64() / duration.as_secs_f64()
+    }
+}
+
+/// The stacks whose long polls took more of the recording after than before, normalized by
+/// the length of the recordings, by how much more, most first. A poll with several samples
+/// counts once, with the stack trace and duration of its latest sample.
+pub fn worse_stacks(
+    before: &[Sample],
+    before_duration: Duration,
+    after: &[Sample],
+    after_duration: Duration,
+) -> Vec<StackDiff> {
+    let mut stacks: BTreeMap<&[StackFrame], (Option<GroupStats>, Option<GroupStats>)> =
+        BTreeMap::new();
+    for (samples, is_after) in [(before, false), (after, true)] {
+        for sample in crate::latest_samples(samples) {
+            let (before, after) = stacks.entry(&sample.frames).or_default();
+            let stats = if is_after { after } else { before };
+            match stats {
+                Some(stats) => stats.add(sample.delta_t),
+                None => *stats = Some(GroupStats::new(sample.delta_t)),
+            }
+        }
+    }
+    let total = |stats: &Option<GroupStats>| stats.as_ref().map_or(Duration::ZERO, |s| s.total);
+    let mut diffs: Vec<_> = stacks
+        .into_iter()
+        .map(|(frames, (before, after))| StackDiff {
+            before_fraction: fraction(total(&before), before_duration),
+            after_fraction: fraction(total(&after), after_duration),
+            frames: frames.to_vec(),
+            before,
+            after,
+        })
+        .filter(|diff| diff.after_fraction > diff.before_fraction)
+        .collect();
+    diffs.sort_by(|a, b| {
+        let delta = |diff: &StackDiff| diff.after_fraction - diff.before_fraction;
+        delta(b).total_cmp(&delta(a))
+    });
+    diffs
+}
+
+pub fn print(out: &mut dyn Write, diffs: &[StackDiff], stack_depth: usize) -> io::Result<()> {
+    if diffs.is_empty() {
+        return writeln!(out, "no stack got worse");
+    }
+    let polls = |stats: &Option<GroupStats>| match stats {
+        Some(stats) => format!(
+            "{} poll(s) totaling {}us",
+            stats.count,
+            stats.total.as_micros()
+        ),
+        None => "none".to_owned(),
+    };
+    for diff in diffs {
+        let factor = match diff.factor() {
+            factor if factor.is_finite() => format!("{:.2}x", factor),
+            _ => "new".to_owned(),
+        };
+        writeln!(
+            out,
+            "+{:.3}% of the recording ({}): {} before, {} after",
+            100.0 * (diff.after_fraction - diff.before_fraction),
+            factor,
+            polls(&diff.before),
+            polls(&diff.after)
+        )?;
+        crate::print_frames(
+            out,
+            &diff.frames,
+            stack_depth,
+            &[],
+            crate::FrameStyle::default(),
+        )?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::worse_stacks;
+    use crate::{Sample, StackFrame};
+    use std::time::Duration;
+
+    fn sample(frame: &str, millis: u64) -> Sample {
+        sample_of_poll(frame, millis, None)
+    }
+
+    fn sample_of_poll(frame: &str, millis: u64, poll_start: Option<u64>) -> Sample {
+        Sample {
+            delta_t: Duration::from_millis(millis),
+            start_time: Duration::ZERO,
+            thread_id: 1,
+            thread_name: None,
+            session_id: None,
+            wall_time: None,
+            service_ready: false,
+            ready: false,
+            label: None,
+            poll_start,
+            parked: None,
+            frames: vec![StackFrame {
+                class_name: None,
+                name: Some(frame.to_owned()),
+            }],
+        }
+    }
+
+    #[test]
+    fn normalizes_by_recording_length() {
+        let before = vec![sample("same", 10), sample("slower", 10), sample("gone", 10)];
+        // twice as long, so `same` takes the same fraction of it
+        let after = vec![
+            sample("same", 10),
+            sample("same", 10),
+            sample("slower", 50),
+            sample("new", 1),
+        ];
+        let diffs = worse_stacks(
+            &before,
+            Duration::from_secs(1),
+            &after,
+            Duration::from_secs(2),
+        );
+        let names: Vec<_> = diffs
+            .iter()
+            .map(|diff| diff.frames[0].name.as_deref().unwrap())
+            .collect();
+        assert_eq!(names, ["slower", "new"]);
+        assert!((diffs[0].factor() - 2.5).abs() < 1e-9);
+        assert!(diffs[1].factor().is_infinite());
+    }
+
+    #[test]
+    fn counts_polls_not_samples() {
+        let before = vec![sample("slow", 10)];
+        // one poll sampled three times, lasting 30ms by its last sample
+        let after = vec![
+            sample_of_poll("slow", 10, Some(1)),
+            sample_of_poll("slow", 20, Some(1)),
+            sample_of_poll("slow", 30, Some(1)),
+        ];
+        let diffs = worse_stacks(
+            &before,
+            Duration::from_secs(1),
+            &after,
+            Duration::from_secs(1),
+        );
+        let after = diffs[0].after.as_ref().unwrap();
+        assert_eq!(after.count, 1);
+        assert_eq!(after.total, Duration::from_millis(30));
+        assert!((diffs[0].factor() - 3.0).abs() < 1e-9);
+    }
+}
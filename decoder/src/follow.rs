@@ -0,0 +1,333 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::pr_parser::{
+    self, CalibrationData, PossiblyUnknownEvent, ReadEventError, COMPRESSION_NONE, PR_MAGIC,
+    RING_MAGIC,
+};
+
+/// Reads the records appended to a growing PR file
+struct PrFollower {
+    reader: BufReader<File>,
+    /// The end of the last complete record
+    offset: u64,
+}
+
+impl PrFollower {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = [0; RING_MAGIC.len()];
+        let len = file.read(&mut header)?;
+        let offset = if header[..len].starts_with(&RING_MAGIC) {
+            anyhow::bail!("can't follow a ring-buffer PR file");
+        } else if header[..len].starts_with(&PR_MAGIC) && len > PR_MAGIC.len() {
+            if header[PR_MAGIC.len()] != COMPRESSION_NONE {
+                anyhow::bail!("can't follow a compressed PR file");
+            }
+            PR_MAGIC.len() as u64 + 1
+        } else {
+            0
+        };
+        Ok(PrFollower {
+            reader: BufReader::new(file),
+            offset,
+        })
+    }
+
+    /// Calls `f` with each complete record after the last one read. A partial record at the
+    /// end is left for the next call, once the writer has written the rest of it.
+    fn read_new(
+        &mut self,
+        mut f: impl FnMut(PossiblyUnknownEvent) -> io::Result<()>,
+    ) -> Result<(), ReadEventError> {
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+        loop {
+            match pr_parser::read_event(&mut self.reader) {
+                Ok(Some(event)) => {
+                    self.offset = self.reader.stream_position()?;
+                    f(event)?;
+                }
+                Ok(None) => return Ok(()),
+                Err(ReadEventError::Read(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Waits for writes to a file with inotify
+#[cfg(target_os = "linux")]
+struct Watcher {
+    fd: std::os::fd::OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn new(path: &Path) -> io::Result<Self> {
+        use std::os::{fd::FromRawFd, unix::ffi::OsStrExt};
+
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        // safety: plain syscalls, the fd is owned once it's valid
+        unsafe {
+            let fd = libc::inotify_init1(libc::IN_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = std::os::fd::OwnedFd::from_raw_fd(fd);
+            if libc::inotify_add_watch(
+                std::os::fd::AsRawFd::as_raw_fd(&fd),
+                path.as_ptr(),
+                libc::IN_MODIFY,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Watcher { fd })
+        }
+    }
+
+    fn wait(&self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            // safety: reading into a buffer of the given size
+            let n = unsafe {
+                libc::read(
+                    std::os::fd::AsRawFd::as_raw_fd(&self.fd),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                )
+            };
+            if n >= 0 {
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Waits for writes to a file with kqueue
+#[cfg(target_os = "macos")]
+struct Watcher {
+    kq: std::os::fd::OwnedFd,
+    /// kqueue watches open files rather than paths
+    _file: File,
+}
+
+#[cfg(target_os = "macos")]
+impl Watcher {
+    fn new(path: &Path) -> io::Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let file = File::open(path)?;
+        // safety: plain syscalls, the fd is owned once it's valid
+        unsafe {
+            let kq = libc::kqueue();
+            if kq < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let kq = std::os::fd::OwnedFd::from_raw_fd(kq);
+            let change = libc::kevent {
+                ident: file.as_raw_fd() as libc::uintptr_t,
+                filter: libc::EVFILT_VNODE,
+                flags: libc::EV_ADD | libc::EV_CLEAR,
+                fflags: libc::NOTE_WRITE | libc::NOTE_EXTEND,
+                data: 0,
+                udata: std::ptr::null_mut(),
+            };
+            if libc::kevent(
+                kq.as_raw_fd(),
+                &change,
+                1,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null(),
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Watcher { kq, _file: file })
+        }
+    }
+
+    fn wait(&self) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        // safety: an all-zero kevent is valid
+        let mut event: libc::kevent = unsafe { std::mem::zeroed() };
+        loop {
+            // safety: room for the one event asked for
+            let n = unsafe {
+                libc::kevent(
+                    self.kq.as_raw_fd(),
+                    std::ptr::null(),
+                    0,
+                    &mut event,
+                    1,
+                    std::ptr::null(),
+                )
+            };
+            if n >= 0 {
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Polls for writes elsewhere
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+struct Watcher;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+impl Watcher {
+    fn new(_path: &Path) -> io::Result<Self> {
+        Ok(Watcher)
+    }
+
+    fn wait(&self) -> io::Result<()> {
+        std::thread::sleep(Duration::from_millis(250));
+        Ok(())
+    }
+}
+
+/// What is needed from the earlier events of a PR file to print a poll
+#[derive(Default)]
+struct FollowState {
+    calibration: Option<CalibrationData>,
+    realtime_offset: Option<i64>,
+    event_pid: Option<u32>,
+}
+
+impl FollowState {
+    /// Updates the state with `event`, printing it if it's a poll of at least `min_length`
+    fn handle(
+        &mut self,
+        out: &mut dyn Write,
+        event: PossiblyUnknownEvent,
+        min_length: Duration,
+        pid: Option<u32>,
+        print: bool,
+    ) -> io::Result<()> {
+        let PossiblyUnknownEvent::Event(event) = event else {
+            return Ok(());
+        };
+        let (start, end, clock_end, tid, kind) = match event {
+            pr_parser::Event::CalibrateTscToMonotonic { data } => {
+                self.calibration = Some(data);
+                return Ok(());
+            }
+            // only durations are scaled here, so the epochs don't matter
+            pr_parser::Event::CpuInfo { tsc_hz, .. } => {
+                if self.calibration.is_none() {
+                    self.calibration = CalibrationData::from_tsc_hz(tsc_hz, 0, 0);
+                }
+                return Ok(());
+            }
+            pr_parser::Event::SessionStart { pid, .. } => {
+                *self = FollowState {
+                    event_pid: Some(pid),
+                    ..FollowState::default()
+                };
+                return Ok(());
+            }
+            pr_parser::Event::ProcessInfo { data } => {
+                self.event_pid = Some(data.pid);
+                return Ok(());
+            }
+            pr_parser::Event::WallClockAnchor {
+                monotonic_ns,
+                realtime_ns,
+                ..
+            } => {
+                self.realtime_offset = Some(realtime_ns.wrapping_sub(monotonic_ns) as i64);
+                return Ok(());
+            }
+            pr_parser::Event::Poll {
+                start,
+                end,
+                clock_end,
+                tid,
+            }
+            | pr_parser::Event::PollReady {
+                start,
+                end,
+                clock_end,
+                tid,
+            } => (start, end, clock_end, tid, "poll".to_owned()),
+            pr_parser::Event::ServicePollReady {
+                start,
+                end,
+                clock_end,
+                tid,
+            } => (start, end, clock_end, tid, "poll_ready".to_owned()),
+            pr_parser::Event::LabeledBlock {
+                start,
+                end,
+                clock_end,
+                tid,
+                label,
+            } => (start, end, clock_end, tid, format!("block {:?}", label)),
+            _ => return Ok(()),
+        };
+        if !print || (pid.is_some() && self.event_pid != pid) {
+            return Ok(());
+        }
+        let Some(calibration) = &self.calibration else {
+            tracing::warn!("got poll event but no calibration");
+            return Ok(());
+        };
+        let duration =
+            Duration::from_nanos(calibration.scale_src_duration_to_ref(end.saturating_sub(start)));
+        if duration < min_length {
+            return Ok(());
+        }
+        let clock_start = clock_end.saturating_sub(duration.as_nanos() as u64);
+        let time = match self.realtime_offset {
+            Some(offset) => humantime::format_rfc3339_micros(
+                UNIX_EPOCH + Duration::from_nanos(clock_start.wrapping_add_signed(offset)),
+            )
+            .to_string(),
+            None => format!("{:.6}", Duration::from_nanos(clock_start).as_secs_f64()),
+        };
+        writeln!(
+            out,
+            "[{}] thread {} - {} of {}us",
+            time,
+            tid,
+            kind,
+            duration.as_micros()
+        )?;
+        out.flush()
+    }
+}
+
+/// Prints the polls of at least `min_length` appended to `pr_file` from now on, like
+/// `tail -f`. Runs until interrupted.
+pub fn follow(
+    out: &mut dyn Write,
+    pr_file: &Path,
+    min_length: Duration,
+    pid: Option<u32>,
+) -> anyhow::Result<()> {
+    let watcher = Watcher::new(pr_file)?;
+    let mut follower = PrFollower::open(pr_file)?;
+    let mut state = FollowState::default();
+    // catch up silently, the polls so far were already printed from the JFR file
+    follower.read_new(|event| state.handle(out, event, min_length, pid, false))?;
+    loop {
+        watcher.wait()?;
+        follower.read_new(|event| state.handle(out, event, min_length, pid, true))?;
+    }
+}
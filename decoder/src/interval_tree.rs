@@ -0,0 +1,128 @@
+use std::ops::Range;
+
+/// A static interval tree of half-open `[start, end)` intervals, each on a key such as a
+/// thread id, for finding all the intervals of a key that contain a point. The intervals may
+/// overlap, e.g. nested polls.
+///
+/// The entries are sorted by key and start, and each key's run of entries is an implicit
+/// balanced binary search tree: the root of a subrange is its middle entry. `max_end` holds
+/// the largest end in the subtree rooted at each entry, which lets a lookup skip the
+/// subtrees that end before the point.
+pub(crate) struct IntervalTree<T> {
+    entries: Vec<Entry<T>>,
+    max_end: Vec<u64>,
+}
+
+struct Entry<T> {
+    key: u32,
+    start: u64,
+    end: u64,
+    value: T,
+}
+
+impl<T> IntervalTree<T> {
+    pub fn new(intervals: impl IntoIterator<Item = (u32, Range<u64>, T)>) -> Self {
+        let mut entries: Vec<_> = intervals
+            .into_iter()
+            .map(|(key, range, value)| Entry {
+                key,
+                start: range.start,
+                end: range.end,
+                value,
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry.key, entry.start));
+        let mut tree = IntervalTree {
+            max_end: vec![0; entries.len()],
+            entries,
+        };
+        let mut lo = 0;
+        while lo < tree.entries.len() {
+            let key = tree.entries[lo].key;
+            let hi = lo + tree.entries[lo..].partition_point(|entry| entry.key == key);
+            tree.fill_max_end(lo, hi);
+            lo = hi;
+        }
+        tree
+    }
+
+    /// Sets `max_end` for the subtree of `lo..hi`, returning its largest end
+    fn fill_max_end(&mut self, lo: usize, hi: usize) -> u64 {
+        if lo >= hi {
+            return 0;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let max_end = self
+            .fill_max_end(lo, mid)
+            .max(self.fill_max_end(mid + 1, hi))
+            .max(self.entries[mid].end);
+        self.max_end[mid] = max_end;
+        max_end
+    }
+
+    /// Returns the intervals of `key` that contain `point` with their starts, by start
+    pub fn containing(&self, key: u32, point: u64) -> Vec<(u64, &T)> {
+        let lo = self.entries.partition_point(|entry| entry.key < key);
+        let hi = lo + self.entries[lo..].partition_point(|entry| entry.key == key);
+        let mut found = Vec::new();
+        self.collect_containing(lo, hi, point, &mut found);
+        found
+    }
+
+    fn collect_containing<'a>(
+        &'a self,
+        lo: usize,
+        hi: usize,
+        point: u64,
+        found: &mut Vec<(u64, &'a T)>,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        if self.max_end[mid] <= point {
+            return;
+        }
+        self.collect_containing(lo, mid, point, found);
+        let entry = &self.entries[mid];
+        if entry.start <= point {
+            if point < entry.end {
+                found.push((entry.start, &entry.value));
+            }
+            // the entries after `mid` start no earlier
+            self.collect_containing(mid + 1, hi, point, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IntervalTree;
+
+    #[test]
+    fn containing() {
+        let tree = IntervalTree::new([
+            (1, 0..100, "outer"),
+            (1, 10..20, "inner"),
+            (1, 150..160, "later"),
+            (2, 0..1000, "other thread"),
+        ]);
+        assert_eq!(tree.containing(1, 15), vec![(0, &"outer"), (10, &"inner")]);
+        // the latest poll to start before 50 doesn't contain it, but an earlier one does
+        assert_eq!(tree.containing(1, 50), vec![(0, &"outer")]);
+        assert_eq!(tree.containing(1, 120), vec![]);
+        assert_eq!(tree.containing(1, 150), vec![(150, &"later")]);
+        assert_eq!(tree.containing(1, 160), vec![]);
+        assert_eq!(tree.containing(2, 120), vec![(0, &"other thread")]);
+        assert_eq!(tree.containing(3, 0), vec![]);
+    }
+
+    #[test]
+    fn many_nested() {
+        // each interval contains the next
+        let tree = IntervalTree::new((0..1000).map(|i| (0, i..2000 - i, i)));
+        assert_eq!(tree.containing(0, 999).len(), 1000);
+        assert_eq!(tree.containing(0, 500).len(), 501);
+        assert_eq!(tree.containing(0, 1500).len(), 500);
+    }
+}
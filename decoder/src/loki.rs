@@ -0,0 +1,188 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+
+use crate::Sample;
+
+/// Writes each poll as a line of NDJSON in the format of Loki's push API, so that promtail or
+/// alloy can ship it. Each poll gets a stream of its own, labeled with its thread id, top
+/// frame and duration bucket, with a log line like the text output's. A poll with several
+/// samples is as long as its latest sample says, and its log line has the stack trace of each.
+///
+/// `wall_time` converts a sample's `start_time` to the wall clock.
+pub fn write(
+    out: &mut dyn Write,
+    samples: &[Sample],
+    wall_time: impl Fn(&Sample) -> SystemTime,
+    stack_depth: usize,
+    collapse: &[Regex],
+) -> io::Result<()> {
+    for poll in crate::group_by_poll(samples) {
+        let Some(&sample) = poll.iter().max_by_key(|sample| sample.delta_t) else {
+            continue;
+        };
+        let mut line = Vec::new();
+        match &sample.thread_name {
+            Some(name) => write!(line, "thread {} ({})", sample.thread_id, name)?,
+            None => write!(line, "thread {}", sample.thread_id)?,
+        }
+        writeln!(
+            line,
+            " - {} of {}us",
+            sample.kind(),
+            sample.delta_t.as_micros()
+        )?;
+        for poll_sample in &poll {
+            if poll.len() > 1 {
+                writeln!(
+                    line,
+                    "sampled {}us into the {}:",
+                    poll_sample.delta_t.as_micros(),
+                    sample.kind()
+                )?;
+            }
+            crate::print_frames(
+                &mut line,
+                &poll_sample.frames,
+                stack_depth,
+                collapse,
+                crate::FrameStyle::default(),
+            )?;
+        }
+        let line = String::from_utf8_lossy(&line);
+        let top_frame = sample
+            .frames
+            .first()
+            .map_or_else(String::new, ToString::to_string);
+        let timestamp = wall_time(sample)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        writeln!(
+            out,
+            concat!(
+                r#"{{"streams":[{{"stream":{{"tid":"{}","top_frame":{},"duration_bucket":"{}"}},"#,
+                r#""values":[["{}",{}]]}}]}}"#
+            ),
+            sample.thread_id,
+            json_string(&top_frame),
+            duration_bucket(sample.delta_t),
+            timestamp,
+            json_string(line.trim_end())
+        )?;
+    }
+    Ok(())
+}
+
+/// The power of ten `duration` is at least, such as `1ms` for 1-10ms, which keeps the number
+/// of streams down
+fn duration_bucket(duration: Duration) -> String {
+    let bucket = 10u128.pow(duration.as_micros().max(1).ilog10());
+    match bucket {
+        1_000_000.. => format!("{}s", bucket / 1_000_000),
+        1_000.. => format!("{}ms", bucket / 1_000),
+        _ => format!("{}us", bucket),
+    }
+}
+
+/// `s` as a quoted JSON string
+pub fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{duration_bucket, json_string, write};
+    use crate::{Sample, StackFrame};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn sample(delta_t_ms: u64, poll_start: Option<u64>, frame: &str) -> Sample {
+        Sample {
+            delta_t: Duration::from_millis(delta_t_ms),
+            start_time: Duration::ZERO,
+            thread_id: 7,
+            thread_name: None,
+            session_id: None,
+            wall_time: None,
+            service_ready: false,
+            ready: false,
+            label: None,
+            poll_start,
+            parked: None,
+            frames: vec![StackFrame {
+                class_name: Some("app".to_owned()),
+                name: Some(frame.to_owned()),
+            }],
+        }
+    }
+
+    #[test]
+    fn one_stream_per_poll() {
+        let mut out = Vec::new();
+        let wall_time = |_: &Sample| UNIX_EPOCH + Duration::from_secs(1);
+        write(&mut out, &[sample(12, None, "handle")], wall_time, 5, &[]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"streams":[{"stream":{"tid":"7","top_frame":"app.handle","#,
+                r#""duration_bucket":"10ms"},"values":[["1000000000","#,
+                r#""thread 7 - poll of 12000us\n -   1: app.handle"]]}]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn samples_of_a_poll_share_a_line() {
+        let samples = [
+            sample(2, Some(100), "parse"),
+            sample(12, Some(100), "handle"),
+        ];
+        let mut out = Vec::new();
+        let wall_time = |_: &Sample| UNIX_EPOCH + Duration::from_secs(1);
+        write(&mut out, &samples, wall_time, 5, &[]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains(r#""top_frame":"app.handle""#), "{}", out);
+        assert!(
+            out.contains(concat!(
+                r#"poll of 12000us\nsampled 2000us into the poll:\n -   1: app.parse\n"#,
+                r#"sampled 12000us into the poll:\n -   1: app.handle"#
+            )),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(duration_bucket(Duration::from_micros(0)), "1us");
+        assert_eq!(duration_bucket(Duration::from_micros(999)), "100us");
+        assert_eq!(duration_bucket(Duration::from_millis(1)), "1ms");
+        assert_eq!(duration_bucket(Duration::from_millis(42)), "10ms");
+        assert_eq!(duration_bucket(Duration::from_secs(3)), "1s");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+    }
+}
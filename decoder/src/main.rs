@@ -1,16 +1,40 @@
-use std::{ffi::OsString, io::BufReader};
+use std::{
+    collections::{btree_map::Entry, hash_map, BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fmt,
+    io::{self, BufReader, BufWriter, IsTerminal, Write},
+    path::Path,
+    sync::LazyLock,
+};
 
-use clap::{Parser, Subcommand};
+use anstyle::{AnsiColor, Style};
+use clap::{Parser, Subcommand, ValueEnum};
+use interval_tree::IntervalTree;
 use jfrs::reader::{
     event::Accessor,
     value_descriptor::{Primitive, ValueDescriptor},
     Chunk, JfrReader,
 };
-use pr_parser::PossiblyUnknownEvent;
+use pollcatch::HdrHistogram;
+use pr_parser::{
+    CalibrationData, ExecutorEventKind, MmapPrReader, PossiblyUnknownEvent, ReadEventError,
+};
+use progress::ProgressReader;
+use regex::Regex;
 use std::io::{Read, Seek};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thread_names::{ThreadNameResolver, ThreadNameResolverBuilder};
 
+mod diff;
+mod follow;
+mod interval_tree;
+mod loki;
+mod otlp;
 mod pr_parser;
+mod progress;
+mod serve;
+mod thread_names;
+mod tui;
 
 #[derive(Debug, Parser)]
 #[command(name = "pollcatch-decoder")]
@@ -18,9 +42,13 @@ mod pr_parser;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Skip over corrupted records in PR files, e.g. from a crashed process, instead of failing
+    #[arg(long, global = true)]
+    skip_corrupt: bool,
 }
 
 #[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)] // only parsed once
 enum Commands {
     /// Print long polls from a JFR file
     Longpolls {
@@ -34,14 +62,221 @@ enum Commands {
         min_length: Duration,
         #[arg(long, default_value = "5")]
         stack_depth: usize,
+        /// Print runs of consecutive tokio runtime and `std::future` frames as a single line
+        #[arg(long)]
+        collapse_runtime_frames: bool,
+        /// Also collapse frames matching this glob pattern, where `*` matches anything (can be
+        /// repeated). More patterns are read from ~/.pollcatch/collapse_patterns.txt.
+        #[arg(long, requires = "collapse_runtime_frames")]
+        collapse_pattern: Vec<String>,
+        /// Only print the N longest polls, longest first
+        #[arg(long)]
+        top: Option<usize>,
+        /// Print each distinct stack trace once, with the count and duration of its polls
+        #[arg(long)]
+        group_by_stack: bool,
+        /// Print the longest poll and the number of long polls in each second of the
+        /// recording, instead of the polls
+        #[arg(long, conflicts_with = "group_by_stack")]
+        timeline: bool,
+        /// Print a tree of the stack frames of the polls, from the outermost caller in, with
+        /// the count and total duration of the polls through each frame
+        #[arg(long, conflicts_with_all = ["group_by_stack", "timeline"])]
+        call_graph: bool,
+        /// Browse the polls in a terminal UI, with their stack traces and a histogram of their
+        /// durations
+        #[arg(
+            short,
+            long,
+            conflicts_with_all = ["group_by_stack", "timeline", "call_graph", "output"]
+        )]
+        interactive: bool,
+        /// After printing the polls, keep printing the long polls appended to the PR file, like
+        /// `tail -f`. These have no stack traces, which are only in the JFR file.
+        #[arg(short, long, requires = "pr_file", conflicts_with = "interactive")]
+        follow: bool,
+        /// Only print polls with a `Class.method` frame matching this regex (can be repeated)
+        #[arg(long)]
+        filter_frame: Vec<Regex>,
+        /// Skip polls with a `Class.method` frame containing this string (can be repeated).
+        /// Defaults to the frames of runtimes waiting for work, which passing this replaces.
+        /// More strings are read from ~/.pollcatch/exclude_frames.txt, one per line.
+        #[arg(long, default_values = SLEEP_FRAMES)]
+        exclude_frame: Vec<String>,
+        /// Skip polls with a `Class.method` frame matching this regex (can be repeated)
+        #[arg(long)]
+        exclude_frame_regex: Vec<Regex>,
+        /// Only print polls starting at least this long after the start of the recording
+        #[arg(long, value_parser = humantime::parse_duration)]
+        start: Option<Duration>,
+        /// Only print polls starting less than this long after the start of the recording
+        #[arg(long, value_parser = humantime::parse_duration)]
+        end: Option<Duration>,
+        /// Only print polls on this OS thread id (can be repeated)
+        #[arg(long)]
+        thread_id: Vec<i64>,
+        /// Skip polls on this OS thread id (can be repeated)
+        #[arg(long)]
+        exclude_thread_id: Vec<i64>,
+        /// File to write the report to, instead of stdout
+        #[arg(long)]
+        output: Option<OsString>,
+        /// How to report the polls: as text, as OpenTelemetry spans exported to
+        /// `--otlp-endpoint`, as NUL-terminated fields, or as Loki log lines
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        /// OTLP/gRPC collector to export the polls to with `--format otlp`
+        #[arg(long, default_value = otlp::DEFAULT_ENDPOINT)]
+        otlp_endpoint: String,
+        /// Only use PR events from this process id, for PR files merged from several processes
+        #[arg(long)]
+        pid: Option<u32>,
+        /// Also print the JFR chunks' timing and the JVM version, and information about the
+        /// profiled processes
+        #[arg(short, long)]
+        verbose: bool,
+        /// Also print percentiles of the durations of all the polls in the PR file, not just
+        /// the long ones
+        #[arg(long, requires = "pr_file")]
+        percentiles: bool,
+        /// Demangle Rust and C++ symbol names in stack frames
+        #[arg(long)]
+        demangle: bool,
+        /// Color the durations by how long they are, and dim the runtime's frames. `auto`
+        /// colors when writing to a terminal.
+        #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+        color: ColorChoice,
+        /// Same as `--color never`
+        #[arg(long, conflicts_with = "color")]
+        no_color: bool,
+        /// Highlight the frames containing this crate's name, in bold with `--color` and with
+        /// a `>>>` prefix otherwise, to tell the application's frames from the runtime's (can
+        /// be repeated)
+        #[arg(long)]
+        highlight_crate: Vec<String>,
+        /// After the polls, print a table of them grouped by their innermost frame that is
+        /// neither the runtime's nor excluded with `--exclude-frame`, the one with the most
+        /// long poll time first
+        #[arg(long)]
+        top_frame_stats: bool,
+    },
+    /// Serve the long polls from a JFR file to the Perfetto UI as a Chrome JSON trace, and
+    /// print the link that opens it there
+    Serve {
+        /// JFR file to read from
+        jfr_file: OsString,
+        /// PR file to read performance data from
+        #[arg(long)]
+        pr_file: Option<OsString>,
+        /// Duration to mark from
+        #[clap(value_parser = humantime::parse_duration)]
+        min_length: Duration,
+        /// Port to listen on, on localhost
+        #[arg(long, default_value_t = serve::DEFAULT_PORT)]
+        port: u16,
+        /// Write the trace to this file, which the Perfetto UI opens with "Open trace file",
+        /// instead of serving it
+        #[arg(long)]
+        output: Option<OsString>,
+        /// Only use PR events from this process id, for PR files merged from several processes
+        #[arg(long)]
+        pid: Option<u32>,
+        /// Demangle Rust and C++ symbol names in stack frames
+        #[arg(long)]
+        demangle: bool,
+    },
+    /// Print the distribution of poll durations in a PR file
+    Stats {
+        /// PR file to read performance data from
+        pr_file: OsString,
+        /// Only use PR events from this process id, for PR files merged from several processes
+        #[arg(long)]
+        pid: Option<u32>,
+    },
+    /// Print the stacks whose long polls took more of the recording in a later profiling
+    /// session than in an earlier one, most first, e.g. to catch regressions in CI
+    Diff {
+        /// JFR file of the earlier session
+        #[arg(long)]
+        before: OsString,
+        /// PR file of the earlier session
+        #[arg(long)]
+        before_pr: Option<OsString>,
+        /// JFR file of the later session
+        #[arg(long)]
+        after: OsString,
+        /// PR file of the later session
+        #[arg(long)]
+        after_pr: Option<OsString>,
+        /// Duration to mark from
+        #[arg(long, value_parser = humantime::parse_duration)]
+        min_length: Duration,
+        #[arg(long, default_value = "5")]
+        stack_depth: usize,
+        /// Fail if a stack took more than this many times as much of the recording as before,
+        /// or is new
+        #[arg(long)]
+        max_regression: Option<f64>,
+        /// Only use PR events from this process id, for PR files merged from several processes
+        #[arg(long)]
+        pid: Option<u32>,
+    },
+    /// Merge several PR files into one, ordered by monotonic time
+    MergePr {
+        /// PR files to merge
+        #[arg(required = true)]
+        pr_files: Vec<OsString>,
+        /// PR file to write the merged events to
+        #[arg(long)]
+        output: OsString,
     },
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    /// A `long_poll` span of each poll, with the thread id and stack trace as attributes
+    Otlp,
+    /// The time, thread id, duration in microseconds and top frame of each sample, each
+    /// followed by a NUL byte, for `xargs -0 -n 4` and the like
+    NullTerminated,
+    /// A line of NDJSON in the format of Loki's push API for each poll, labeled with its
+    /// thread id, top frame and duration bucket
+    Loki,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 struct PollEventKey {
     tid: u32,
     clock_start: u64,
     duration: u64,
+    session_id: Option<u128>,
+    /// Start of the poll in nanoseconds since the Unix epoch, if the PR file has a wall clock anchor
+    realtime_start: Option<u64>,
+    /// Whether this is a `poll_ready` of a tower service rather than a poll of a future
+    service_ready: bool,
+    /// Whether the poll returned `Poll::Ready`
+    ready: bool,
+    /// The label of a block timed with `time_block`, rather than a poll
+    label: Option<String>,
+    /// When the executor last woke the thread up before the poll, if it records executor events
+    parked: Option<Parked>,
+}
+
+/// The last time the executor parked the thread of a poll before the poll, in nanoseconds
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Parked {
+    /// How long the thread was parked
+    duration: u64,
+    /// From when the thread was unparked to the start of the poll
+    before_poll: u64,
 }
 
 #[derive(PartialEq, Eq, Copy, Clone)]
@@ -50,30 +285,255 @@ enum ClockSource {
     Monotonic,
 }
 
+/// Prints the timing of each JFR chunk, to check the PR file's timestamps against, and the
+/// JVM that wrote the file if it says
+fn print_jfr_info(
+    out: &mut dyn Write,
+    chunks: &[JfrChunkInfo],
+    jvm_version: Option<&str>,
+) -> io::Result<()> {
+    for (i, chunk) in chunks.iter().enumerate() {
+        writeln!(
+            out,
+            "jfr chunk {}: started {} at tick {}, {} ticks/s, lasting {}ms",
+            i,
+            humantime::format_rfc3339_micros(chunk.start_wall_time),
+            chunk.start_ticks,
+            chunk.ticks_per_second,
+            chunk.duration.as_millis()
+        )?;
+    }
+    if let Some(jvm_version) = jvm_version {
+        writeln!(out, "jvm: {}", jvm_version)?;
+    }
+    writeln!(out)
+}
 
-fn make_pr_map<R: Read + Seek>(pr_reader: &mut R, clock_source: ClockSource) -> anyhow::Result<Vec<PollEventKey>> {
-    let mut pr_map = Vec::new();
-    let mut calibration = None;
-    while let Some(record) = pr_parser::read_event(pr_reader)? {
+/// Prints the processes that wrote to the PR file, and the CPUs they ran on
+fn print_process_infos(
+    out: &mut dyn Write,
+    pr_reader: &MmapPrReader,
+    skip_corrupt: bool,
+) -> anyhow::Result<()> {
+    for record in pr_reader.events().resilient(skip_corrupt) {
+        match record? {
+            PossiblyUnknownEvent::Event(pr_parser::Event::ProcessInfo { data }) => {
+                writeln!(
+                    out,
+                    "process {} on {}: {}",
+                    data.pid,
+                    data.hostname(),
+                    data.cmdline()
+                )?;
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::CpuInfo {
+                tsc_hz,
+                cpu_model,
+                cpu_flags,
+            }) => {
+                let model = pr_parser::from_fixed_cstr(&cpu_model);
+                let mut details = vec![match tsc_hz {
+                    0 => "TSC at unknown frequency".to_owned(),
+                    _ => format!("TSC at {:.3}GHz", tsc_hz as f64 / 1e9),
+                }];
+                if cpu_flags & pr_parser::CPU_FLAG_INVARIANT_TSC != 0 {
+                    details.push("invariant TSC".to_owned());
+                }
+                if cpu_flags & pr_parser::CPU_FLAG_RDTSCP != 0 {
+                    details.push("rdtscp".to_owned());
+                }
+                if cpu_flags & pr_parser::CPU_FLAG_HYPERVISOR != 0 {
+                    details.push("hypervisor".to_owned());
+                }
+                writeln!(
+                    out,
+                    "  cpu: {}, {}",
+                    if model.is_empty() {
+                        "unknown"
+                    } else {
+                        model.trim()
+                    },
+                    details.join(", ")
+                )?;
+            }
+            _ => {}
+        }
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Builds the polls of a PR file on one clock, from its events one at a time, keeping only
+/// those of process `pid` if given
+struct PrMapBuilder {
+    clock_source: ClockSource,
+    pid: Option<u32>,
+    pr_map: Vec<PollEventKey>,
+    calibration: Option<CalibrationData>,
+    session_id: Option<u128>,
+    event_pid: Option<u32>,
+    realtime_offset: Option<i64>,
+    /// the last park of each thread, and the unpark that ended it, on the TSC
+    parks: HashMap<u32, (u64, Option<u64>)>,
+}
+
+impl PrMapBuilder {
+    fn new(clock_source: ClockSource, pid: Option<u32>) -> Self {
+        PrMapBuilder {
+            clock_source,
+            pid,
+            pr_map: Vec::new(),
+            calibration: None,
+            session_id: None,
+            event_pid: None,
+            realtime_offset: None,
+            parks: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, record: &PossiblyUnknownEvent) {
+        let service_ready = matches!(
+            record,
+            PossiblyUnknownEvent::Event(pr_parser::Event::ServicePollReady { .. })
+        );
+        let ready = matches!(
+            record,
+            PossiblyUnknownEvent::Event(pr_parser::Event::PollReady { .. })
+        );
+        let label = match record {
+            PossiblyUnknownEvent::Event(pr_parser::Event::LabeledBlock { label, .. }) => {
+                Some(label.clone())
+            }
+            _ => None,
+        };
         match record {
-            PossiblyUnknownEvent::UnknownEvent { .. } => continue,
+            // the reordered event itself comes next, and order doesn't matter here
+            PossiblyUnknownEvent::UnknownEvent { .. } | PossiblyUnknownEvent::Reordered => {}
+            PossiblyUnknownEvent::Corrupt { bytes_skipped } => {
+                tracing::warn!(message = "skipped corrupted PR data", bytes_skipped);
+            }
+            PossiblyUnknownEvent::Lost { events } => {
+                tracing::warn!(message = "PR events were lost", events);
+            }
             PossiblyUnknownEvent::Event(pr_parser::Event::CalibrateTscToMonotonic { data }) => {
-                calibration = Some(data);
+                self.calibration = Some(data.clone());
             }
-            PossiblyUnknownEvent::Event(pr_parser::Event::Poll {
-                start,
-                end,
-                clock_end,
-                tid,
+            // only durations are scaled here, so the epochs don't matter
+            PossiblyUnknownEvent::Event(pr_parser::Event::CpuInfo { tsc_hz, .. }) => {
+                if self.calibration.is_none() {
+                    self.calibration = CalibrationData::from_tsc_hz(*tsc_hz, 0, 0);
+                }
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::SessionStart {
+                session_id: id,
+                pid: session_pid,
+                ..
             }) => {
-                let (clock_start, duration) = match clock_source {
-                    ClockSource::Tsc => {
-                        (start, end.saturating_sub(start))
+                // a calibration from a previous run does not apply to this one
+                self.session_id = Some(*id);
+                self.event_pid = Some(*session_pid);
+                self.calibration = None;
+                self.realtime_offset = None;
+                self.parks.clear();
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::WallClockAnchor {
+                monotonic_ns,
+                realtime_ns,
+                ..
+            }) => {
+                self.realtime_offset = Some(realtime_ns.wrapping_sub(*monotonic_ns) as i64);
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::ProcessInfo { data }) => {
+                self.event_pid = Some(data.pid);
+            }
+            // the JFR samples already tell where the signals were received
+            PossiblyUnknownEvent::Event(pr_parser::Event::Signal { .. }) => {}
+            // read by `ThreadNameResolver`
+            PossiblyUnknownEvent::Event(pr_parser::Event::ThreadName { .. }) => {}
+            // read by `read_annotations`
+            PossiblyUnknownEvent::Event(pr_parser::Event::UserAnnotation { .. }) => {}
+            // checked by `warn_unended_sessions`
+            PossiblyUnknownEvent::Event(pr_parser::Event::EndOfSession { .. }) => {}
+            PossiblyUnknownEvent::Event(pr_parser::Event::ExecutorEvent { kind, tid, tsc }) => {
+                if self.pid.is_some() && self.event_pid != self.pid {
+                    return;
+                }
+                match kind {
+                    ExecutorEventKind::Park => {
+                        self.parks.insert(*tid, (*tsc, None));
+                    }
+                    ExecutorEventKind::Unpark => {
+                        if let Some((_, unpark)) = self.parks.get_mut(tid) {
+                            *unpark = Some(*tsc);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::WriterError { error_code }) => {
+                tracing::warn!(
+                    message = "performance writer failed, later polls are missing",
+                    error = %io::Error::from_raw_os_error(*error_code as i32)
+                );
+            }
+            PossiblyUnknownEvent::Event(
+                pr_parser::Event::Poll {
+                    start,
+                    end,
+                    clock_end,
+                    tid,
+                }
+                | pr_parser::Event::ServicePollReady {
+                    start,
+                    end,
+                    clock_end,
+                    tid,
+                }
+                | pr_parser::Event::PollReady {
+                    start,
+                    end,
+                    clock_end,
+                    tid,
+                }
+                | pr_parser::Event::LabeledBlock {
+                    start,
+                    end,
+                    clock_end,
+                    tid,
+                    ..
+                },
+            ) => {
+                if self.pid.is_some() && self.event_pid != self.pid {
+                    return;
+                }
+                let (start, end, clock_end, tid) = (*start, *end, *clock_end, *tid);
+                let realtime_start = match (&self.calibration, self.realtime_offset) {
+                    (Some(calibration), Some(realtime_offset)) => {
+                        let duration =
+                            calibration.scale_src_duration_to_ref(end.saturating_sub(start));
+                        Some(
+                            clock_end
+                                .saturating_sub(duration)
+                                .wrapping_add_signed(realtime_offset),
+                        )
+                    }
+                    _ => None,
+                };
+                let parked = match (self.parks.get(&tid), &self.calibration) {
+                    (Some(&(park, Some(unpark))), Some(calibration)) if unpark <= start => {
+                        Some(Parked {
+                            duration: calibration.scale_src_duration_to_ref(unpark - park),
+                            before_poll: calibration.scale_src_duration_to_ref(start - unpark),
+                        })
                     }
+                    _ => None,
+                };
+                let (clock_start, duration) = match self.clock_source {
+                    ClockSource::Tsc => (start, end.saturating_sub(start)),
                     ClockSource::Monotonic => {
-                        let Some(calibration) = &calibration else {
+                        let Some(calibration) = &self.calibration else {
                             tracing::warn!("got poll event but no calibration");
-                            continue;
+                            return;
                         };
                         let poll_duration = end.saturating_sub(start);
                         let duration = calibration.scale_src_duration_to_ref(poll_duration);
@@ -81,16 +541,38 @@ fn make_pr_map<R: Read + Seek>(pr_reader: &mut R, clock_source: ClockSource) ->
                         (clock_start, duration)
                     }
                 };
-                pr_map.push(PollEventKey {
+                self.pr_map.push(PollEventKey {
                     tid,
                     clock_start,
                     duration,
+                    session_id: self.session_id,
+                    realtime_start,
+                    service_ready,
+                    ready,
+                    label,
+                    parked,
                 });
             }
         }
     }
-    pr_map.sort();
-    Ok(pr_map)
+
+    fn finish(mut self) -> Vec<PollEventKey> {
+        self.pr_map.sort();
+        self.pr_map
+    }
+}
+
+/// Reads the polls in the PR file, keeping only those of process `pid` if given
+fn make_pr_map(
+    events: impl IntoIterator<Item = Result<PossiblyUnknownEvent, ReadEventError>>,
+    clock_source: ClockSource,
+    pid: Option<u32>,
+) -> anyhow::Result<Vec<PollEventKey>> {
+    let mut builder = PrMapBuilder::new(clock_source, pid);
+    for record in events {
+        builder.add(&record?);
+    }
+    Ok(builder.finish())
 }
 
 fn main() -> anyhow::Result<()> {
@@ -102,23 +584,505 @@ fn main() -> anyhow::Result<()> {
             pr_file,
             min_length,
             stack_depth,
+            collapse_runtime_frames,
+            collapse_pattern,
+            top,
+            group_by_stack,
+            timeline,
+            call_graph,
+            interactive,
+            follow,
+            filter_frame,
+            mut exclude_frame,
+            exclude_frame_regex,
+            start,
+            end,
+            thread_id,
+            exclude_thread_id,
+            output,
+            format,
+            otlp_endpoint,
+            pid,
+            verbose,
+            percentiles,
+            demangle,
+            color,
+            no_color,
+            highlight_crate,
+            top_frame_stats,
         } => {
-            let (tsc_pr_map, monotonic_pr_map) = if let Some(pr_file) = pr_file {
-                let mut pr_reader = BufReader::new(std::fs::File::open(pr_file.clone())?);
-                let tsc_pr_map = make_pr_map(&mut pr_reader, ClockSource::Tsc)?;
-                let mut pr_reader = BufReader::new(std::fs::File::open(pr_file)?);
-                let monotonic_pr_map = make_pr_map(&mut pr_reader, ClockSource::Monotonic)?;
-                (tsc_pr_map, monotonic_pr_map)
+            let (tsc_pr_map, monotonic_pr_map, mut thread_names) =
+                read_pr_polls(pr_file.as_deref(), pid, cli.skip_corrupt)?;
+            exclude_frame.extend(read_exclude_frames_file()?);
+            let collapse: Vec<Regex> = if collapse_runtime_frames {
+                let file_patterns = read_config_lines("collapse_patterns.txt")?;
+                RUNTIME_FRAME_PATTERNS
+                    .iter()
+                    .copied()
+                    .chain(collapse_pattern.iter().map(String::as_str))
+                    .chain(file_patterns.iter().map(String::as_str))
+                    .map(glob_to_regex)
+                    .collect()
             } else {
-                (Vec::new(), Vec::new())
+                Vec::new()
+            };
+            let recording = jfr_samples(
+                &mut open_jfr(&jfr_file)?,
+                min_length,
+                &tsc_pr_map,
+                &monotonic_pr_map,
+            )?;
+            let mut samples = recording.samples;
+            if demangle {
+                demangle_frames(&mut samples);
+            }
+            samples.retain(|sample| {
+                let offset = sample.start_time.saturating_sub(recording.start_time);
+                matches_frame_filters(sample, &filter_frame, &exclude_frame, &exclude_frame_regex)
+                    && start.is_none_or(|start| start <= offset)
+                    && end.is_none_or(|end| offset < end)
+                    && (thread_id.is_empty() || thread_id.contains(&sample.thread_id))
+                    && !exclude_thread_id.contains(&sample.thread_id)
+            });
+            for sample in &mut samples {
+                sample.thread_name = thread_names.resolve(sample.thread_id).map(str::to_owned);
+            }
+            // of all the matching polls, not just the top ones
+            let long_poll_totals = LongPollTotals::by_thread(&samples);
+            let timeline_buckets =
+                timeline.then(|| timeline_buckets(&samples, recording.start_time));
+            let top_frame_stats = top_frame_stats.then(|| {
+                let is_runtime_frame = |frame: &str| {
+                    RUNTIME_FRAMES.iter().any(|re| re.is_match(frame))
+                        || exclude_frame.iter().any(|s| frame.contains(s.as_str()))
+                        || exclude_frame_regex.iter().any(|re| re.is_match(frame))
+                };
+                group_by_top_frame(&samples, is_runtime_frame)
+            });
+            if let Some(top) = top {
+                // stable sort, so polls of equal length stay in chronological order
+                samples.sort_by_key(|sample| std::cmp::Reverse(sample.delta_t));
+                samples.truncate(top);
+            }
+            if interactive {
+                tui::run(samples)?;
+                return Ok(());
+            }
+            let wall_time = |sample: &Sample| {
+                sample.wall_time.unwrap_or_else(|| {
+                    recording.start_wall_time
+                        + sample.start_time.saturating_sub(recording.start_time)
+                })
+            };
+            if format == OutputFormat::Otlp {
+                return otlp::export(&samples, wall_time, &otlp_endpoint);
+            }
+            let color = match color {
+                _ if no_color => false,
+                ColorChoice::Auto => output.is_none() && io::stdout().is_terminal(),
+                ColorChoice::Always => true,
+                ColorChoice::Never => false,
+            };
+            let frame_style = FrameStyle {
+                color,
+                highlight_crates: &highlight_crate,
             };
-            let mut reader = BufReader::new(std::fs::File::open(jfr_file)?);
-            print_samples(jfr_samples(&mut reader, min_length, &tsc_pr_map, &monotonic_pr_map)?, stack_depth);
+            let mut out: Box<dyn Write> = match output {
+                Some(output) => Box::new(BufWriter::new(std::fs::File::create(output)?)),
+                None => Box::new(io::stdout().lock()),
+            };
+            match format {
+                OutputFormat::NullTerminated => print_samples_null_terminated(&mut out, &samples)?,
+                OutputFormat::Loki => {
+                    loki::write(&mut out, &samples, wall_time, stack_depth, &collapse)?
+                }
+                OutputFormat::Text | OutputFormat::Otlp => {}
+            }
+            if format != OutputFormat::Text {
+                out.flush()?;
+                return Ok(());
+            }
+            if verbose {
+                print_jfr_info(
+                    &mut out,
+                    &recording.chunks,
+                    recording.jvm_version.as_deref(),
+                )?;
+            }
+            if let (true, Some(pr_file)) = (verbose, &pr_file) {
+                let pr_reader = MmapPrReader::open(pr_file)?;
+                print_process_infos(&mut out, &pr_reader, cli.skip_corrupt)?;
+            }
+            if let Some(buckets) = timeline_buckets {
+                print_timeline(&mut out, &buckets, recording.duration)?;
+            } else if group_by_stack {
+                print_stack_groups(&mut out, &samples, stack_depth, &collapse, frame_style)?;
+            } else if call_graph {
+                print_call_graph(&mut out, &samples)?;
+            } else {
+                // in chronological order, unless sorted by duration
+                let annotations = match (&pr_file, top) {
+                    (Some(pr_file), None) => {
+                        let pr_reader = MmapPrReader::open(pr_file)?;
+                        read_annotations(pr_reader.events().resilient(cli.skip_corrupt), pid)?
+                    }
+                    _ => Vec::new(),
+                };
+                print_samples(
+                    &mut out,
+                    samples,
+                    stack_depth,
+                    &collapse,
+                    &annotations,
+                    frame_style,
+                )?;
+            }
+            if let Some(top_frame_stats) = top_frame_stats {
+                print_top_frame_stats(&mut out, top_frame_stats)?;
+            }
+            print_long_poll_fraction(&mut out, &long_poll_totals, recording.duration)?;
+            if let (true, Some(pr_file)) = (percentiles, &pr_file) {
+                let pr_reader = MmapPrReader::open(pr_file)?;
+                let events = pr_reader.events().resilient(cli.skip_corrupt);
+                let pr_map = make_pr_map(events, ClockSource::Monotonic, pid)?;
+                print_percentiles(&mut out, &poll_histogram(&pr_map))?;
+            }
+            out.flush()?;
+            if let (true, Some(pr_file)) = (follow, &pr_file) {
+                follow::follow(&mut out, Path::new(pr_file), min_length, pid)?;
+            }
             Ok(())
         }
+        Commands::Serve {
+            jfr_file,
+            pr_file,
+            min_length,
+            port,
+            output,
+            pid,
+            demangle,
+        } => {
+            let (tsc_pr_map, monotonic_pr_map, mut thread_names) =
+                read_pr_polls(pr_file.as_deref(), pid, cli.skip_corrupt)?;
+            let recording = jfr_samples(
+                &mut open_jfr(&jfr_file)?,
+                min_length,
+                &tsc_pr_map,
+                &monotonic_pr_map,
+            )?;
+            let mut samples = recording.samples;
+            if demangle {
+                demangle_frames(&mut samples);
+            }
+            samples.retain(|sample| {
+                matches_frame_filters(sample, &[], &SLEEP_FRAMES.map(str::to_owned), &[])
+            });
+            for sample in &mut samples {
+                sample.thread_name = thread_names.resolve(sample.thread_id).map(str::to_owned);
+            }
+            let trace = serve::chrome_trace(&samples);
+            match output {
+                Some(output) => Ok(std::fs::write(output, trace)?),
+                None => serve::serve(trace, port),
+            }
+        }
+        Commands::Stats { pr_file, pid } => {
+            let pr_reader = MmapPrReader::open(pr_file)?;
+            warn_unended_sessions(pr_reader.events().resilient(cli.skip_corrupt))?;
+            let events = pr_reader.events().resilient(cli.skip_corrupt);
+            let pr_map = make_pr_map(events, ClockSource::Monotonic, pid)?;
+            print_poll_stats(&mut io::stdout().lock(), &pr_map)?;
+            Ok(())
+        }
+        Commands::Diff {
+            before,
+            before_pr,
+            after,
+            after_pr,
+            min_length,
+            stack_depth,
+            max_regression,
+            pid,
+        } => {
+            let read_recording = |jfr_file: &std::ffi::OsStr, pr_file| -> anyhow::Result<_> {
+                let (tsc_pr_map, monotonic_pr_map, _) =
+                    read_pr_polls(pr_file, pid, cli.skip_corrupt)?;
+                let mut recording = jfr_samples(
+                    &mut open_jfr(jfr_file)?,
+                    min_length,
+                    &tsc_pr_map,
+                    &monotonic_pr_map,
+                )?;
+                // like `serve`, without the runtimes waiting for work
+                recording.samples.retain(|sample| {
+                    matches_frame_filters(sample, &[], &SLEEP_FRAMES.map(str::to_owned), &[])
+                });
+                Ok(recording)
+            };
+            let before = read_recording(&before, before_pr.as_deref())?;
+            let after = read_recording(&after, after_pr.as_deref())?;
+            let diffs = diff::worse_stacks(
+                &before.samples,
+                before.duration,
+                &after.samples,
+                after.duration,
+            );
+            diff::print(&mut io::stdout().lock(), &diffs, stack_depth)?;
+            if let Some(max_regression) = max_regression {
+                let regressed = diffs
+                    .iter()
+                    .filter(|diff| diff.factor() > max_regression)
+                    .count();
+                if regressed > 0 {
+                    anyhow::bail!(
+                        "{} stack(s) got worse by more than {}x",
+                        regressed,
+                        max_regression
+                    );
+                }
+            }
+            Ok(())
+        }
+        Commands::MergePr { pr_files, output } => {
+            merge_pr_files(&pr_files, &output, cli.skip_corrupt)
+        }
     }
 }
 
+/// Opens a JFR file, showing the progress of reading it on stderr
+fn open_jfr(path: &std::ffi::OsStr) -> io::Result<BufReader<ProgressReader<std::fs::File>>> {
+    let file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    Ok(BufReader::new(ProgressReader::new(
+        file,
+        "reading the JFR file",
+        len,
+    )))
+}
+
+/// Reads the polls in the PR file, if any, on the TSC and monotonic clocks, and the names of
+/// their threads
+fn read_pr_polls(
+    pr_file: Option<&std::ffi::OsStr>,
+    pid: Option<u32>,
+    skip_corrupt: bool,
+) -> anyhow::Result<(
+    IntervalTree<PollEventKey>,
+    IntervalTree<PollEventKey>,
+    ThreadNameResolver,
+)> {
+    let Some(pr_file) = pr_file else {
+        return Ok((
+            make_poll_tree(Vec::new()),
+            make_poll_tree(Vec::new()),
+            ThreadNameResolver::default(),
+        ));
+    };
+    let pr_reader = MmapPrReader::open(pr_file)?;
+    let mut events = pr_reader.events().resilient(skip_corrupt);
+    let len = events.remaining_len() as u64;
+    let progress = progress::Progress::new("reading the PR file", len);
+    // a single pass over the file, which can be larger than memory
+    let mut sessions = SessionEnds::default();
+    let mut tsc_pr_map = PrMapBuilder::new(ClockSource::Tsc, pid);
+    let mut monotonic_pr_map = PrMapBuilder::new(ClockSource::Monotonic, pid);
+    let mut thread_names = ThreadNameResolverBuilder::new(pid);
+    while let Some(record) = events.next() {
+        let record = record?;
+        sessions.add(&record);
+        tsc_pr_map.add(&record);
+        monotonic_pr_map.add(&record);
+        thread_names.add(&record);
+        progress.set(len - events.remaining_len() as u64);
+    }
+    // erased before the warnings about sessions
+    drop(progress);
+    sessions.finish();
+    let (tsc_pr_map, monotonic_pr_map) = (tsc_pr_map.finish(), monotonic_pr_map.finish());
+    let thread_names = thread_names.finish();
+    Ok((
+        make_poll_tree(tsc_pr_map),
+        make_poll_tree(monotonic_pr_map),
+        thread_names,
+    ))
+}
+
+/// The percentiles of poll durations that are printed
+const PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 99.99];
+
+fn poll_histogram(polls: &[PollEventKey]) -> HdrHistogram {
+    let mut histogram = HdrHistogram::new(7);
+    for poll in polls {
+        histogram.record(poll.duration);
+    }
+    histogram
+}
+
+/// Prints the percentiles of the durations in `histogram` on one line
+fn print_percentiles(out: &mut dyn Write, histogram: &HdrHistogram) -> io::Result<()> {
+    if histogram.count() == 0 {
+        return writeln!(out, "no polls in the PR file");
+    }
+    let line = PERCENTILES
+        .iter()
+        .map(|&p| format!("P{}: {}us", p, histogram.percentile(p) / 1_000))
+        .collect::<Vec<_>>()
+        .join("  ");
+    writeln!(out, "{}", line)
+}
+
+/// Warns about the sessions in a PR file that don't end with an end-of-session event, which
+/// means that the recording was interrupted, e.g. by the process crashing
+fn warn_unended_sessions(
+    events: impl IntoIterator<Item = Result<PossiblyUnknownEvent, ReadEventError>>,
+) -> Result<(), ReadEventError> {
+    let mut sessions = SessionEnds::default();
+    for record in events {
+        sessions.add(&record?);
+    }
+    sessions.finish();
+    Ok(())
+}
+
+/// Checks that the sessions of a PR file end, from its events one at a time, for
+/// `warn_unended_sessions`
+#[derive(Default)]
+struct SessionEnds {
+    /// the session being read, and whether it ended
+    session: Option<(u128, bool)>,
+}
+
+impl SessionEnds {
+    fn warn(session_id: u128) {
+        tracing::warn!(
+            message = "PR session has no end, the recording was interrupted",
+            session_id = format!("{:032x}", session_id)
+        );
+    }
+
+    fn add(&mut self, record: &PossiblyUnknownEvent) {
+        match record {
+            PossiblyUnknownEvent::Event(pr_parser::Event::SessionStart { session_id, .. }) => {
+                if let Some((previous, false)) = self.session {
+                    Self::warn(previous);
+                }
+                self.session = Some((*session_id, false));
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::EndOfSession { session_id, .. }) => {
+                self.session = Some((*session_id, true));
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(self) {
+        if let Some((session_id, false)) = self.session {
+            Self::warn(session_id);
+        }
+    }
+}
+
+/// Prints the count and distribution of the durations of `polls`
+fn print_poll_stats(out: &mut dyn Write, polls: &[PollEventKey]) -> io::Result<()> {
+    let histogram = poll_histogram(polls);
+    if histogram.count() == 0 {
+        return writeln!(out, "no polls");
+    }
+    let nanos = Duration::from_nanos;
+    writeln!(out, "polls: {}", histogram.count())?;
+    writeln!(out, "mean: {:?}", nanos(histogram.mean() as u64))?;
+    for p in PERCENTILES {
+        writeln!(out, "p{}: {:?}", p, nanos(histogram.percentile(p)))?;
+    }
+    writeln!(out, "max: {:?}", nanos(histogram.max()))
+}
+
+/// Merge the events of several PR files, sorted by their monotonic timestamps.
+///
+/// Unknown events are dropped, since they can't be placed in time, and so are executor events
+/// of unknown kinds, which pollcatch can't write.
+fn merge_pr_files(
+    pr_files: &[OsString],
+    output: &OsString,
+    skip_corrupt: bool,
+) -> anyhow::Result<()> {
+    let mut events = vec![];
+    for pr_file in pr_files {
+        let mut file_events = vec![];
+        let pr_reader = MmapPrReader::open(pr_file)?;
+        for record in pr_reader.events().resilient(skip_corrupt) {
+            match record? {
+                PossiblyUnknownEvent::Event(pr_parser::Event::ExecutorEvent {
+                    kind: ExecutorEventKind::Unknown(kind),
+                    ..
+                }) => {
+                    tracing::warn!(message = "dropping unknown executor event", ?pr_file, kind);
+                }
+                PossiblyUnknownEvent::Event(event) => file_events.push(event),
+                PossiblyUnknownEvent::UnknownEvent { kind } => {
+                    tracing::warn!(message = "dropping unknown event", ?pr_file, kind);
+                }
+                PossiblyUnknownEvent::Corrupt { bytes_skipped } => {
+                    tracing::warn!(
+                        message = "skipped corrupted PR data",
+                        ?pr_file,
+                        bytes_skipped
+                    );
+                }
+                PossiblyUnknownEvent::Lost { events } => {
+                    tracing::warn!(message = "PR events were lost", ?pr_file, events);
+                }
+                // the events are sorted by time below anyway
+                PossiblyUnknownEvent::Reordered => {}
+            }
+        }
+        // session starts, process infos, thread names and writer errors have no monotonic
+        // timestamp, so keep them right before the event that follows them
+        let mut time = u64::MAX;
+        let mut keyed: Vec<_> = file_events
+            .into_iter()
+            .rev()
+            .map(|event| {
+                time = match &event {
+                    pr_parser::Event::Poll { clock_end, .. }
+                    | pr_parser::Event::ServicePollReady { clock_end, .. }
+                    | pr_parser::Event::PollReady { clock_end, .. }
+                    | pr_parser::Event::LabeledBlock { clock_end, .. } => *clock_end,
+                    pr_parser::Event::CalibrateTscToMonotonic { data } => data.ref_epoch,
+                    pr_parser::Event::WallClockAnchor { monotonic_ns, .. } => *monotonic_ns,
+                    pr_parser::Event::SessionStart { .. }
+                    | pr_parser::Event::ProcessInfo { .. }
+                    | pr_parser::Event::WriterError { .. }
+                    | pr_parser::Event::ThreadName { .. }
+                    | pr_parser::Event::ExecutorEvent { .. }
+                    | pr_parser::Event::UserAnnotation { .. }
+                    | pr_parser::Event::EndOfSession { .. }
+                    | pr_parser::Event::CpuInfo { .. }
+                    | pr_parser::Event::Signal { .. } => time,
+                };
+                (time, event)
+            })
+            .collect();
+        keyed.reverse();
+        // signals instead stay right after the poll they were received in
+        for i in 1..keyed.len() {
+            if let pr_parser::Event::Signal { .. } = keyed[i].1 {
+                keyed[i].0 = keyed[i - 1].0;
+            }
+        }
+        events.extend(keyed);
+    }
+    // stable sort, so events with the same timestamp stay in file order
+    events.sort_by_key(|(time, _)| *time);
+    let mut w = BufWriter::new(std::fs::File::create(output)?);
+    pr_parser::write_header(&mut w)?;
+    for (seq, (_, event)) in events.iter().enumerate() {
+        pr_parser::write_event(&mut w, seq as u64, event)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
 fn symbol_to_string(s: Accessor<'_>) -> Option<&str> {
     if let Some(sym) = s.get_field("string") {
         if let Ok(val) = sym.value.try_into() {
@@ -129,56 +1093,747 @@ fn symbol_to_string(s: Accessor<'_>) -> Option<&str> {
     None
 }
 
-fn print_samples(samples: Vec<Sample>, stack_depth: usize) {
+/// Frames of runtimes waiting for work, whose samples are not of a poll
+const SLEEP_FRAMES: [&str; 3] = [
+    "<tokio::runtime::scheduler::multi_thread::worker::Context>::park",
+    "<tokio::runtime::scheduler::current_thread::Context>::park",
+    // async-std and smol
+    "<parking::Inner>::park",
+];
+
+/// Reads the frames to exclude from ~/.pollcatch/exclude_frames.txt
+fn read_exclude_frames_file() -> io::Result<Vec<String>> {
+    read_config_lines("exclude_frames.txt")
+}
+
+/// Reads the lines of ~/.pollcatch/`name`, skipping empty lines and `#` comments. A missing
+/// file is fine.
+fn read_config_lines(name: &str) -> io::Result<Vec<String>> {
+    let Some(home) = std::env::var_os("HOME") else {
+        return Ok(Vec::new());
+    };
+    let path = Path::new(&home).join(".pollcatch").join(name);
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns true if every `filter_frame` pattern matches some frame of the sample, and no
+/// `exclude_frame` string or `exclude_frame_regex` pattern does
+fn matches_frame_filters(
+    sample: &Sample,
+    filter_frame: &[Regex],
+    exclude_frame: &[String],
+    exclude_frame_regex: &[Regex],
+) -> bool {
+    let frames: Vec<String> = sample.frames.iter().map(|f| f.to_string()).collect();
+    filter_frame
+        .iter()
+        .all(|re| frames.iter().any(|f| re.is_match(f)))
+        && !exclude_frame
+            .iter()
+            .any(|s| frames.iter().any(|f| f.contains(s.as_str())))
+        && !exclude_frame_regex
+            .iter()
+            .any(|re| frames.iter().any(|f| re.is_match(f)))
+}
+
+/// A marker recorded with `pollcatch::annotate`
+struct Annotation {
+    /// When it was recorded, if the PR file has a calibration and a wall clock anchor
+    wall_time: Option<SystemTime>,
+    tag: u32,
+    message: String,
+}
+
+/// Reads the annotations in a PR file, keeping only those of process `pid` if given
+fn read_annotations(
+    events: impl IntoIterator<Item = Result<PossiblyUnknownEvent, ReadEventError>>,
+    pid: Option<u32>,
+) -> Result<Vec<Annotation>, ReadEventError> {
+    let mut annotations = vec![];
+    let mut calibration = None;
+    let mut tsc_hz = 0;
+    let mut realtime_offset = None;
+    let mut event_pid = None;
+    for record in events {
+        match record? {
+            PossiblyUnknownEvent::Event(pr_parser::Event::SessionStart {
+                pid: session_pid,
+                ..
+            }) => {
+                event_pid = Some(session_pid);
+                calibration = None;
+                tsc_hz = 0;
+                realtime_offset = None;
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::ProcessInfo { data }) => {
+                event_pid = Some(data.pid);
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::CalibrateTscToMonotonic { data }) => {
+                calibration = Some(data);
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::CpuInfo { tsc_hz: hz, .. }) => {
+                tsc_hz = hz;
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::WallClockAnchor {
+                tsc,
+                monotonic_ns,
+                realtime_ns,
+            }) => {
+                // the anchor pairs a TSC reading with the monotonic clock, which is all a
+                // calibration from the TSC frequency is missing
+                if calibration.is_none() {
+                    calibration = CalibrationData::from_tsc_hz(tsc_hz, tsc, monotonic_ns);
+                }
+                realtime_offset = Some(realtime_ns.wrapping_sub(monotonic_ns) as i64);
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::UserAnnotation {
+                timestamp_tsc,
+                tag,
+                message,
+            }) if pid.is_none() || event_pid == pid => {
+                let wall_time = match (&calibration, realtime_offset) {
+                    (Some(calibration), Some(offset)) => Some(
+                        UNIX_EPOCH
+                            + Duration::from_nanos(
+                                calibration
+                                    .scale_src_to_ref(timestamp_tsc)
+                                    .wrapping_add_signed(offset),
+                            ),
+                    ),
+                    _ => None,
+                };
+                annotations.push(Annotation {
+                    wall_time,
+                    tag,
+                    message: pr_parser::from_fixed_cstr(&message).into_owned(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(annotations)
+}
+
+fn print_annotation(out: &mut dyn Write, annotation: &Annotation) -> io::Result<()> {
+    let time = match annotation.wall_time {
+        Some(wall_time) => humantime::format_rfc3339_micros(wall_time).to_string(),
+        None => "unknown time".to_owned(),
+    };
+    writeln!(
+        out,
+        "[{}] annotation {}: {}",
+        time, annotation.tag, annotation.message
+    )?;
+    writeln!(out)
+}
+
+/// Prints the samples, and the `annotations` between them by wall-clock time. Annotations
+/// whose time is unknown, or after the last sample, are printed at the end.
+fn print_samples(
+    out: &mut dyn Write,
+    samples: Vec<Sample>,
+    stack_depth: usize,
+    collapse: &[Regex],
+    annotations: &[Annotation],
+    frame_style: FrameStyle,
+) -> io::Result<()> {
+    let mut session_id = None;
+    // stable, so the rest stay in file order
+    let mut annotations: Vec<_> = annotations.iter().collect();
+    annotations.sort_by_key(|annotation| annotation.wall_time.is_none());
+    let mut annotations = annotations.into_iter().peekable();
     for sample in samples {
-        if sample.frames.iter().any(|f| {
-            f.name.as_ref().is_some_and(|n| {
-                n.contains(
-                    "<tokio::runtime::scheduler::multi_thread::worker::Context>::park_timeout",
-                )
-            })
-        }) {
-            // skip samples that are of sleeps
-            continue;
+        if let Some(wall_time) = sample.wall_time {
+            while let Some(annotation) =
+                annotations.next_if(|a| a.wall_time.is_some_and(|time| time <= wall_time))
+            {
+                print_annotation(out, annotation)?;
+            }
         }
-        println!(
-            "[{:.6}] thread {} - poll of {}us",
-            sample.start_time.as_secs_f64(),
+        if sample.session_id.is_some() && sample.session_id != session_id {
+            session_id = sample.session_id;
+            if let Some(session_id) = session_id {
+                writeln!(out, "===== session {:032x} =====", session_id)?;
+                writeln!(out)?;
+            }
+        }
+        let time = sample_time(&sample);
+        let thread_name = match &sample.thread_name {
+            Some(name) => format!(" ({})", name),
+            None => String::new(),
+        };
+        let style = if frame_style.color {
+            duration_style(sample.delta_t)
+        } else {
+            Style::new()
+        };
+        writeln!(
+            out,
+            "[{}] thread {}{} - {} of {style}{}us{style:#}",
+            time,
             sample.thread_id,
+            thread_name,
+            sample.kind(),
             sample.delta_t.as_micros()
-        );
-        for (i, frame) in sample.frames.iter().enumerate() {
-            if i == stack_depth {
-                println!(
-                    " - {:3} more frame(s) (pass --stack-depth={} to show)",
-                    sample.frames.len() - stack_depth,
-                    sample.frames.len()
-                );
-                break;
+        )?;
+        if let Some(parked) = sample.parked {
+            writeln!(
+                out,
+                " -      [woken up {}us before the poll, after parking for {}us]",
+                parked.before_poll / 1_000,
+                parked.duration / 1_000
+            )?;
+        }
+        print_frames(out, &sample.frames, stack_depth, collapse, frame_style)?;
+        writeln!(out)?;
+    }
+    for annotation in annotations {
+        print_annotation(out, annotation)?;
+    }
+    Ok(())
+}
+
+/// The wall-clock time of `sample` if known, or else its seconds since the JFR clock's epoch
+fn sample_time(sample: &Sample) -> String {
+    match sample.wall_time {
+        Some(wall_time) => humantime::format_rfc3339_micros(wall_time).to_string(),
+        None => format!("{:.6}", sample.start_time.as_secs_f64()),
+    }
+}
+
+/// Prints the time, thread id, duration in microseconds and top frame of each sample, each
+/// followed by a NUL byte like `find -print0` does, so that frames with any characters in them
+/// can be read back. The top frame is empty if the stack trace is.
+fn print_samples_null_terminated(out: &mut dyn Write, samples: &[Sample]) -> io::Result<()> {
+    for sample in samples {
+        let top_frame = sample
+            .frames
+            .first()
+            .map_or_else(String::new, StackFrame::to_string);
+        write!(
+            out,
+            "{}\0{}\0{}\0{}\0",
+            sample_time(sample),
+            sample.thread_id,
+            sample.delta_t.as_micros(),
+            top_frame
+        )?;
+    }
+    Ok(())
+}
+
+/// Red for polls over 10ms, yellow for those over 1ms, and green for the rest
+fn duration_style(duration: Duration) -> Style {
+    let color = if duration > Duration::from_millis(10) {
+        AnsiColor::Red
+    } else if duration > Duration::from_millis(1) {
+        AnsiColor::Yellow
+    } else {
+        AnsiColor::Green
+    };
+    color.on_default()
+}
+
+/// Frames of the runtime, which are dimmed when coloring
+static RUNTIME_FRAMES: LazyLock<Vec<Regex>> =
+    LazyLock::new(|| RUNTIME_FRAME_PATTERNS.map(glob_to_regex).into());
+
+/// How to mark frames when printing them
+#[derive(Clone, Copy, Default)]
+struct FrameStyle<'a> {
+    /// Dim the runtime's frames, and make the highlighted ones bold
+    color: bool,
+    /// Frames containing one of these are highlighted
+    highlight_crates: &'a [String],
+}
+
+/// Prints the first `stack_depth` lines of a stack trace. Runs of several consecutive frames
+/// matching one of the `collapse` patterns take a single line.
+fn print_frames(
+    out: &mut dyn Write,
+    frames: &[StackFrame],
+    stack_depth: usize,
+    collapse: &[Regex],
+    frame_style: FrameStyle,
+) -> io::Result<()> {
+    let mut i = 0;
+    for line in 0.. {
+        if i == frames.len() {
+            break;
+        }
+        if line == stack_depth {
+            writeln!(
+                out,
+                " - {:3} more frame(s) (pass --stack-depth={} to show)",
+                frames.len() - i,
+                frames.len()
+            )?;
+            break;
+        }
+        let run = frames[i..]
+            .iter()
+            .take_while(|frame| {
+                let frame = frame.to_string();
+                collapse.iter().any(|re| re.is_match(&frame))
+            })
+            .count();
+        if run > 1 {
+            writeln!(out, " -      [{} runtime frames collapsed]", run)?;
+            i += run;
+        } else {
+            let frame = frames[i].to_string();
+            let highlighted = frame_style
+                .highlight_crates
+                .iter()
+                .any(|name| frame.contains(name.as_str()));
+            let (prefix, style) = match (highlighted, frame_style.color) {
+                (true, true) => (" - ", AnsiColor::BrightWhite.on_default().bold()),
+                (true, false) => (">>>", Style::new()),
+                (false, true) if RUNTIME_FRAMES.iter().any(|re| re.is_match(&frame)) => {
+                    (" - ", Style::new().dimmed())
+                }
+                (false, _) => (" - ", Style::new()),
+            };
+            writeln!(out, "{style}{prefix}{:3}: {}{style:#}", i + 1, frame)?;
+            i += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Frames collapsed by `--collapse-runtime-frames`, as glob patterns
+const RUNTIME_FRAME_PATTERNS: [&str; 3] =
+    ["tokio::runtime::*", "std::future::*", "core::future::*"];
+
+/// Turns a glob pattern, where `*` matches anything, into a regex matching anywhere in a frame
+fn glob_to_regex(pattern: &str) -> Regex {
+    let parts: Vec<_> = pattern.split('*').map(regex::escape).collect();
+    Regex::new(&parts.join(".*")).expect("escaped regex is valid")
+}
+
+/// The long polls of a thread, and how long they took at least, going by their latest sample
+#[derive(Default)]
+struct LongPollTotals {
+    thread_name: Option<String>,
+    count: u64,
+    total: Duration,
+    /// Number of the polls found in the PR file
+    from_pr: u64,
+    /// Number of those that completed their future
+    ready: u64,
+}
+
+impl LongPollTotals {
+    fn by_thread(samples: &[Sample]) -> BTreeMap<i64, LongPollTotals> {
+        // samples without a PR poll are counted as polls of their own
+        let mut polls: HashMap<(i64, u64), Duration> = HashMap::new();
+        let mut by_thread: BTreeMap<i64, LongPollTotals> = BTreeMap::new();
+        for sample in samples {
+            let totals = by_thread.entry(sample.thread_id).or_default();
+            totals.thread_name.clone_from(&sample.thread_name);
+            match sample.poll_start {
+                Some(poll_start) => match polls.entry((sample.thread_id, poll_start)) {
+                    hash_map::Entry::Vacant(entry) => {
+                        totals.count += 1;
+                        totals.from_pr += 1;
+                        totals.ready += u64::from(sample.ready);
+                        totals.total += sample.delta_t;
+                        entry.insert(sample.delta_t);
+                    }
+                    hash_map::Entry::Occupied(mut entry) => {
+                        let longest = entry.get_mut();
+                        totals.total += sample.delta_t.saturating_sub(*longest);
+                        *longest = (*longest).max(sample.delta_t);
+                    }
+                },
+                None => {
+                    totals.count += 1;
+                    totals.total += sample.delta_t;
+                }
             }
-            println!(
-                " - {:3}: {}.{}",
-                i + 1,
-                frame.class_name.as_deref().unwrap_or("<unknown>"),
-                frame.name.as_deref().unwrap_or("<unknown>")
-            );
         }
-        println!();
+        by_thread
+    }
+}
+
+/// Groups the samples by the poll they're in, in the order of their first samples. Samples of
+/// the same PR poll are told apart by their thread and poll start, and samples without a PR
+/// poll are polls of their own.
+fn group_by_poll(samples: &[Sample]) -> Vec<Vec<&Sample>> {
+    let mut poll_indices: HashMap<(i64, u64), usize> = HashMap::new();
+    let mut polls: Vec<Vec<&Sample>> = Vec::new();
+    for sample in samples {
+        let Some(poll_start) = sample.poll_start else {
+            polls.push(vec![sample]);
+            continue;
+        };
+        let index = *poll_indices
+            .entry((sample.thread_id, poll_start))
+            .or_insert_with(|| {
+                polls.push(Vec::new());
+                polls.len() - 1
+            });
+        polls[index].push(sample);
+    }
+    polls
+}
+
+/// The latest sample of each poll, whose `delta_t` is how long the poll took at least
+fn latest_samples(samples: &[Sample]) -> Vec<&Sample> {
+    group_by_poll(samples)
+        .into_iter()
+        .filter_map(|poll| poll.into_iter().max_by_key(|sample| sample.delta_t))
+        .collect()
+}
+
+/// Prints how much of the recording the long polls took, for each thread and overall
+fn print_long_poll_fraction(
+    out: &mut dyn Write,
+    by_thread: &BTreeMap<i64, LongPollTotals>,
+    recording: Duration,
+) -> io::Result<()> {
+    let line = |out: &mut dyn Write, totals: &LongPollTotals| {
+        writeln!(
+            out,
+            "{} long polls totaling {}ms out of {}ms recording = {:.2}% of time",
+            totals.count,
+            totals.total.as_millis(),
+            recording.as_millis(),
+            if recording.is_zero() {
+                0.0
+            } else {
+                100.0 * totals.total.as_secs_f64() / recording.as_secs_f64()
+            }
+        )
+    };
+    let mut all = LongPollTotals::default();
+    for (thread_id, totals) in by_thread {
+        match &totals.thread_name {
+            Some(name) => write!(out, "thread {} ({}): ", thread_id, name)?,
+            None => write!(out, "thread {}: ", thread_id)?,
+        }
+        line(out, totals)?;
+        all.count += totals.count;
+        all.total += totals.total;
+        all.from_pr += totals.from_pr;
+        all.ready += totals.ready;
+    }
+    write!(out, "all threads: ")?;
+    line(out, &all)?;
+    if all.from_pr > 0 {
+        // a long final poll was slow to detect completion, a long intermediate one was slow
+        // to make progress
+        writeln!(
+            out,
+            "{} of {} long polls from the PR file completed their future ({:.2}%)",
+            all.ready,
+            all.from_pr,
+            100.0 * all.ready as f64 / all.from_pr as f64
+        )?;
+    }
+    Ok(())
+}
+
+/// The longest poll in microseconds and the number of long polls, by the second of the
+/// recording they were sampled in
+fn timeline_buckets(samples: &[Sample], recording_start: Duration) -> BTreeMap<u64, (u64, usize)> {
+    let mut buckets: BTreeMap<u64, (u64, usize)> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for sample in samples {
+        let second = sample.start_time.saturating_sub(recording_start).as_secs();
+        let (max, count) = buckets.entry(second).or_default();
+        *max = (*max).max(sample.delta_t.as_micros() as u64);
+        // several samples of the same poll count once
+        if sample.poll_start.is_none() || seen.insert((sample.thread_id, sample.poll_start)) {
+            *count += 1;
+        }
+    }
+    buckets
+}
+
+/// Width of the bar of the longest poll of the timeline
+const TIMELINE_BAR_WIDTH: u64 = 40;
+
+/// Prints a line for each second of the recording, with a bar for its longest poll
+fn print_timeline(
+    out: &mut dyn Write,
+    buckets: &BTreeMap<u64, (u64, usize)>,
+    recording: Duration,
+) -> io::Result<()> {
+    let longest = buckets.values().map(|(max, _)| *max).max().unwrap_or(0);
+    let last_second = buckets
+        .keys()
+        .next_back()
+        .copied()
+        .unwrap_or(0)
+        .max(recording.as_secs());
+    for second in 0..=last_second {
+        let (max, count) = buckets.get(&second).copied().unwrap_or_default();
+        let bar = (max * TIMELINE_BAR_WIDTH).checked_div(longest).unwrap_or(0);
+        writeln!(
+            out,
+            "{:>6}s max {:>9}us {:>5} long poll(s) {}",
+            second,
+            max,
+            count,
+            "#".repeat(bar as usize)
+        )?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+struct GroupStats {
+    count: u64,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+}
+
+impl GroupStats {
+    fn new(delta_t: Duration) -> Self {
+        GroupStats {
+            count: 1,
+            min: delta_t,
+            max: delta_t,
+            total: delta_t,
+        }
+    }
+
+    fn add(&mut self, delta_t: Duration) {
+        self.count += 1;
+        self.min = self.min.min(delta_t);
+        self.max = self.max.max(delta_t);
+        self.total += delta_t;
+    }
+}
+
+/// Groups the polls by the innermost frame of their latest sample for which
+/// `is_runtime_frame` is false, or `<runtime>` if there is none
+fn group_by_top_frame(
+    samples: &[Sample],
+    is_runtime_frame: impl Fn(&str) -> bool,
+) -> BTreeMap<String, GroupStats> {
+    let mut groups: BTreeMap<String, GroupStats> = BTreeMap::new();
+    for sample in latest_samples(samples) {
+        let top_frame = sample
+            .frames
+            .iter()
+            .map(StackFrame::to_string)
+            .find(|frame| !is_runtime_frame(frame))
+            .unwrap_or_else(|| "<runtime>".to_owned());
+        match groups.entry(top_frame) {
+            Entry::Vacant(entry) => {
+                entry.insert(GroupStats::new(sample.delta_t));
+            }
+            Entry::Occupied(mut entry) => entry.get_mut().add(sample.delta_t),
+        }
+    }
+    groups
+}
+
+/// Prints a table of the `groups` of `group_by_top_frame`, ordered by total poll time
+fn print_top_frame_stats(
+    out: &mut dyn Write,
+    groups: BTreeMap<String, GroupStats>,
+) -> io::Result<()> {
+    if groups.is_empty() {
+        return writeln!(out, "no long polls to group by top frame\n");
+    }
+    let all: Duration = groups.values().map(|stats| stats.total).sum();
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    groups.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.total));
+    writeln!(
+        out,
+        "{:>8} {:>10} {:>10} {:>12} {:>7}  top frame",
+        "polls", "min(us)", "max(us)", "total(us)", "%"
+    )?;
+    for (frame, stats) in groups {
+        writeln!(
+            out,
+            "{:>8} {:>10} {:>10} {:>12} {:>7.2}  {}",
+            stats.count,
+            stats.min.as_micros(),
+            stats.max.as_micros(),
+            stats.total.as_micros(),
+            if all.is_zero() {
+                0.0
+            } else {
+                100.0 * stats.total.as_secs_f64() / all.as_secs_f64()
+            },
+            frame
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Print polls with identical stack traces once, ordered by total poll time. A poll with
+/// several samples counts once, with the stack trace and duration of its latest sample.
+fn print_stack_groups(
+    out: &mut dyn Write,
+    samples: &[Sample],
+    stack_depth: usize,
+    collapse: &[Regex],
+    frame_style: FrameStyle,
+) -> io::Result<()> {
+    let mut groups: BTreeMap<&[StackFrame], GroupStats> = BTreeMap::new();
+    for sample in latest_samples(samples) {
+        match groups.entry(&sample.frames) {
+            Entry::Vacant(entry) => {
+                entry.insert(GroupStats::new(sample.delta_t));
+            }
+            Entry::Occupied(mut entry) => entry.get_mut().add(sample.delta_t),
+        }
+    }
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    groups.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.total));
+    for (frames, stats) in groups {
+        writeln!(
+            out,
+            "{} poll(s) totaling {}us (min {}us, max {}us)",
+            stats.count,
+            stats.total.as_micros(),
+            stats.min.as_micros(),
+            stats.max.as_micros()
+        )?;
+        print_frames(out, frames, stack_depth, collapse, frame_style)?;
+        writeln!(out)?;
     }
+    Ok(())
+}
+
+/// A frame in the call graph of the samples, and the frames it calls
+#[derive(Default)]
+struct CallNode<'a> {
+    count: u64,
+    total: Duration,
+    children: BTreeMap<&'a StackFrame, CallNode<'a>>,
+}
+
+impl CallNode<'_> {
+    fn print(&self, out: &mut dyn Write, prefix: &str) -> io::Result<()> {
+        let mut children: Vec<_> = self.children.iter().collect();
+        children.sort_by_key(|(_, node)| std::cmp::Reverse(node.total));
+        for (i, (frame, node)) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            writeln!(
+                out,
+                "{}{}{} - {} poll(s), {}us",
+                prefix,
+                if last { "└─ " } else { "├─ " },
+                frame,
+                node.count,
+                node.total.as_micros()
+            )?;
+            node.print(
+                out,
+                &format!("{}{}", prefix, if last { "   " } else { "│  " }),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the frames of all polls as a tree, starting from the outermost callers, ordered by
+/// total poll time. A poll with several samples counts once, with the stack trace and duration
+/// of its latest sample.
+fn print_call_graph(out: &mut dyn Write, samples: &[Sample]) -> io::Result<()> {
+    let mut root = CallNode::default();
+    for sample in latest_samples(samples) {
+        let mut node = &mut root;
+        // the innermost frame is first
+        for frame in sample.frames.iter().rev() {
+            node = node.children.entry(frame).or_default();
+            node.count += 1;
+            node.total += sample.delta_t;
+        }
+    }
+    root.print(out, "")
 }
 
 struct Sample {
     delta_t: Duration,
     start_time: Duration,
     thread_id: i64,
+    /// Resolved once the samples to print are known
+    thread_name: Option<String>,
+    session_id: Option<u128>,
+    /// Wall-clock time of the sample, if known from the PR file
+    wall_time: Option<SystemTime>,
+    /// Whether the sample is in a `poll_ready` of a tower service, per the PR file
+    service_ready: bool,
+    /// Whether the sample is in a poll that completed its future, per the PR file
+    ready: bool,
+    /// The label of the `time_block` block the sample is in, per the PR file
+    label: Option<String>,
+    /// Start of the poll in the PR file the sample is in, which tells samples of the same poll
+    /// apart from samples of different ones
+    poll_start: Option<u64>,
+    /// When the executor woke the thread up before the poll, per the PR file
+    parked: Option<Parked>,
     frames: Vec<StackFrame>,
 }
 
+impl Sample {
+    /// What the sample is in: a poll, a `poll_ready` or a labeled block
+    fn kind(&self) -> String {
+        match &self.label {
+            Some(label) => format!("block {:?}", label),
+            None if self.service_ready => "poll_ready".to_owned(),
+            None => "poll".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 struct StackFrame {
     class_name: Option<String>,
     name: Option<String>,
 }
 
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}",
+            self.class_name.as_deref().unwrap_or("<unknown>"),
+            self.name.as_deref().unwrap_or("<unknown>")
+        )
+    }
+}
+
+/// Demangles `name` as a Rust symbol, or else as a C++ one. Names that are neither, such as
+/// those already demangled, are returned as they are.
+fn demangle_symbol(name: &str) -> String {
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        // without the hash
+        return format!("{:#}", demangled);
+    }
+    cpp_demangle::Symbol::new(name)
+        .ok()
+        .and_then(|symbol| symbol.demangle(&Default::default()).ok())
+        .unwrap_or_else(|| name.to_owned())
+}
+
+fn demangle_frames(samples: &mut [Sample]) {
+    for sample in samples {
+        for frame in &mut sample.frames {
+            if let Some(name) = &mut frame.name {
+                *name = demangle_symbol(name);
+            }
+        }
+    }
+}
+
 fn resolve_stack_trace(trace: Accessor<'_>) -> Vec<StackFrame> {
     let mut res = vec![];
     if let Some(frames) = trace.get_field("frames") {
@@ -206,28 +1861,37 @@ fn resolve_stack_trace(trace: Accessor<'_>) -> Vec<StackFrame> {
     res
 }
 
-fn find_delta_t_from_clock(pr_map: &Vec<PollEventKey>, tid: i64, clock_start: i64) -> Option<u64> {
-    if let (Ok(tid), Ok(clock_start)) = (tid.try_into(), clock_start.try_into()) {
-        let partition_point = pr_map
-            .partition_point(|x| x.tid < tid || (tid == x.tid && x.clock_start <= clock_start));
-        if let Some(index) = partition_point.checked_sub(1) {
-            let bound = pr_map[index];
-            let inside = tid == bound.tid
-                && bound.clock_start < clock_start
-                && clock_start - bound.clock_start < bound.duration;
-            if inside {
-                return Some(clock_start - bound.clock_start);
-            }
-        }
-        None
-    } else {
-        None
-    }
+/// Indexes polls by thread and time, for [`find_delta_t_from_clock`]
+fn make_poll_tree(polls: Vec<PollEventKey>) -> IntervalTree<PollEventKey> {
+    IntervalTree::new(polls.into_iter().map(|poll| {
+        let range = poll.clock_start..poll.clock_start.saturating_add(poll.duration);
+        (poll.tid, range, poll)
+    }))
+}
+
+/// Finds the polls containing `clock_start` on thread `tid`, and how far into them
+/// `clock_start` is, longest first. There is more than one when `PollTimingFuture`s are nested.
+fn find_delta_t_from_clock(
+    pr_map: &IntervalTree<PollEventKey>,
+    tid: i64,
+    clock_start: i64,
+) -> Vec<(u64, &PollEventKey)> {
+    let (Ok(tid), Ok(clock_start)) = (tid.try_into(), clock_start.try_into()) else {
+        return Vec::new();
+    };
+    let mut polls: Vec<_> = pr_map
+        .containing(tid, clock_start)
+        .into_iter()
+        .map(|(start, poll)| (clock_start - start, poll))
+        .collect();
+    polls.sort_by_key(|(_, poll)| std::cmp::Reverse(poll.duration));
+    polls
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_sample(
     chunk: &Chunk,
-    pr_map: &Vec<PollEventKey>,
+    pr_map: &IntervalTree<PollEventKey>,
     sampled_thread: Option<&ValueDescriptor>,
     stacktrace: Option<&ValueDescriptor>,
     appword: Option<i64>,
@@ -237,43 +1901,100 @@ fn process_sample(
 ) -> Option<Sample> {
     let mut delta_t = 0;
     let mut thread_id = !0;
+    let mut session_id = None;
+    let mut realtime_start = None;
+    let mut service_ready = false;
+    let mut ready = false;
+    let mut label = None;
+    let mut poll_start = None;
+    let mut parked = None;
     if let Some(ValueDescriptor::Object(st)) = sampled_thread {
         if let Some(&ValueDescriptor::Primitive(Primitive::Long(tid))) =
             st.fields.get(os_thread_index)
         {
-            thread_id = tid as i64;
+            thread_id = tid;
         }
     }
     if let Some(appword) = appword {
         delta_t = appword as u64;
     }
+    let to_micros =
+        |ticks: u64| (ticks as u128) * 1000000 / (chunk.header.ticks_per_second as u128);
     if delta_t == 0 {
-        if let Some(delta_t_) = find_delta_t_from_clock(pr_map, thread_id, start_time_ticks) {
+        // of nested polls, the sample is attributed to the innermost one that is long enough,
+        // which is the one its stack is most specific to
+        let candidates = find_delta_t_from_clock(pr_map, thread_id, start_time_ticks);
+        if let Some(&(delta_t_, poll)) = candidates
+            .iter()
+            .rev()
+            .find(|(delta_t, _)| to_micros(*delta_t) >= long_poll_duration)
+        {
             delta_t = delta_t_;
+            session_id = poll.session_id;
+            realtime_start = poll.realtime_start;
+            service_ready = poll.service_ready;
+            ready = poll.ready;
+            label.clone_from(&poll.label);
+            poll_start = Some(poll.clock_start);
+            parked = poll.parked;
         }
     }
 
-    let delta_t_micros = (delta_t as u128) * 1000000 / (chunk.header.ticks_per_second as u128);
+    let delta_t_micros = to_micros(delta_t);
     if delta_t_micros < long_poll_duration {
         return None;
     }
+    let delta_t = Duration::from_micros(delta_t_micros as u64);
     stacktrace.map(|trace| Sample {
         thread_id,
-        start_time: Duration::from_nanos(
-            ((start_time_ticks as u128) * 1_000_000_000 / (chunk.header.ticks_per_second as u128))
-                as u64,
-        ),
-        delta_t: Duration::from_micros(delta_t_micros as u64),
+        thread_name: None,
+        session_id,
+        wall_time: realtime_start.map(|start| UNIX_EPOCH + Duration::from_nanos(start) + delta_t),
+        start_time: ticks_to_duration(chunk, start_time_ticks),
+        delta_t,
+        service_ready,
+        ready,
+        label,
+        poll_start,
+        parked,
         frames: resolve_stack_trace(Accessor::new(chunk, trace)),
     })
 }
 
+fn ticks_to_duration(chunk: &Chunk, ticks: i64) -> Duration {
+    Duration::from_nanos(
+        ((ticks as u128) * 1_000_000_000 / (chunk.header.ticks_per_second as u128)) as u64,
+    )
+}
+
+/// The long poll samples from a JFR file
+struct Recording {
+    /// Start of the first chunk, on the same clock as `Sample::start_time`
+    start_time: Duration,
+    /// Start of the first chunk on the wall clock
+    start_wall_time: SystemTime,
+    /// Total duration of the chunks
+    duration: Duration,
+    chunks: Vec<JfrChunkInfo>,
+    /// The JVM's name and version, from its `jdk.JVMInformation` event
+    jvm_version: Option<String>,
+    samples: Vec<Sample>,
+}
+
+/// The timing in a JFR chunk's header
+struct JfrChunkInfo {
+    start_wall_time: SystemTime,
+    start_ticks: i64,
+    ticks_per_second: i64,
+    duration: Duration,
+}
+
 fn jfr_samples<T>(
     reader: &mut T,
     long_poll_duration: Duration,
-    tsc_pr_map: &Vec<PollEventKey>,
-    monotonic_pr_map: &Vec<PollEventKey>,
-) -> anyhow::Result<Vec<Sample>>
+    tsc_pr_map: &IntervalTree<PollEventKey>,
+    monotonic_pr_map: &IntervalTree<PollEventKey>,
+) -> anyhow::Result<Recording>
 where
     T: Read + Seek,
 {
@@ -281,8 +2002,24 @@ where
     let long_poll_duration = long_poll_duration.as_micros();
 
     let mut samples = vec![];
+    let mut start_time = None;
+    let mut duration = Duration::ZERO;
+    let mut chunks = vec![];
+    let mut jvm_version = None;
     for chunk in jfr_reader.chunks() {
         let (mut c_rdr, c) = chunk?;
+        let start_wall_time =
+            UNIX_EPOCH + Duration::from_nanos(c.header.start_time_nanos.try_into().unwrap_or(0));
+        start_time
+            .get_or_insert_with(|| (ticks_to_duration(&c, c.header.start_ticks), start_wall_time));
+        let chunk_duration = Duration::from_nanos(c.header.duration_nanos.try_into().unwrap_or(0));
+        duration += chunk_duration;
+        chunks.push(JfrChunkInfo {
+            start_wall_time,
+            start_ticks: c.header.start_ticks,
+            ticks_per_second: c.header.ticks_per_second,
+            duration: chunk_duration,
+        });
         let mut wall_clock_sample = None;
         let mut execution_sample = None;
         let mut wcs_start_time_index = !0;
@@ -296,6 +2033,9 @@ where
         let mut active_setting_value_index = !0;
         let mut os_thread_index = !0;
         let mut active_setting = None;
+        let mut jvm_information = None;
+        let mut jvm_name_index = !0;
+        let mut jvm_version_index = !0;
         for ty in c.metadata.type_pool.get_types() {
             if ty.name() == "profiler.WallClockSample" {
                 wall_clock_sample = Some(ty.class_id);
@@ -321,9 +2061,18 @@ where
                 }
             }
             if ty.name() == "java.lang.Thread" {
+                for (i, field) in ty.fields.iter().enumerate() {
+                    if field.name() == "osThreadId" {
+                        os_thread_index = i;
+                    }
+                }
+            }
+            if ty.name() == "jdk.JVMInformation" {
+                jvm_information = Some(ty.class_id);
                 for (i, field) in ty.fields.iter().enumerate() {
                     match field.name() {
-                        "osThreadId" => os_thread_index = i,
+                        "jvmName" => jvm_name_index = i,
+                        "jvmVersion" => jvm_version_index = i,
                         _ => {}
                     }
                 }
@@ -355,8 +2104,10 @@ where
                         .and_then(|st| Accessor::new(&c, st).resolve())
                         .map(|a| a.value);
                     match (name, value) {
-                        (Some(ValueDescriptor::Primitive(Primitive::String(name))),
-                        Some(ValueDescriptor::Primitive(Primitive::String(value)))) if name == "clock" => {
+                        (
+                            Some(ValueDescriptor::Primitive(Primitive::String(name))),
+                            Some(ValueDescriptor::Primitive(Primitive::String(value))),
+                        ) if name == "clock" => {
                             if value == "tsc" {
                                 pr_map = tsc_pr_map;
                             } else {
@@ -367,6 +2118,23 @@ where
                     }
                 }
             }
+            if Some(event.class.class_id) == jvm_information {
+                if let ValueDescriptor::Object(o) = event.value().value {
+                    let string = |index: usize| match o
+                        .fields
+                        .get(index)
+                        .and_then(|st| Accessor::new(&c, st).resolve())
+                        .map(|a| a.value)
+                    {
+                        Some(ValueDescriptor::Primitive(Primitive::String(s))) => Some(s.as_str()),
+                        _ => None,
+                    };
+                    jvm_version = match (string(jvm_name_index), string(jvm_version_index)) {
+                        (Some(name), Some(version)) => Some(format!("{} {}", name, version)),
+                        (name, version) => name.or(version).map(str::to_owned),
+                    };
+                }
+            }
             if Some(event.class.class_id) == wall_clock_sample {
                 if let ValueDescriptor::Object(o) = event.value().value {
                     let start_time_ticks =
@@ -439,5 +2207,207 @@ where
             }
         }
     }
-    Ok(samples)
+    let (start_time, start_wall_time) = start_time.unwrap_or((Duration::ZERO, UNIX_EPOCH));
+    Ok(Recording {
+        start_time,
+        start_wall_time,
+        duration,
+        chunks,
+        jvm_version,
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        group_by_top_frame, merge_pr_files, pr_parser, timeline_buckets, LongPollTotals,
+        MmapPrReader, PossiblyUnknownEvent, Sample, StackFrame,
+    };
+    use pr_parser::{Event, ProcessInfoData};
+
+    fn sample(thread_id: i64, start_ms: u64, delta_t_ms: u64, poll_start: Option<u64>) -> Sample {
+        Sample {
+            delta_t: Duration::from_millis(delta_t_ms),
+            start_time: Duration::from_millis(start_ms),
+            thread_id,
+            thread_name: None,
+            session_id: None,
+            wall_time: None,
+            service_ready: false,
+            ready: false,
+            label: None,
+            poll_start,
+            parked: None,
+            frames: vec![StackFrame {
+                class_name: Some("my_crate".to_owned()),
+                name: Some("work".to_owned()),
+            }],
+        }
+    }
+
+    /// Two samples of one PR poll, and a sample without one, which is a poll of its own
+    fn samples() -> Vec<Sample> {
+        vec![
+            sample(1, 110, 10, Some(100)),
+            sample(1, 130, 30, Some(100)),
+            sample(1, 150, 5, None),
+        ]
+    }
+
+    #[test]
+    fn by_thread_counts_polls_once() {
+        let by_thread = LongPollTotals::by_thread(&samples());
+        let totals = &by_thread[&1];
+        assert_eq!(totals.count, 2);
+        assert_eq!(totals.from_pr, 1);
+        assert_eq!(totals.total, Duration::from_millis(35));
+    }
+
+    /// With a minimum length of 0s, samples can be at the very start of their poll
+    #[test]
+    fn by_thread_counts_zero_length_polls_once() {
+        let by_thread = LongPollTotals::by_thread(&[
+            sample(1, 100, 0, Some(100)),
+            sample(1, 100, 0, Some(100)),
+            sample(1, 110, 10, Some(100)),
+        ]);
+        let totals = &by_thread[&1];
+        assert_eq!(totals.count, 1);
+        assert_eq!(totals.from_pr, 1);
+        assert_eq!(totals.total, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn timeline_buckets_count_polls_once() {
+        let buckets = timeline_buckets(&samples(), Duration::ZERO);
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[&0], (30_000, 2));
+    }
+
+    #[test]
+    fn group_by_top_frame_counts_polls_once() {
+        let groups = group_by_top_frame(&samples(), |_| false);
+        let stats = &groups["my_crate.work"];
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.min, Duration::from_millis(5));
+        assert_eq!(stats.max, Duration::from_millis(30));
+        assert_eq!(stats.total, Duration::from_millis(35));
+    }
+
+    /// A PR file of one process, whose thread `pid` polls at `clock_ends`, receiving a signal
+    /// at the end of each poll
+    fn pr_file(pid: u32, clock_ends: &[u64]) -> Vec<u8> {
+        let mut events = vec![
+            Event::SessionStart {
+                session_id: pid.into(),
+                wall_time_ns: 0,
+                pid,
+            },
+            Event::ProcessInfo {
+                data: Box::new(ProcessInfoData {
+                    pid,
+                    hostname: [0; 64],
+                    cmdline: [0; 256],
+                    start_time: None,
+                }),
+            },
+        ];
+        for &clock_end in clock_ends {
+            events.push(Event::Poll {
+                start: clock_end - 1,
+                end: clock_end,
+                clock_end,
+                tid: pid,
+            });
+            events.push(Event::Signal {
+                tsc: clock_end,
+                tid: pid,
+            });
+        }
+        let mut data = vec![];
+        pr_parser::write_header(&mut data).unwrap();
+        for (seq, event) in events.iter().enumerate() {
+            pr_parser::write_event(&mut data, seq as u64, event).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn merge_keeps_untimed_events_with_their_polls() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let inputs = [
+            (
+                dir.join(format!("pollcatch-merge-{}-1.pr", id)),
+                pr_file(1, &[100, 300]),
+            ),
+            (
+                dir.join(format!("pollcatch-merge-{}-2.pr", id)),
+                pr_file(2, &[200, 400]),
+            ),
+        ];
+        for (path, data) in &inputs {
+            std::fs::write(path, data).unwrap();
+        }
+        let output = dir.join(format!("pollcatch-merge-{}.pr", id));
+        merge_pr_files(
+            &inputs
+                .iter()
+                .map(|(path, _)| path.into())
+                .collect::<Vec<_>>(),
+            &output.clone().into(),
+            false,
+        )
+        .unwrap();
+        let events: Vec<_> = MmapPrReader::open(&output)
+            .unwrap()
+            .events()
+            .map(|event| match event.unwrap() {
+                PossiblyUnknownEvent::Event(event) => event,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        for (path, _) in &inputs {
+            std::fs::remove_file(path).unwrap();
+        }
+        std::fs::remove_file(&output).unwrap();
+
+        assert_eq!(events.len(), 12, "{:?}", events);
+        let position = |wanted: &dyn Fn(&Event) -> bool| {
+            events.iter().position(|event| wanted(event)).unwrap()
+        };
+        for pid in [1, 2] {
+            let session_start =
+                position(&|event| matches!(event, Event::SessionStart { pid: p, .. } if *p == pid));
+            let process_info =
+                position(&|event| matches!(event, Event::ProcessInfo { data } if data.pid == pid));
+            let first_poll =
+                position(&|event| matches!(event, Event::Poll { tid, .. } if *tid == pid));
+            assert!(session_start < process_info, "{:?}", events);
+            assert!(process_info < first_poll, "{:?}", events);
+        }
+        // the second process starts between the polls of the first
+        assert!(
+            position(&|event| matches!(event, Event::SessionStart { pid: 2, .. }))
+                > position(&|event| matches!(event, Event::Poll { clock_end: 100, .. })),
+            "{:?}",
+            events
+        );
+        for (i, event) in events.iter().enumerate() {
+            if let Event::Signal { tsc, tid } = event {
+                assert!(
+                    matches!(
+                        events[..i].last(),
+                        Some(Event::Poll { clock_end, tid: poll_tid, .. })
+                            if clock_end == tsc && poll_tid == tid
+                    ),
+                    "{:?}",
+                    events
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,80 @@
+use std::time::SystemTime;
+
+use opentelemetry::{
+    trace::{Span, Tracer, TracerProvider},
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+
+use crate::Sample;
+
+/// The default endpoint of an OTLP/gRPC collector
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:4317";
+
+/// Exports each poll as a `long_poll` span to the OTLP/gRPC collector at `endpoint`. A span
+/// ends when the latest sample of its poll was taken, and starts that sample's `delta_t`
+/// before, when the poll started. Its stack trace is the latest sample's, and each sample is
+/// a `sample` event with its own.
+///
+/// `wall_time` converts a sample's `start_time` to the wall clock.
+pub fn export(
+    samples: &[Sample],
+    wall_time: impl Fn(&Sample) -> SystemTime,
+    endpoint: &str,
+) -> anyhow::Result<()> {
+    // tonic needs a runtime to drive its connection while the batch processor exports from
+    // its own thread
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()?;
+    let _guard = runtime.enter();
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    let stacktrace = |sample: &Sample| {
+        sample
+            .frames
+            .iter()
+            .map(|frame| frame.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    for poll in crate::group_by_poll(samples) {
+        let Some(&sample) = poll.iter().max_by_key(|sample| sample.delta_t) else {
+            continue;
+        };
+        let end = wall_time(sample);
+        let mut attributes = vec![
+            KeyValue::new("thread.id", sample.thread_id),
+            KeyValue::new("code.stacktrace", stacktrace(sample)),
+        ];
+        if let Some(frame) = sample.frames.first() {
+            attributes.push(KeyValue::new("code.function", frame.to_string()));
+        }
+        if let Some(name) = &sample.thread_name {
+            attributes.push(KeyValue::new("thread.name", name.clone()));
+        }
+        let mut span = tracer
+            .span_builder("long_poll")
+            .with_start_time(end - sample.delta_t)
+            .with_attributes(attributes)
+            .start(&tracer);
+        for poll_sample in poll {
+            span.add_event_with_timestamp(
+                "sample",
+                wall_time(poll_sample),
+                vec![KeyValue::new("code.stacktrace", stacktrace(poll_sample))],
+            );
+        }
+        span.end_with_timestamp(end);
+    }
+    provider.shutdown()?;
+    Ok(())
+}
@@ -1,6 +1,13 @@
-use std::io::{self, Read, Seek};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Mutex;
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use memmap2::Mmap;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,9 +16,61 @@ pub enum ReadEventError {
     Read(#[from] io::Error),
     #[error("size field too small")]
     SizeTooSmall,
+    #[error("field extends past the end of the record")]
+    FieldTruncated,
+    #[error("missing field {0}")]
+    MissingField(u8),
+    #[error("field {0} too short")]
+    FieldTooShort(u8),
+    #[error("unknown compression {0}")]
+    UnknownCompression(u8),
 }
 
-#[derive(Debug)]
+/// Written at the start of every PR file, followed by a compression byte. Files written
+/// before the header was introduced start directly with a record.
+pub const PR_MAGIC: [u8; 4] = *b"PCPR";
+pub const COMPRESSION_NONE: u8 = 0;
+pub const COMPRESSION_ZSTD: u8 = 1;
+
+/// Written at the start of ring-buffer PR files, followed by the write head and the offset of
+/// the oldest record as `u64`s, then the records. The magic comes first, rather than the
+/// head, so that ring files can be told apart from the others.
+pub const RING_MAGIC: [u8; 8] = *b"PCPRRING";
+const RING_HEADER_LEN: usize = 24;
+
+/// Copies the records of a ring buffer into chronological order, going from the oldest record
+/// to the write head and jumping to the start at the zero-sized end marker.
+fn linearize_ring(records: &[u8], head: usize, oldest: usize) -> Vec<u8> {
+    let mut out = vec![];
+    let mut pos = oldest;
+    // a ring with records in it always has its head past the first one
+    while head != 0 && out.len() <= records.len() {
+        let size = records
+            .get(pos..pos + 4)
+            .map_or(0, |size| LittleEndian::read_u32(size) as usize);
+        if size == 0 {
+            if pos == 0 {
+                break;
+            }
+            pos = 0;
+        } else {
+            let Some(record) = records.get(pos..pos + size) else {
+                break;
+            };
+            out.extend_from_slice(record);
+            pos += size;
+        }
+        if pos == head {
+            break;
+        }
+    }
+    out
+}
+
+pub use pollcatch::pr::write_pr_header as write_header;
+
+#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)] // `ExecutorEvent`, named like the pollcatch one
 pub enum Event {
     Poll {
         start: u64,
@@ -21,18 +80,124 @@ pub enum Event {
     },
     /// monotonic time = (tsc-time - src-epoch) * mul >> shift + ref-epoch
     CalibrateTscToMonotonic { data: CalibrationData },
+    /// Start of a new process run, written before any other event of that run
+    SessionStart {
+        session_id: u128,
+        wall_time_ns: u64,
+        pid: u32,
+    },
+    /// The profiled process. Boxed to keep poll events small
+    ProcessInfo { data: Box<ProcessInfoData> },
+    /// The same instant on the TSC, monotonic and realtime clocks
+    WallClockAnchor {
+        tsc: u64,
+        monotonic_ns: u64,
+        realtime_ns: u64,
+    },
+    /// The writer failed and is exiting. `error_code` is the OS error, or 0 if there is none
+    WriterError { error_code: u32 },
+    /// Like `Poll`, for a `poll_ready` of a service wrapped in `PollTimingLayer`
+    ServicePollReady {
+        start: u64,
+        end: u64,
+        clock_end: u64,
+        tid: u32,
+    },
+    /// A profiling signal received during the poll written before it
+    Signal { tsc: u64, tid: u32 },
+    /// The name of a thread, written before its first poll. Null-terminated
+    ThreadName { tid: u32, name: [u8; 16] },
+    /// Like `Poll`, for a poll that returned `Poll::Ready`, which completed the future
+    PollReady {
+        start: u64,
+        end: u64,
+        clock_end: u64,
+        tid: u32,
+    },
+    /// Like `Poll`, for a block timed with `time_block`
+    LabeledBlock {
+        start: u64,
+        end: u64,
+        clock_end: u64,
+        tid: u32,
+        label: String,
+    },
+    /// An event of the executor on a thread, such as its worker parking
+    ExecutorEvent {
+        kind: ExecutorEventKind,
+        tid: u32,
+        tsc: u64,
+    },
+    /// A marker the application recorded with `annotate`. The message is null-terminated
+    UserAnnotation {
+        timestamp_tsc: u64,
+        tag: u32,
+        message: [u8; 64],
+    },
+    /// The recording ended cleanly. Files whose sessions don't end with it were cut short
+    EndOfSession {
+        session_id: u128,
+        /// The polls a profiling signal landed in
+        total_polls: u64,
+        /// The polls recorded in the file
+        total_long_polls: u64,
+    },
+    /// The CPU of the profiled process. `tsc_hz` is 0 if unknown, and `cpu_model` is
+    /// null-terminated
+    CpuInfo {
+        tsc_hz: u64,
+        cpu_model: [u8; 48],
+        cpu_flags: u64,
+    },
 }
 
-#[derive(Debug)]
+/// `Event::CpuInfo::cpu_flags`
+pub const CPU_FLAG_INVARIANT_TSC: u64 = 1 << 0;
+pub const CPU_FLAG_HYPERVISOR: u64 = 1 << 1;
+pub const CPU_FLAG_RDTSCP: u64 = 1 << 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExecutorEventKind {
+    Park,
+    Unpark,
+    SpawnTask,
+    DropTask,
+    /// A kind added after this decoder was written
+    Unknown(u32),
+}
+
+impl ExecutorEventKind {
+    fn from_u32(kind: u32) -> Self {
+        match kind {
+            0 => ExecutorEventKind::Park,
+            1 => ExecutorEventKind::Unpark,
+            2 => ExecutorEventKind::SpawnTask,
+            3 => ExecutorEventKind::DropTask,
+            kind => ExecutorEventKind::Unknown(kind),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum PossiblyUnknownEvent {
     Event(Event),
     UnknownEvent {
-        #[allow(unused)]
         kind: u32,
     },
+    /// A corrupted region skipped by the resilient readers
+    Corrupt {
+        bytes_skipped: u64,
+    },
+    /// Events missing before the next one, according to the sequence numbers
+    Lost {
+        events: u64,
+    },
+    /// The next event came after some with higher sequence numbers. The writers number
+    /// events as they're sent from several threads, so this is expected now and then.
+    Reordered,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct CalibrationData {
     pub src_epoch: u64,
     pub ref_epoch: u64,
@@ -40,6 +205,32 @@ pub struct CalibrationData {
     pub shift: u32,
 }
 
+/// Strings are null-terminated
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessInfoData {
+    pub pid: u32,
+    pub hostname: [u8; 64],
+    pub cmdline: [u8; 256],
+    /// When the process started, in clock ticks after boot as in `/proc/<pid>/stat`, if the
+    /// writer recorded it
+    pub start_time: Option<u64>,
+}
+
+pub fn from_fixed_cstr(bytes: &[u8]) -> Cow<'_, str> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len])
+}
+
+impl ProcessInfoData {
+    pub fn hostname(&self) -> Cow<'_, str> {
+        from_fixed_cstr(&self.hostname)
+    }
+
+    pub fn cmdline(&self) -> Cow<'_, str> {
+        from_fixed_cstr(&self.cmdline)
+    }
+}
+
 #[inline]
 fn mul_div_po2_u64(value: u64, numer: u64, denom: u32) -> u64 {
     // Modified muldiv routine where the denominator has to be a power of two. `denom` is expected
@@ -60,66 +251,876 @@ impl CalibrationData {
     pub fn scale_src_duration_to_ref(&self, delta: u64) -> u64 {
         mul_div_po2_u64(delta, self.mul, self.shift)
     }
+
+    /// A calibration from the TSC frequency of an `Event::CpuInfo`, for files without a
+    /// `CalibrateTscToMonotonic` event. Durations are as exact as the frequency, and
+    /// timestamps are relative to the epochs, which a `WallClockAnchor` provides.
+    pub fn from_tsc_hz(tsc_hz: u64, src_epoch: u64, ref_epoch: u64) -> Option<Self> {
+        const SHIFT: u32 = 32;
+        let mul = ((1_000_000_000u128 << SHIFT) / u128::from(tsc_hz.max(1))).try_into();
+        match (tsc_hz, mul) {
+            (0, _) | (_, Err(_)) => None,
+            (_, Ok(mul)) => Some(CalibrationData {
+                src_epoch,
+                ref_epoch,
+                mul,
+                shift: SHIFT,
+            }),
+        }
+    }
+}
+
+/// The `tag: u8, len: u16, value: [u8; len]` fields of an event body
+struct Fields<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    /// Returns the first `len` bytes of the field with tag `tag`. Fields may be longer than
+    /// expected, to allow extending them later.
+    fn get(&self, tag: u8, len: usize) -> Result<&'a [u8], ReadEventError> {
+        self.field(tag)?
+            .get(..len)
+            .ok_or(ReadEventError::FieldTooShort(tag))
+    }
+
+    /// Returns the whole field with tag `tag`
+    fn field(&self, tag: u8) -> Result<&'a [u8], ReadEventError> {
+        let mut body = self.body;
+        while !body.is_empty() {
+            if body.len() < 3 {
+                return Err(ReadEventError::FieldTruncated);
+            }
+            let field_tag = body[0];
+            let field_len = LittleEndian::read_u16(&body[1..3]) as usize;
+            body = &body[3..];
+            if body.len() < field_len {
+                return Err(ReadEventError::FieldTruncated);
+            }
+            if field_tag == tag {
+                return Ok(&body[..field_len]);
+            }
+            body = &body[field_len..];
+        }
+        Err(ReadEventError::MissingField(tag))
+    }
+
+    fn u32(&self, tag: u8) -> Result<u32, ReadEventError> {
+        Ok(LittleEndian::read_u32(self.get(tag, 4)?))
+    }
+
+    fn u64(&self, tag: u8) -> Result<u64, ReadEventError> {
+        Ok(LittleEndian::read_u64(self.get(tag, 8)?))
+    }
+
+    fn u128(&self, tag: u8) -> Result<u128, ReadEventError> {
+        Ok(LittleEndian::read_u128(self.get(tag, 16)?))
+    }
+
+    fn array<const N: usize>(&self, tag: u8) -> Result<[u8; N], ReadEventError> {
+        Ok(self.get(tag, N)?.try_into().unwrap())
+    }
+
+    fn string(&self, tag: u8) -> Result<String, ReadEventError> {
+        Ok(String::from_utf8_lossy(self.field(tag)?).into_owned())
+    }
 }
 
+fn parse_event(kind: u32, body: &[u8]) -> Result<Option<Event>, ReadEventError> {
+    let f = Fields { body };
+    let event = match kind {
+        0 => Event::Poll {
+            start: f.u64(1)?,
+            end: f.u64(2)?,
+            clock_end: f.u64(3)?,
+            tid: f.u32(4)?,
+        },
+        1 => Event::CalibrateTscToMonotonic {
+            data: CalibrationData {
+                src_epoch: f.u64(1)?,
+                ref_epoch: f.u64(2)?,
+                mul: f.u64(3)?,
+                shift: f.u32(4)?,
+            },
+        },
+        2 => Event::SessionStart {
+            session_id: f.u128(1)?,
+            wall_time_ns: f.u64(2)?,
+            pid: f.u32(3)?,
+        },
+        3 => Event::ProcessInfo {
+            data: Box::new(ProcessInfoData {
+                pid: f.u32(1)?,
+                hostname: f.array(2)?,
+                cmdline: f.array(3)?,
+                start_time: f.u64(4).ok().filter(|&start_time| start_time != 0),
+            }),
+        },
+        4 => Event::WallClockAnchor {
+            tsc: f.u64(1)?,
+            monotonic_ns: f.u64(2)?,
+            realtime_ns: f.u64(3)?,
+        },
+        5 => Event::WriterError {
+            error_code: f.u32(1)?,
+        },
+        6 => Event::ServicePollReady {
+            start: f.u64(1)?,
+            end: f.u64(2)?,
+            clock_end: f.u64(3)?,
+            tid: f.u32(4)?,
+        },
+        7 => Event::Signal {
+            tsc: f.u64(1)?,
+            tid: f.u32(2)?,
+        },
+        8 => Event::ThreadName {
+            tid: f.u32(1)?,
+            name: f.array(2)?,
+        },
+        9 => Event::PollReady {
+            start: f.u64(1)?,
+            end: f.u64(2)?,
+            clock_end: f.u64(3)?,
+            tid: f.u32(4)?,
+        },
+        10 => Event::LabeledBlock {
+            start: f.u64(1)?,
+            end: f.u64(2)?,
+            clock_end: f.u64(3)?,
+            tid: f.u32(4)?,
+            label: f.string(5)?,
+        },
+        11 => Event::ExecutorEvent {
+            kind: ExecutorEventKind::from_u32(f.u32(1)?),
+            tid: f.u32(2)?,
+            tsc: f.u64(3)?,
+        },
+        12 => Event::UserAnnotation {
+            timestamp_tsc: f.u64(1)?,
+            tag: f.u32(2)?,
+            message: f.array(3)?,
+        },
+        13 => Event::EndOfSession {
+            session_id: f.u128(1)?,
+            total_polls: f.u64(2)?,
+            total_long_polls: f.u64(3)?,
+        },
+        14 => Event::CpuInfo {
+            tsc_hz: f.u64(1)?,
+            cpu_model: f.array(2)?,
+            cpu_flags: f.u64(3)?,
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(event))
+}
+
+/// Parses a record body into an event and its sequence number, which records written before
+/// sequence numbers were introduced don't have
+fn parse_body(
+    kind: u32,
+    body: &[u8],
+) -> Result<(PossiblyUnknownEvent, Option<u64>), ReadEventError> {
+    let event = match parse_event(kind, body)? {
+        Some(event) => PossiblyUnknownEvent::Event(event),
+        None => PossiblyUnknownEvent::UnknownEvent { kind },
+    };
+    Ok((event, Fields { body }.u64(0).ok()))
+}
+
+/// Reads a single record.
+///
+/// A record is a `u32` size (including the 8 header bytes), a `u32` kind, and a body made of
+/// `tag: u8, len: u16, value: [u8; len]` fields. Fields with unknown tags are ignored.
+/// Unlike `MmapPrReader`, this does not handle the PR file header or compression.
 pub fn read_event<R: Read + Seek>(
     r: &mut R,
 ) -> Result<Option<PossiblyUnknownEvent>, ReadEventError> {
-    let size = match r.read_u32::<LittleEndian>() {
-        Ok(size) => size,
-        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-            return Ok(None);
+    Ok(read_record(r)?.map(|(event, _)| event))
+}
+
+/// Like `read_event`, but also returns the sequence number of the record
+fn read_record<R: Read + Seek>(
+    r: &mut R,
+) -> Result<Option<(PossiblyUnknownEvent, Option<u64>)>, ReadEventError> {
+    let size = loop {
+        match r.read_u32::<LittleEndian>() {
+            Ok(size) if size.to_le_bytes() == PR_MAGIC => match r.read_u8()? {
+                COMPRESSION_NONE => continue,
+                compression => return Err(ReadEventError::UnknownCompression(compression)),
+            },
+            Ok(size) => break size,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
         }
-        Err(e) => return Err(e.into()),
     };
-    let mut poll_size = 4 + 4;
-    if size < poll_size {
+    if size < 4 + 4 {
         return Err(ReadEventError::SizeTooSmall);
     }
     let kind = r.read_u32::<LittleEndian>()?;
+    let body_size = size - (4 + 4);
+
+    // don't trust the size with a large allocation before the data is actually there
+    let mut body = vec![];
+    r.take(body_size.into()).read_to_end(&mut body)?;
+    if body.len() < body_size as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    parse_body(kind, &body).map(Some)
+}
+
+/// How far past a bad record the resilient readers look for the next good one
+const RESYNC_WINDOW: usize = 4096;
+/// Sizes a record found while resynchronizing may have
+const PLAUSIBLE_RECORD_SIZES: RangeInclusive<u32> = 8..=65536;
+
+/// Parses the record at the start of `data`, returning it along with its sequence number and
+/// its size
+fn parse_record(
+    data: &[u8],
+) -> Result<Option<(PossiblyUnknownEvent, Option<u64>, usize)>, ReadEventError> {
+    let skip = header_len(data);
+    let data = &data[skip..];
+    if data.len() < 4 {
+        return Ok(None);
+    }
+    let size = LittleEndian::read_u32(data);
+    if size < 4 + 4 {
+        return Err(ReadEventError::SizeTooSmall);
+    }
+    let record = data
+        .get(..size as usize)
+        .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    let kind = LittleEndian::read_u32(&record[4..]);
+    let (event, seq) = parse_body(kind, &record[8..])?;
+    Ok(Some((event, seq, skip + size as usize)))
+}
+
+/// Returns the length of the headers of uncompressed PR files at the start of `data`.
+///
+/// Besides at the start of a file, they are found in the middle of files that were
+/// concatenated, or that writers in append mode raced to create. As a size, the magic would
+/// be an implausible 1.3GB, so a header can't be mistaken for a record.
+fn header_len(data: &[u8]) -> usize {
+    let mut len = 0;
+    while let Some([magic @ .., COMPRESSION_NONE]) = data.get(len..len + PR_MAGIC.len() + 1) {
+        if *magic != PR_MAGIC {
+            break;
+        }
+        len += PR_MAGIC.len() + 1;
+    }
+    len
+}
 
-    let res = match kind {
-        0 => {
-            poll_size = 4 + 4 + 8 + 8 + 8 + 4;
-            if size < poll_size {
-                return Err(ReadEventError::SizeTooSmall);
+/// Given `data` starting with a bad record, returns how many bytes to skip to get to the next
+/// plausible record: one with a size in `PLAUSIBLE_RECORD_SIZES` that parses.
+fn find_resync_point(data: &[u8]) -> usize {
+    let window = RESYNC_WINDOW.min(data.len());
+    (1..window)
+        .find(|&skip| {
+            let rest = &data[skip..];
+            rest.len() >= 4
+                && PLAUSIBLE_RECORD_SIZES.contains(&LittleEndian::read_u32(rest))
+                && matches!(parse_record(rest), Ok(Some(_)))
+        })
+        .unwrap_or(window)
+}
+
+/// How far behind the highest sequence number so far a record can arrive and still count as
+/// reordered rather than lost
+const REORDER_WINDOW: u64 = 1024;
+
+/// Reports gaps in the sequence numbers of records as `PossiblyUnknownEvent::Lost`, once the
+/// missing records are too far behind to still arrive, and records that arrive late as
+/// `PossiblyUnknownEvent::Reordered`
+#[derive(Default)]
+struct GapDetector {
+    /// One past the highest sequence number so far
+    next_seq: Option<u64>,
+    /// The sequence numbers skipped within `REORDER_WINDOW` of `next_seq`
+    missing: BTreeSet<u64>,
+    /// The events to return before reading the next record
+    pending: VecDeque<PossiblyUnknownEvent>,
+}
+
+impl GapDetector {
+    /// Queues the events to yield for the record `event` with sequence number `seq`
+    fn push(&mut self, event: PossiblyUnknownEvent, seq: Option<u64>) {
+        let Some(seq) = seq else {
+            self.pending.push_back(event);
+            return;
+        };
+        let mut lost = 0;
+        match self.next_seq {
+            Some(next_seq) if seq >= next_seq => {
+                let window_start = seq.saturating_sub(REORDER_WINDOW).max(next_seq);
+                lost += window_start - next_seq;
+                self.missing.extend(window_start..seq);
+                self.next_seq = Some(seq + 1);
+                let still_missing = self
+                    .missing
+                    .split_off(&(seq + 1).saturating_sub(REORDER_WINDOW));
+                lost += std::mem::replace(&mut self.missing, still_missing).len() as u64;
             }
-            let start = r.read_u64::<LittleEndian>()?;
-            let end = r.read_u64::<LittleEndian>()?;
-            let clock_end = r.read_u64::<LittleEndian>()?;
-            let tid = r.read_u32::<LittleEndian>()?;
-
-            PossiblyUnknownEvent::Event(Event::Poll {
-                start,
-                end,
-                clock_end,
-                tid,
-            })
-        }
-        1 => {
-            poll_size = 4 + 4 + 8 + 8 + 8 + 4;
-            if size < poll_size {
-                return Err(ReadEventError::SizeTooSmall);
+            Some(_) if self.missing.remove(&seq) => {
+                self.pending.push_back(PossiblyUnknownEvent::Reordered);
+            }
+            // a lower sequence number that wasn't missing is a new writer, e.g. after a restart
+            _ => {
+                lost += std::mem::take(&mut self.missing).len() as u64;
+                self.next_seq = Some(seq + 1);
+            }
+        }
+        if lost > 0 {
+            self.pending
+                .push_back(PossiblyUnknownEvent::Lost { events: lost });
+        }
+        self.pending.push_back(event);
+    }
+
+    /// Queues the `Lost` for the records still missing at the end
+    fn finish(&mut self) {
+        let lost = std::mem::take(&mut self.missing).len() as u64;
+        if lost > 0 {
+            self.pending
+                .push_back(PossiblyUnknownEvent::Lost { events: lost });
+        }
+    }
+}
+
+/// Reads a PR file through a memory map, parsing events in place.
+///
+/// Compressed files are decompressed into memory when opened, and ring-buffer files are
+/// read into memory and put into chronological order.
+pub struct MmapPrReader {
+    /// `None` for ring-buffer files, which are never mapped
+    mmap: Option<Mmap>,
+    /// Where the records start in `mmap`
+    offset: usize,
+    /// The records, if they can't be read from `mmap` directly
+    owned: Option<Vec<u8>>,
+}
+
+impl MmapPrReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ReadEventError> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; RING_MAGIC.len()];
+        if file.read_exact(&mut magic).is_ok() && magic == RING_MAGIC {
+            // a live ring buffer is overwritten in place, so it can't be mapped. The copy
+            // may still catch a record being written, which reads as corrupt data.
+            file.rewind()?;
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            let owned = match data.get(..RING_HEADER_LEN) {
+                Some(header) => {
+                    let head = LittleEndian::read_u64(&header[8..]) as usize;
+                    let oldest = LittleEndian::read_u64(&header[16..]) as usize;
+                    linearize_ring(&data[RING_HEADER_LEN..], head, oldest)
+                }
+                None => vec![],
+            };
+            return Ok(MmapPrReader {
+                mmap: None,
+                offset: 0,
+                owned: Some(owned),
+            });
+        }
+        // safety: the file must not be truncated or overwritten while it's mapped. PR files
+        // other than ring buffers are only ever appended to.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let (offset, compression) = match mmap.get(..PR_MAGIC.len() + 1) {
+            Some([magic @ .., compression]) if *magic == PR_MAGIC => {
+                (PR_MAGIC.len() + 1, *compression)
+            }
+            _ => (0, COMPRESSION_NONE),
+        };
+        let owned = match compression {
+            COMPRESSION_NONE => None,
+            COMPRESSION_ZSTD => {
+                let mut data = vec![];
+                match zstd::Decoder::new(&mmap[offset..])?.read_to_end(&mut data) {
+                    // a file cut short by a crash ends with a partial frame, keep what we got
+                    Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e.into()),
+                    _ => {}
+                }
+                Some(data)
+            }
+            compression => return Err(ReadEventError::UnknownCompression(compression)),
+        };
+        Ok(MmapPrReader {
+            mmap: Some(mmap),
+            offset,
+            owned,
+        })
+    }
+
+    pub fn events(&self) -> MmapPrEventIter<'_> {
+        MmapPrEventIter {
+            data: match (&self.owned, &self.mmap) {
+                (Some(data), _) => data,
+                (None, Some(mmap)) => &mmap[self.offset..],
+                (None, None) => unreachable!("ring-buffer files are read into memory"),
+            },
+            resilient: false,
+            done: false,
+            gaps: GapDetector::default(),
+        }
+    }
+}
+
+/// Iterates over the events of a PR file in memory, stopping after the first error. Records
+/// are parsed lazily, one at a time, so a large file doesn't need more memory than its mapping.
+pub struct MmapPrEventIter<'a> {
+    data: &'a [u8],
+    resilient: bool,
+    done: bool,
+    gaps: GapDetector,
+}
+
+impl MmapPrEventIter<'_> {
+    /// If `resilient`, skip over a corrupted record to the next plausible one, returning a
+    /// `PossiblyUnknownEvent::Corrupt` for the skipped bytes, so that what's left of a PR file
+    /// damaged e.g. by a crash can be read
+    pub fn resilient(mut self, resilient: bool) -> Self {
+        self.resilient = resilient;
+        self
+    }
+
+    /// The number of bytes left to read
+    pub fn remaining_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Reads the next record into `self.gaps`, returning whether there was one
+    fn read_event(&mut self) -> Result<bool, ReadEventError> {
+        match parse_record(self.data) {
+            Ok(Some((event, seq, size))) => {
+                self.data = &self.data[size..];
+                self.gaps.push(event, seq);
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(_) if self.resilient => {
+                let skip = find_resync_point(self.data);
+                self.data = &self.data[skip..];
+                let corrupt = PossiblyUnknownEvent::Corrupt {
+                    bytes_skipped: skip as u64,
+                };
+                self.gaps.push(corrupt, None);
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Iterator for MmapPrEventIter<'_> {
+    type Item = Result<PossiblyUnknownEvent, ReadEventError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.gaps.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.done {
+                return None;
+            }
+            match self.read_event() {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.done = true;
+                    self.gaps.finish();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
             }
-            let src_epoch = r.read_u64::<LittleEndian>()?;
-            let ref_epoch = r.read_u64::<LittleEndian>()?;
-            let mul = r.read_u64::<LittleEndian>()?;
-            let shift = r.read_u32::<LittleEndian>()?;
-
-            PossiblyUnknownEvent::Event(Event::CalibrateTscToMonotonic {
-                data: CalibrationData {
-                    src_epoch,
-                    ref_epoch,
-                    mul,
-                    shift,
-                },
-            })
         }
-        _ => PossiblyUnknownEvent::UnknownEvent { kind },
+    }
+}
+
+/// Writes an event with sequence number `seq` with the pollcatch writer's serializer. Executor
+/// events of unknown kinds can't be written.
+pub fn write_event<W: Write>(w: &mut W, seq: u64, e: &Event) -> io::Result<()> {
+    use pollcatch::Event as Pc;
+    let e = match e.clone() {
+        Event::Poll {
+            start,
+            end,
+            clock_end,
+            tid,
+        } => Pc::Poll {
+            start,
+            end,
+            clock_end,
+            tid,
+        },
+        Event::CalibrateTscToMonotonic { data } => Pc::CalibrateTscToMonotonic {
+            data: pollcatch::CalibrationData {
+                src_epoch: data.src_epoch,
+                ref_epoch: data.ref_epoch,
+                mul: data.mul,
+                shift: data.shift,
+            },
+        },
+        Event::SessionStart {
+            session_id,
+            wall_time_ns,
+            pid,
+        } => Pc::SessionStart {
+            session_id,
+            wall_time_ns,
+            pid,
+        },
+        Event::ProcessInfo { data } => Pc::ProcessInfo {
+            data: Box::new(pollcatch::ProcessInfoData {
+                pid: data.pid,
+                hostname: data.hostname,
+                cmdline: data.cmdline,
+                start_time: data.start_time.unwrap_or(0),
+            }),
+        },
+        Event::WallClockAnchor {
+            tsc,
+            monotonic_ns,
+            realtime_ns,
+        } => Pc::WallClockAnchor {
+            tsc,
+            monotonic_ns,
+            realtime_ns,
+        },
+        Event::WriterError { error_code } => Pc::WriterError { error_code },
+        Event::ServicePollReady {
+            start,
+            end,
+            clock_end,
+            tid,
+        } => Pc::ServicePollReady {
+            start,
+            end,
+            clock_end,
+            tid,
+        },
+        Event::Signal { tsc, tid } => Pc::Signal { tsc, tid },
+        Event::ThreadName { tid, name } => Pc::ThreadName { tid, name },
+        Event::PollReady {
+            start,
+            end,
+            clock_end,
+            tid,
+        } => Pc::PollReady {
+            start,
+            end,
+            clock_end,
+            tid,
+        },
+        Event::LabeledBlock {
+            start,
+            end,
+            clock_end,
+            tid,
+            label,
+        } => Pc::LabeledBlock {
+            start,
+            end,
+            clock_end,
+            tid,
+            label: intern_label(&label),
+        },
+        Event::ExecutorEvent { kind, tid, tsc } => Pc::ExecutorEvent {
+            kind: match kind {
+                ExecutorEventKind::Park => pollcatch::ExecutorEventKind::Park,
+                ExecutorEventKind::Unpark => pollcatch::ExecutorEventKind::Unpark,
+                ExecutorEventKind::SpawnTask => pollcatch::ExecutorEventKind::SpawnTask,
+                ExecutorEventKind::DropTask => pollcatch::ExecutorEventKind::DropTask,
+                ExecutorEventKind::Unknown(kind) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown executor event kind {}", kind),
+                    ))
+                }
+            },
+            tid,
+            tsc,
+        },
+        Event::UserAnnotation {
+            timestamp_tsc,
+            tag,
+            message,
+        } => Pc::UserAnnotation {
+            timestamp_tsc,
+            tag,
+            message,
+        },
+        Event::EndOfSession {
+            session_id,
+            total_polls,
+            total_long_polls,
+        } => Pc::EndOfSession {
+            session_id,
+            total_polls,
+            total_long_polls,
+        },
+        Event::CpuInfo {
+            tsc_hz,
+            cpu_model,
+            cpu_flags,
+        } => Pc::CpuInfo {
+            tsc_hz,
+            cpu_model,
+            cpu_flags,
+        },
+    };
+    pollcatch::pr::write_record(w, seq, e)
+}
+
+/// `label` as the `&'static str` pollcatch records labels as, leaking each distinct label
+/// once. Labels are string literals of the profiled program, so there are few of them.
+fn intern_label(label: &str) -> &'static str {
+    static LABELS: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    let mut labels = LABELS.lock().unwrap();
+    match labels.get(label) {
+        Some(label) => label,
+        None => {
+            let label = Box::leak(label.into());
+            labels.insert(label);
+            label
+        }
+    }
+}
+
+/// The events of the records in `data`
+#[cfg(test)]
+fn events_in(data: &[u8], resilient: bool) -> Result<Vec<PossiblyUnknownEvent>, ReadEventError> {
+    MmapPrEventIter {
+        data,
+        resilient,
+        done: false,
+        gaps: GapDetector::default(),
+    }
+    .collect()
+}
+
+#[test]
+fn test_write_event() -> Result<(), ReadEventError> {
+    let mut buf = io::Cursor::new(vec![]);
+    write_event(
+        &mut buf,
+        0,
+        &Event::Poll {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        },
+    )?;
+    write_event(
+        &mut buf,
+        1,
+        &Event::CalibrateTscToMonotonic {
+            data: CalibrationData {
+                src_epoch: 1,
+                ref_epoch: 2,
+                mul: 3,
+                shift: 4,
+            },
+        },
+    )?;
+    write_event(
+        &mut buf,
+        2,
+        &Event::SessionStart {
+            session_id: 1 << 100,
+            wall_time_ns: 2,
+            pid: 3,
+        },
+    )?;
+    write_event(
+        &mut buf,
+        3,
+        &Event::ServicePollReady {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        },
+    )?;
+    let mut name = [0; 16];
+    name[..4].copy_from_slice(b"main");
+    write_event(&mut buf, 4, &Event::ThreadName { tid: 4, name })?;
+    write_event(
+        &mut buf,
+        5,
+        &Event::PollReady {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        },
+    )?;
+    write_event(
+        &mut buf,
+        6,
+        &Event::LabeledBlock {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+            label: "parse".to_owned(),
+        },
+    )?;
+    write_event(
+        &mut buf,
+        7,
+        &Event::ExecutorEvent {
+            kind: ExecutorEventKind::Unpark,
+            tid: 4,
+            tsc: 5,
+        },
+    )?;
+    let mut message = [0; 64];
+    message[..6].copy_from_slice(b"deploy");
+    write_event(
+        &mut buf,
+        8,
+        &Event::UserAnnotation {
+            timestamp_tsc: 1,
+            tag: 2,
+            message,
+        },
+    )?;
+    let mut cpu_model = [0; 48];
+    cpu_model[..3].copy_from_slice(b"cpu");
+    write_event(
+        &mut buf,
+        9,
+        &Event::CpuInfo {
+            tsc_hz: 1,
+            cpu_model,
+            cpu_flags: CPU_FLAG_INVARIANT_TSC,
+        },
+    )?;
+    write_event(
+        &mut buf,
+        10,
+        &Event::EndOfSession {
+            session_id: 1 << 100,
+            total_polls: 2,
+            total_long_polls: 3,
+        },
+    )?;
+    buf.set_position(0);
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::Poll {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        })) => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::CalibrateTscToMonotonic {
+            data:
+                CalibrationData {
+                    src_epoch: 1,
+                    ref_epoch: 2,
+                    mul: 3,
+                    shift: 4,
+                },
+        })) => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::SessionStart {
+            session_id,
+            wall_time_ns: 2,
+            pid: 3,
+        })) if session_id == 1 << 100 => {}
+        e => panic!("bad event {:?}", e),
     };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::ServicePollReady {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        })) => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::ThreadName { tid: 4, name }))
+            if from_fixed_cstr(&name) == "main" => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::PollReady {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        })) => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::LabeledBlock {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+            label,
+        })) if label == "parse" => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::ExecutorEvent {
+            kind: ExecutorEventKind::Unpark,
+            tid: 4,
+            tsc: 5,
+        })) => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::UserAnnotation {
+            timestamp_tsc: 1,
+            tag: 2,
+            message,
+        })) if from_fixed_cstr(&message) == "deploy" => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::CpuInfo {
+            tsc_hz: 1,
+            cpu_model,
+            cpu_flags: CPU_FLAG_INVARIANT_TSC,
+        })) if from_fixed_cstr(&cpu_model) == "cpu" => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        Some(PossiblyUnknownEvent::Event(Event::EndOfSession {
+            session_id,
+            total_polls: 2,
+            total_long_polls: 3,
+        })) if session_id == 1 << 100 => {}
+        e => panic!("bad event {:?}", e),
+    };
+    match read_event(&mut buf)? {
+        None => {}
+        e => panic!("bad event {:?}", e),
+    };
+    Ok(())
+}
 
-    r.seek_relative((size - poll_size).into())?;
-    return Ok(Some(res));
+#[test]
+fn test_calibration_from_tsc_hz() {
+    let calibration = CalibrationData::from_tsc_hz(2_000_000_000, 100, 1_000).unwrap();
+    assert_eq!(calibration.scale_src_duration_to_ref(2_000), 1_000);
+    assert_eq!(calibration.scale_src_to_ref(2_100), 2_000);
+    assert!(CalibrationData::from_tsc_hz(0, 0, 0).is_none());
 }
 
 #[test]
@@ -127,14 +1128,18 @@ fn test_read_event() -> Result<(), ReadEventError> {
     let mut buf = io::Cursor::new(vec![
         // unknown event of type 0x12345678
         16, 0, 0, 0, 0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, // poll event
-        36, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0,
-        0, 0, 4, 0, 0, 0, // poll event with extra data
-        40, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0,
-        0, 0, 4, 0, 0, 0, 1, 2, 3, 4, // calibration event
-        36, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0,
-        0, 0, 4, 0, 0, 0, // calibration event with extra data
-        40, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0,
-        0, 0, 4, 0, 0, 0, 1, 2, 3, 4, // another unknown event of type 0x12345679
+        48, 0, 0, 0, 0, 0, 0, 0, 1, 8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 8, 0, 2, 0, 0, 0, 0, 0, 0, 0,
+        3, 8, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 4, 0, 4, 0, 0, 0,
+        // poll event with an unknown field
+        55, 0, 0, 0, 0, 0, 0, 0, 1, 8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 100, 4, 0, 1, 2, 3, 4, 2, 8, 0, 2,
+        0, 0, 0, 0, 0, 0, 0, 3, 8, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 4, 0, 4, 0, 0, 0,
+        // calibration event
+        48, 0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 8, 0, 2, 0, 0, 0, 0, 0, 0, 0,
+        3, 8, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 4, 0, 4, 0, 0, 0,
+        // calibration event with an unknown field
+        55, 0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 8, 0, 2, 0, 0, 0, 0, 0, 0, 0,
+        3, 8, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 4, 0, 4, 0, 0, 0, 100, 4, 0, 1, 2, 3, 4,
+        // another unknown event of type 0x12345679
         16, 0, 0, 0, 0x79, 0x56, 0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0,
     ]);
     match read_event(&mut buf)? {
@@ -193,3 +1198,332 @@ fn test_read_event() -> Result<(), ReadEventError> {
     };
     Ok(())
 }
+
+#[test]
+fn test_read_event_resilient() -> Result<(), ReadEventError> {
+    let poll = Event::Poll {
+        start: 1,
+        end: 2,
+        clock_end: 3,
+        tid: 4,
+    };
+    let mut data = vec![];
+    write_event(&mut data, 0, &poll)?;
+    data.extend_from_slice(&[0xff; 5]);
+    write_event(&mut data, 1, &poll)?;
+    // a record cut short by a crash
+    data.extend_from_slice(&[48, 0, 0, 0, 0, 0, 0, 0]);
+
+    let events = events_in(&data, true)?;
+    assert_eq!(events.len(), 4, "{:?}", events);
+    assert!(matches!(
+        events[0],
+        PossiblyUnknownEvent::Event(Event::Poll { .. })
+    ));
+    assert!(matches!(
+        events[1],
+        PossiblyUnknownEvent::Corrupt { bytes_skipped: 5 }
+    ));
+    assert!(matches!(
+        events[2],
+        PossiblyUnknownEvent::Event(Event::Poll { .. })
+    ));
+    assert!(matches!(
+        events[3],
+        PossiblyUnknownEvent::Corrupt { bytes_skipped: 8 }
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_lost_events() -> Result<(), ReadEventError> {
+    let poll = Event::Poll {
+        start: 1,
+        end: 2,
+        clock_end: 3,
+        tid: 4,
+    };
+    let mut data = vec![];
+    let far = 5 + REORDER_WINDOW;
+    for seq in [0, 1, 4, 5, far, 0] {
+        write_event(&mut data, seq, &poll)?;
+    }
+    let events = events_in(&data, false)?;
+    let lost = |events| PossiblyUnknownEvent::Lost { events };
+    let poll = PossiblyUnknownEvent::Event(poll);
+    // 2 and 3 are only lost once they're too far behind to be reordered, and the ones
+    // before `far` are when the writer restarts
+    assert_eq!(
+        events,
+        [
+            poll.clone(),
+            poll.clone(),
+            poll.clone(),
+            poll.clone(),
+            lost(2),
+            poll.clone(),
+            lost(far - 6),
+            poll.clone()
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_reordered_events() -> Result<(), ReadEventError> {
+    let poll = |start| Event::Poll {
+        start,
+        end: 2,
+        clock_end: 3,
+        tid: 4,
+    };
+    let mut data = vec![];
+    for seq in [0, 2, 1, 4, 5, 7] {
+        write_event(&mut data, seq, &poll(seq))?;
+    }
+    let events = events_in(&data, false)?;
+    let poll = |start| PossiblyUnknownEvent::Event(poll(start));
+    // 3 and 6 never come, which is only known at the end
+    let expected = [
+        poll(0),
+        poll(2),
+        PossiblyUnknownEvent::Reordered,
+        poll(1),
+        poll(4),
+        poll(5),
+        poll(7),
+        PossiblyUnknownEvent::Lost { events: 2 },
+    ];
+    assert_eq!(events, expected);
+    Ok(())
+}
+
+#[test]
+fn test_open_compressed() -> Result<(), ReadEventError> {
+    let path = std::env::temp_dir().join(format!("pollcatch-zstd-{}.pr", std::process::id()));
+    {
+        let mut w = File::create(&path)?;
+        w.write_all(&PR_MAGIC)?;
+        w.write_all(&[COMPRESSION_ZSTD])?;
+        let mut w = zstd::Encoder::new(w, 0)?.auto_finish();
+        for i in 0..3 {
+            write_event(
+                &mut w,
+                i,
+                &Event::Poll {
+                    start: i,
+                    end: i + 1,
+                    clock_end: i + 2,
+                    tid: 4,
+                },
+            )?;
+        }
+    }
+    let events: Vec<_> = MmapPrReader::open(&path)?
+        .events()
+        .collect::<Result<_, _>>()?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(events.len(), 3, "{:?}", events);
+    assert!(matches!(
+        events[2],
+        PossiblyUnknownEvent::Event(Event::Poll { start: 2, .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_appended_sessions() -> Result<(), ReadEventError> {
+    let session_start = |session_id| Event::SessionStart {
+        session_id,
+        wall_time_ns: 0,
+        pid: 1,
+    };
+    let poll = Event::Poll {
+        start: 1,
+        end: 2,
+        clock_end: 3,
+        tid: 4,
+    };
+    // two runs appending to the same file, both of which found it empty and wrote the header
+    let mut data = vec![];
+    for session_id in [1, 2] {
+        write_header(&mut data)?;
+        write_event(&mut data, 0, &session_start(session_id))?;
+        write_event(&mut data, 1, &poll)?;
+    }
+    let path = std::env::temp_dir().join(format!("pollcatch-append-{}.pr", std::process::id()));
+    std::fs::write(&path, &data)?;
+    let events: Vec<_> = MmapPrReader::open(&path)?
+        .events()
+        .collect::<Result<_, _>>()?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(events.len(), 4, "{:?}", events);
+    assert_eq!(events[2], PossiblyUnknownEvent::Event(session_start(2)));
+    assert_eq!(events[3], PossiblyUnknownEvent::Event(poll));
+    Ok(())
+}
+
+#[test]
+fn test_linearize_ring() {
+    let record = |size: u8| {
+        let mut record = vec![size; size as usize];
+        record[..4].copy_from_slice(&(size as u32).to_le_bytes());
+        record
+    };
+    // the newest record at 0, the rest of a 12-byte record it partially overwrote, the
+    // oldest record, the end marker and leftovers from before the last wrap
+    let mut records = record(8);
+    records.extend_from_slice(&record(12)[8..]);
+    records.extend(record(16));
+    records.extend_from_slice(&[0; 4]);
+    records.extend_from_slice(&[0xee; 8]);
+    assert_eq!(records.len(), 40);
+    // after wrapping, with the oldest record after the head
+    let mut expected = record(16);
+    expected.extend(record(8));
+    assert_eq!(linearize_ring(&records, 8, 12), expected);
+    // before wrapping
+    assert_eq!(linearize_ring(&records, 8, 0), record(8));
+    // empty
+    assert_eq!(linearize_ring(&records, 0, 0), Vec::<u8>::new());
+}
+
+#[test]
+fn test_open_ring() -> Result<(), ReadEventError> {
+    let poll = |start| Event::Poll {
+        start,
+        end: 2,
+        clock_end: 3,
+        tid: 4,
+    };
+    let mut first = vec![];
+    write_event(&mut first, 0, &poll(0))?;
+    let mut records = vec![];
+    write_event(&mut records, 1, &poll(1))?;
+    records.extend(&first);
+    // the head is after the newest record, at the start, and the oldest record is after it
+    let mut data = RING_MAGIC.to_vec();
+    data.extend((first.len() as u64).to_le_bytes());
+    data.extend((first.len() as u64).to_le_bytes());
+    data.extend(&records);
+    let path = std::env::temp_dir().join(format!("pollcatch-ring-{}.pr", std::process::id()));
+    std::fs::write(&path, &data)?;
+    let events: Vec<_> = MmapPrReader::open(&path)?
+        .events()
+        .collect::<Result<_, _>>()?;
+    std::fs::remove_file(&path)?;
+    let poll = |start| PossiblyUnknownEvent::Event(poll(start));
+    assert_eq!(events, [poll(0), poll(1)]);
+    Ok(())
+}
+
+/// Compares reading records one at a time from a stream, as `follow` does, with the
+/// memory-mapped reader on a large synthetic PR file.
+///
+/// Run with `cargo test --release -- --ignored --nocapture bench_readers`
+#[test]
+#[ignore]
+fn bench_readers() -> Result<(), ReadEventError> {
+    let path = std::env::temp_dir().join(format!("pollcatch-bench-{}.pr", std::process::id()));
+    {
+        let mut w = io::BufWriter::new(File::create(&path)?);
+        for i in 0..10_000_000 {
+            write_event(
+                &mut w,
+                i,
+                &Event::Poll {
+                    start: i,
+                    end: i + 1,
+                    clock_end: i + 2,
+                    tid: 4,
+                },
+            )?;
+        }
+        w.flush()?;
+    }
+
+    let start = std::time::Instant::now();
+    let mut count = 0;
+    let mut reader = io::BufReader::new(File::open(&path)?);
+    while read_event(&mut reader)?.is_some() {
+        count += 1;
+    }
+    println!("streaming: {} events in {:?}", count, start.elapsed());
+
+    let start = std::time::Instant::now();
+    let mut count = 0;
+    for event in MmapPrReader::open(&path)?.events() {
+        event?;
+        count += 1;
+    }
+    println!("mmap: {} events in {:?}", count, start.elapsed());
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn read_event_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..1024)) {
+        let mut cursor = io::Cursor::new(&bytes[..]);
+        // errors are fine, panics are not
+        while let Ok(Some(_)) = read_event(&mut cursor) {}
+    }
+
+    #[test]
+    fn read_event_well_formed_header(
+        kind: u32,
+        body in prop::collection::vec(any::<u8>(), 0..256),
+        trailer in prop::collection::vec(any::<u8>(), 0..64),
+    ) {
+        let size = 4 + 4 + body.len();
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&(size as u32).to_le_bytes());
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes.extend_from_slice(&trailer);
+        let mut cursor = io::Cursor::new(&bytes[..]);
+        match read_event(&mut cursor) {
+            Err(ReadEventError::SizeTooSmall) => prop_assert!(false, "size {} is fine", size),
+            // the body is random, so its fields may be bad, but the record is still consumed
+            Ok(Some(_))
+            | Err(
+                ReadEventError::FieldTruncated
+                | ReadEventError::MissingField(_)
+                | ReadEventError::FieldTooShort(_),
+            ) => prop_assert_eq!(cursor.position(), size as u64),
+            res => prop_assert!(false, "unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn poll_round_trip(start: u64, end: u64, clock_end: u64, tid: u32, seq: u64) {
+        let event = Event::Poll { start, end, clock_end, tid };
+        let mut cursor = io::Cursor::new(vec![]);
+        write_event(&mut cursor, seq, &event)?;
+        let len = cursor.position();
+        cursor.set_position(0);
+        match read_event(&mut cursor)? {
+            Some(PossiblyUnknownEvent::Event(read)) => prop_assert_eq!(read, event),
+            res => prop_assert!(false, "unexpected {:?}", res),
+        }
+        prop_assert_eq!(cursor.position(), len);
+    }
+
+    #[test]
+    fn calibration_round_trip(src_epoch: u64, ref_epoch: u64, mul: u64, shift: u32, seq: u64) {
+        let data = CalibrationData { src_epoch, ref_epoch, mul, shift };
+        let event = Event::CalibrateTscToMonotonic { data };
+        let mut cursor = io::Cursor::new(vec![]);
+        write_event(&mut cursor, seq, &event)?;
+        cursor.set_position(0);
+        match read_event(&mut cursor)? {
+            Some(PossiblyUnknownEvent::Event(read)) => prop_assert_eq!(read, event),
+            res => prop_assert!(false, "unexpected {:?}", res),
+        }
+    }
+}
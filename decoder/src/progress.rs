@@ -0,0 +1,103 @@
+//! Progress on stderr while reading large files. This is a single line redrawn at most every
+//! `REDRAW_INTERVAL`, which is all the decoder needs, so it's written here rather than pulling
+//! in `indicatif` and its terminal handling dependencies for it.
+
+use std::{
+    cell::Cell,
+    io::{self, IsTerminal, Read, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
+
+/// How often the progress line is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A progress line on stderr while reading a large file, so that the decoder doesn't look
+/// hung. Only drawn if stderr is a terminal, and erased once dropped, before the output that
+/// follows.
+pub struct Progress {
+    label: &'static str,
+    total: u64,
+    enabled: bool,
+    /// When the line was last drawn, or else created, so that it's only drawn for files that
+    /// take a while
+    last_draw: Cell<Instant>,
+    drawn: Cell<bool>,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: u64) -> Self {
+        Progress {
+            label,
+            total,
+            enabled: io::stderr().is_terminal(),
+            last_draw: Cell::new(Instant::now()),
+            drawn: Cell::new(false),
+        }
+    }
+
+    /// Shows that `position` bytes out of the total are done
+    pub fn set(&self, position: u64) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        if now.duration_since(self.last_draw.get()) < REDRAW_INTERVAL {
+            return;
+        }
+        self.last_draw.set(now);
+        self.drawn.set(true);
+        const MIB: u64 = 1 << 20;
+        let percent = (position * 100).checked_div(self.total).unwrap_or(100);
+        eprint!(
+            "\r{}: {}/{} MiB ({}%)",
+            self.label,
+            position / MIB,
+            self.total / MIB,
+            percent.min(100)
+        );
+        io::stderr().flush().ok();
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.drawn.get() {
+            // erase the line
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+/// Shows the progress of reading a file through it
+pub struct ProgressReader<R> {
+    inner: R,
+    position: u64,
+    progress: Progress,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, label: &'static str, len: u64) -> Self {
+        ProgressReader {
+            inner,
+            position: 0,
+            progress: Progress::new(label, len),
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        self.progress.set(self.position);
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for ProgressReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        self.progress.set(self.position);
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,206 @@
+//! Writes long polls as a trace in the Chrome JSON trace event format, which the Perfetto UI
+//! opens, and serves it to ui.perfetto.dev the way Perfetto's own `open_trace_in_ui` script
+//! does: on localhost port 9001, which the UI accepts `?url=` links to.
+//!
+//! This is not an implementation of `trace_processor`'s HTTP RPC (`/status`, `/parse`,
+//! `/query`). The UI sends arbitrary SQL over `/query`, against tables and modules of
+//! `trace_processor`'s own standard library, so answering it takes `trace_processor` itself.
+//! A Chrome JSON trace is imported by the UI's own copy of it instead.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+
+use crate::{loki::json_string, Sample};
+
+/// The port the Perfetto UI allows opening traces from with a `?url=` link
+pub const DEFAULT_PORT: u16 = 9001;
+
+/// The origin of the Perfetto UI, which is allowed to fetch the trace from the browser
+const PERFETTO_UI_ORIGIN: &str = "https://ui.perfetto.dev";
+
+/// The `pid` of all the events, since the samples don't say which process they're from
+const PID: u32 = 1;
+
+/// The long polls in `samples` as a Chrome JSON trace: a complete (`X`) event for each poll on
+/// its thread, from the start of its latest sample's poll to that sample, named after the
+/// sample's top frame and with its stack trace as an argument, and the names of the threads as
+/// metadata (`M`) events.
+pub fn chrome_trace(samples: &[Sample]) -> String {
+    let mut json = String::from(r#"{"displayTimeUnit":"ms","traceEvents":["#);
+    let mut events = Vec::new();
+    let mut threads = BTreeMap::new();
+    for sample in crate::latest_samples(samples) {
+        threads
+            .entry(sample.thread_id)
+            .or_insert(&sample.thread_name);
+        let name = sample
+            .frames
+            .first()
+            .map_or_else(|| sample.kind(), ToString::to_string);
+        let stack = sample
+            .frames
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let start = sample.start_time.saturating_sub(sample.delta_t);
+        let mut event = String::new();
+        write!(
+            event,
+            concat!(
+                r#"{{"ph":"X","cat":"long_poll","name":{},"pid":{},"tid":{},"ts":{:.3},"#,
+                r#""dur":{:.3},"args":{{"kind":{},"stack":{}}}}}"#
+            ),
+            json_string(&name),
+            PID,
+            sample.thread_id,
+            start.as_nanos() as f64 / 1_000.0,
+            sample.delta_t.as_nanos() as f64 / 1_000.0,
+            json_string(&sample.kind()),
+            json_string(&stack)
+        )
+        .unwrap();
+        events.push(event);
+    }
+    events.push(format!(
+        r#"{{"ph":"M","name":"process_name","pid":{},"args":{{"name":"pollcatch"}}}}"#,
+        PID
+    ));
+    for (tid, name) in threads {
+        if let Some(name) = name {
+            events.push(format!(
+                r#"{{"ph":"M","name":"thread_name","pid":{},"tid":{},"args":{{"name":{}}}}}"#,
+                PID,
+                tid,
+                json_string(name)
+            ));
+        }
+    }
+    json.push_str(&events.join(",\n"));
+    json.push_str("]}\n");
+    json
+}
+
+/// Lets the UI fetch the trace from the browser
+async fn allow_perfetto_ui(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static(PERFETTO_UI_ORIGIN),
+    );
+    response
+}
+
+/// Serves `trace`, a Chrome JSON trace, on localhost until interrupted
+pub fn serve(trace: String, port: u16) -> anyhow::Result<()> {
+    let trace = Arc::new(trace);
+    let app = Router::new()
+        .route(
+            "/trace.json",
+            get(|| async move {
+                let trace = String::clone(&trace);
+                ([(header::CONTENT_TYPE, "application/json")], trace).into_response()
+            }),
+        )
+        .layer(axum::middleware::map_response(allow_perfetto_ui));
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    runtime.block_on(async {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        eprintln!(
+            "serving the trace on http://{addr}/trace.json, open it with \
+             {PERFETTO_UI_ORIGIN}/#!/?url=http://{addr}/trace.json"
+        );
+        axum::serve(listener, app).await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::chrome_trace;
+    use crate::{Sample, StackFrame};
+
+    fn sample(thread_id: i64, start_ms: u64, delta_t_ms: u64, poll_start: u64) -> Sample {
+        Sample {
+            delta_t: Duration::from_millis(delta_t_ms),
+            start_time: Duration::from_millis(start_ms),
+            thread_id,
+            thread_name: Some(format!("worker \"{}\"", thread_id)),
+            session_id: None,
+            wall_time: None,
+            service_ready: false,
+            ready: false,
+            label: None,
+            poll_start: Some(poll_start),
+            parked: None,
+            frames: vec![
+                StackFrame {
+                    class_name: Some("my_crate".to_owned()),
+                    name: Some("work".to_owned()),
+                },
+                StackFrame {
+                    class_name: Some("tokio".to_owned()),
+                    name: Some("poll".to_owned()),
+                },
+            ],
+        }
+    }
+
+    /// Checks the fields that the Perfetto UI's JSON trace importer, like Chrome's
+    /// `about:tracing`, needs of each kind of event
+    #[test]
+    fn valid_chrome_trace() {
+        let json = chrome_trace(&[
+            sample(1, 110, 10, 100),
+            // a later sample of the same poll
+            sample(1, 130, 30, 100),
+            sample(2, 50, 20, 30),
+        ]);
+        let trace: Value = serde_json::from_str(&json).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let polls: Vec<_> = events.iter().filter(|event| event["ph"] == "X").collect();
+        assert_eq!(polls.len(), 2);
+        for poll in &polls {
+            assert!(poll["name"].is_string());
+            assert!(poll["pid"].is_u64());
+            assert!(poll["tid"].is_i64());
+            assert!(poll["ts"].is_f64());
+            assert!(poll["dur"].is_f64());
+        }
+        let poll = polls.iter().find(|poll| poll["tid"] == 1).unwrap();
+        assert_eq!(poll["ts"], 100_000.0);
+        assert_eq!(poll["dur"], 30_000.0);
+        assert_eq!(poll["name"], "my_crate.work");
+        assert_eq!(poll["args"]["stack"], "my_crate.work\ntokio.poll");
+
+        let thread_names: Vec<_> = events
+            .iter()
+            .filter(|event| event["ph"] == "M" && event["name"] == "thread_name")
+            .map(|event| (event["tid"].as_i64().unwrap(), &event["args"]["name"]))
+            .collect();
+        assert_eq!(
+            thread_names,
+            [
+                (1, &Value::from("worker \"1\"")),
+                (2, &Value::from("worker \"2\""))
+            ]
+        );
+    }
+}
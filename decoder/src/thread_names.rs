@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use crate::pr_parser::{self, from_fixed_cstr, PossiblyUnknownEvent};
+
+/// Resolves OS thread ids to thread names, from the thread names in the PR file, or else from
+/// `/proc` while a profiled process is still running on this machine
+#[derive(Default)]
+pub struct ThreadNameResolver {
+    /// The profiled processes still running on this machine, rather than later processes that
+    /// got their pids
+    local_pids: Vec<u32>,
+    pr_names: HashMap<u32, String>,
+    cache: HashMap<u32, Option<String>>,
+}
+
+impl ThreadNameResolver {
+    pub fn resolve(&mut self, tid: i64) -> Option<&str> {
+        let tid = u32::try_from(tid).ok()?;
+        self.cache
+            .entry(tid)
+            .or_insert_with(|| {
+                self.pr_names.get(&tid).cloned().or_else(|| {
+                    self.local_pids
+                        .iter()
+                        .find_map(|pid| {
+                            std::fs::read_to_string(format!("/proc/{pid}/task/{tid}/comm")).ok()
+                        })
+                        .map(|comm| comm.trim_end_matches('\n').to_owned())
+                })
+            })
+            .as_deref()
+    }
+}
+
+/// Reads the processes and thread names in a PR file from its events one at a time, keeping
+/// only those of process `pid` if given
+pub struct ThreadNameResolverBuilder {
+    pid: Option<u32>,
+    hostname: Option<String>,
+    local_pids: Vec<u32>,
+    pr_names: HashMap<u32, String>,
+    event_pid: Option<u32>,
+}
+
+impl ThreadNameResolverBuilder {
+    pub fn new(pid: Option<u32>) -> Self {
+        ThreadNameResolverBuilder {
+            pid,
+            hostname: std::fs::read_to_string("/proc/sys/kernel/hostname").ok(),
+            local_pids: Vec::new(),
+            pr_names: HashMap::new(),
+            event_pid: None,
+        }
+    }
+
+    pub fn add(&mut self, record: &PossiblyUnknownEvent) {
+        match record {
+            PossiblyUnknownEvent::Event(pr_parser::Event::SessionStart {
+                pid: session_pid,
+                ..
+            }) => self.event_pid = Some(*session_pid),
+            PossiblyUnknownEvent::Event(pr_parser::Event::ProcessInfo { data }) => {
+                self.event_pid = Some(data.pid);
+                // the pid could be a different process on another machine
+                let local = self
+                    .hostname
+                    .as_deref()
+                    .is_some_and(|hostname| hostname.trim_end() == data.hostname());
+                let running =
+                    data.start_time.is_some() && process_start_time(data.pid) == data.start_time;
+                if local && running && self.pid.is_none_or(|pid| pid == data.pid) {
+                    self.local_pids.push(data.pid);
+                }
+            }
+            PossiblyUnknownEvent::Event(pr_parser::Event::ThreadName { tid, name })
+                if self.pid.is_none() || self.event_pid == self.pid =>
+            {
+                self.pr_names
+                    .insert(*tid, from_fixed_cstr(name).into_owned());
+            }
+            _ => {}
+        }
+    }
+
+    pub fn finish(mut self) -> ThreadNameResolver {
+        self.local_pids.sort();
+        self.local_pids.dedup();
+        ThreadNameResolver {
+            local_pids: self.local_pids,
+            pr_names: self.pr_names,
+            cache: HashMap::new(),
+        }
+    }
+}
+
+/// The start time of process `pid` in `/proc/<pid>/stat`, its 22nd field
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // after the command name, which can contain spaces and parentheses
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+// reads `/proc`
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{process_start_time, ThreadNameResolver, ThreadNameResolverBuilder};
+    use crate::pr_parser::{Event, PossiblyUnknownEvent, ProcessInfoData};
+
+    /// A PR file of this process, with `start_time` as its start time and a recorded name for
+    /// the thread `named_tid`
+    fn resolver(start_time: Option<u64>, named_tid: u32) -> ThreadNameResolver {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+        let mut data = ProcessInfoData {
+            pid: std::process::id(),
+            hostname: [0; 64],
+            cmdline: [0; 256],
+            start_time,
+        };
+        data.hostname[..hostname.trim_end().len()].copy_from_slice(hostname.trim_end().as_bytes());
+        let mut name = [0; 16];
+        name[..8].copy_from_slice(b"recorded");
+        let events = [
+            Event::ProcessInfo {
+                data: Box::new(data),
+            },
+            Event::ThreadName {
+                tid: named_tid,
+                name,
+            },
+        ];
+        let mut builder = ThreadNameResolverBuilder::new(None);
+        for event in events {
+            builder.add(&PossiblyUnknownEvent::Event(event));
+        }
+        builder.finish()
+    }
+
+    #[test]
+    fn proc_only_for_the_same_process() {
+        let pid = std::process::id();
+        let start_time = process_start_time(pid);
+        assert!(start_time.is_some());
+        let main_thread = i64::from(pid);
+        // the main thread of the test harness is named after it
+        let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).unwrap();
+
+        let mut same = resolver(start_time, 0);
+        assert_eq!(same.resolve(main_thread), Some(comm.trim_end()));
+        // a later process with the same pid
+        let mut reused = resolver(start_time.map(|start_time| start_time + 1), 0);
+        assert_eq!(reused.resolve(main_thread), None);
+        // the recorded name wins
+        let mut recorded = resolver(start_time, pid);
+        assert_eq!(recorded.resolve(main_thread), Some("recorded"));
+    }
+}
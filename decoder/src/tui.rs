@@ -0,0 +1,238 @@
+use std::io;
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{BarChart, Block, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+use crate::Sample;
+
+/// Number of frames shown in the detail pane before a sample is expanded
+const COLLAPSED_FRAMES: usize = 10;
+
+struct App {
+    /// Longest first
+    samples: Vec<Sample>,
+    /// Indices of the samples that match `filter`
+    visible: Vec<usize>,
+    list: ListState,
+    filter: String,
+    /// The filter being typed after `/`, if any
+    prompt: Option<String>,
+    /// Whether the detail pane shows every frame, and takes the whole screen
+    expanded: bool,
+    detail_scroll: u16,
+}
+
+/// Browses `samples` in the terminal until the user quits
+pub fn run(mut samples: Vec<Sample>) -> io::Result<()> {
+    samples.sort_by_key(|sample| std::cmp::Reverse(sample.delta_t));
+    let mut app = App {
+        visible: (0..samples.len()).collect(),
+        samples,
+        list: ListState::default().with_selected(Some(0)),
+        filter: String::new(),
+        prompt: None,
+        expanded: false,
+        detail_scroll: 0,
+    };
+    let mut terminal = ratatui::init();
+    let res = app.run(&mut terminal);
+    ratatui::restore();
+    res
+}
+
+impl App {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if let Some(prompt) = &mut self.prompt {
+                match key.code {
+                    KeyCode::Enter => {
+                        self.filter = self.prompt.take().unwrap_or_default();
+                        self.apply_filter();
+                    }
+                    KeyCode::Esc => self.prompt = None,
+                    KeyCode::Backspace => {
+                        prompt.pop();
+                    }
+                    KeyCode::Char(c) => prompt.push(c),
+                    _ => {}
+                }
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Esc if self.expanded => self.expanded = false,
+                KeyCode::Esc => return Ok(()),
+                KeyCode::Char('/') => self.prompt = Some(self.filter.clone()),
+                KeyCode::Enter => {
+                    self.expanded = !self.expanded;
+                    self.detail_scroll = 0;
+                }
+                KeyCode::Down | KeyCode::Char('j') if self.expanded => {
+                    self.detail_scroll = self.detail_scroll.saturating_add(1);
+                }
+                KeyCode::Up | KeyCode::Char('k') if self.expanded => {
+                    self.detail_scroll = self.detail_scroll.saturating_sub(1);
+                }
+                KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+                KeyCode::PageDown => self.list.scroll_down_by(20),
+                KeyCode::PageUp => self.list.scroll_up_by(20),
+                KeyCode::Home => self.list.select_first(),
+                KeyCode::End => self.list.select_last(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Keeps the samples with a frame or thread name containing the filter
+    fn apply_filter(&mut self) {
+        let filter = &self.filter;
+        self.visible = (0..self.samples.len())
+            .filter(|&i| {
+                let sample = &self.samples[i];
+                sample.frames.iter().any(|f| f.to_string().contains(filter))
+                    || sample
+                        .thread_name
+                        .as_deref()
+                        .is_some_and(|name| name.contains(filter))
+            })
+            .collect();
+        self.list.select(Some(0));
+    }
+
+    fn selected(&self) -> Option<&Sample> {
+        let index = *self.visible.get(self.list.selected()?)?;
+        Some(&self.samples[index])
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        if self.expanded {
+            self.draw_detail(frame, main);
+        } else {
+            let [top, histogram] =
+                Layout::vertical([Constraint::Min(0), Constraint::Length(8)]).areas(main);
+            let [list, detail] =
+                Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                    .areas(top);
+            self.draw_list(frame, list);
+            self.draw_detail(frame, detail);
+            self.draw_histogram(frame, histogram);
+        }
+        let status_line = match &self.prompt {
+            Some(prompt) => format!("/{}", prompt),
+            None => format!(
+                "{}/{} polls{} | ↑↓ move, Enter expand, / filter, q quit",
+                self.visible.len(),
+                self.samples.len(),
+                if self.filter.is_empty() {
+                    String::new()
+                } else {
+                    format!(" matching {:?}", self.filter)
+                }
+            ),
+        };
+        frame.render_widget(Line::from(status_line), status);
+    }
+
+    fn draw_list(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .visible
+            .iter()
+            .map(|&i| {
+                let sample = &self.samples[i];
+                ListItem::new(format!(
+                    "{:>9}us  thread {}{}",
+                    sample.delta_t.as_micros(),
+                    sample.thread_id,
+                    sample
+                        .thread_name
+                        .as_deref()
+                        .map(|name| format!(" ({})", name))
+                        .unwrap_or_default()
+                ))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title("Long polls"))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.list);
+    }
+
+    fn draw_detail(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = vec![];
+        if let Some(sample) = self.selected() {
+            lines.push(Line::from(format!(
+                "{} of {}us at {:.6}s",
+                sample.kind(),
+                sample.delta_t.as_micros(),
+                sample.start_time.as_secs_f64()
+            )));
+            lines.push(Line::default());
+            let shown = if self.expanded {
+                sample.frames.len()
+            } else {
+                COLLAPSED_FRAMES
+            };
+            for (i, stack_frame) in sample.frames.iter().take(shown).enumerate() {
+                lines.push(Line::from(format!("{:3}: {}", i + 1, stack_frame)));
+            }
+            if sample.frames.len() > shown {
+                lines.push(Line::from(format!(
+                    "... {} more frame(s), Enter to expand",
+                    sample.frames.len() - shown
+                )));
+            }
+        }
+        let detail = Paragraph::new(lines)
+            .block(Block::bordered().title("Stack trace"))
+            .scroll((self.detail_scroll, 0));
+        frame.render_widget(detail, area);
+    }
+
+    /// Bars of the number of visible polls by power of two of microseconds
+    fn draw_histogram(&self, frame: &mut Frame, area: Rect) {
+        let mut counts = [0u64; 64];
+        for &i in &self.visible {
+            let micros = self.samples[i].delta_t.as_micros().max(1) as u64;
+            counts[micros.ilog2() as usize] += 1;
+        }
+        let first = counts.iter().position(|&c| c > 0).unwrap_or(0);
+        let last = counts.iter().rposition(|&c| c > 0).unwrap_or(0);
+        let labels: Vec<String> = (first..=last)
+            .map(|bucket| format_micros(1 << bucket))
+            .collect();
+        let data: Vec<(&str, u64)> = labels
+            .iter()
+            .zip(&counts[first..=last])
+            .map(|(label, &count)| (label.as_str(), count))
+            .collect();
+        let histogram = BarChart::default()
+            .block(Block::bordered().title("Poll durations"))
+            .data(&data)
+            .bar_width(6);
+        frame.render_widget(histogram, area);
+    }
+}
+
+fn format_micros(micros: u64) -> String {
+    match micros {
+        0..1_000 => format!("{}us", micros),
+        1_000..1_000_000 => format!("{}ms", micros / 1_000),
+        _ => format!("{}s", micros / 1_000_000),
+    }
+}
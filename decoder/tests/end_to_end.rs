@@ -0,0 +1,82 @@
+//! Profiles a slow future with a SIGPROF sent by hand mid-poll, and reads the recorded poll
+//! back with the decoder
+
+#![cfg(unix)]
+
+use std::{
+    future::Future,
+    path::Path,
+    pin::Pin,
+    process::Command,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use pollcatch::PollTimingFuture;
+
+/// Spins for `duration` in its only poll, sending itself SIGPROF halfway through
+struct SlowFuture {
+    duration: Duration,
+}
+
+impl Future for SlowFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        let start = Instant::now();
+        let mut signaled = false;
+        while start.elapsed() < self.duration {
+            if !signaled && start.elapsed() >= self.duration / 2 {
+                // what a profiler does, but to this thread: `kill(getpid(), ..)` would go to the
+                // main thread, which is the test harness's
+                // safety: SIGPROF is handled by pollcatch
+                assert_eq!(
+                    unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGPROF) },
+                    0
+                );
+                signaled = true;
+            }
+            std::hint::spin_loop();
+        }
+        Poll::Ready(())
+    }
+}
+
+/// The output of `pollcatch-decoder stats` on a PR file
+fn stats(path: &Path) -> anyhow::Result<String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_pollcatch-decoder"))
+        .arg("stats")
+        .arg(path)
+        .output()?;
+    anyhow::ensure!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[test]
+fn records_poll_interrupted_by_sigprof() -> anyhow::Result<()> {
+    let path = std::env::temp_dir().join(format!("pollcatch-e2e-{}.pr", std::process::id()));
+    pollcatch::enable_poll_timing(Box::new(std::fs::File::create(&path)?))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(PollTimingFuture::new(SlowFuture {
+        duration: Duration::from_millis(20),
+    }));
+
+    // the writer thread flushes at least once a second
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let stats = loop {
+        let stats = stats(&path)?;
+        if !stats.contains("no polls") || Instant::now() > deadline {
+            break stats;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    std::fs::remove_file(&path)?;
+    assert!(stats.lines().any(|line| line == "polls: 1"), "{}", stats);
+    assert!(!stats.contains("max: 0ns"), "{}", stats);
+    Ok(())
+}
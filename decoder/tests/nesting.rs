@@ -0,0 +1,51 @@
+//! Checks that a sample landing in nested `PollTimingFuture`s is only recorded for the innermost
+//! one
+
+#![cfg(unix)]
+
+use std::{
+    fs::File,
+    future::Future,
+    pin::Pin,
+    process::Command,
+    task::{Context, Poll},
+};
+
+use pollcatch::PollTimingFuture;
+
+/// Sends itself SIGPROF in its only poll
+struct SignaledFuture;
+
+impl Future for SignaledFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        // safety: SIGPROF is handled by pollcatch
+        assert_eq!(
+            unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGPROF) },
+            0
+        );
+        Poll::Ready(())
+    }
+}
+
+#[test]
+fn records_innermost_poll_only() -> anyhow::Result<()> {
+    let path = std::env::temp_dir().join(format!("pollcatch-nesting-{}.pr", std::process::id()));
+    let writer = pollcatch::start_performance_writer(Box::new(File::create(&path)?));
+    pollcatch::enable_poll_timing(Box::new(std::io::sink()))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(PollTimingFuture::new(PollTimingFuture::new(SignaledFuture)));
+    writer.stop()?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pollcatch-decoder"))
+        .arg("stats")
+        .arg(&path)
+        .output()?;
+    std::fs::remove_file(&path)?;
+    assert!(output.status.success(), "{:?}", output);
+    let stats = String::from_utf8(output.stdout)?;
+    assert!(stats.lines().any(|line| line == "polls: 1"), "{}", stats);
+    Ok(())
+}
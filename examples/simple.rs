@@ -1,5 +1,4 @@
 use std::ffi::CStr;
-use tokio;
 
 #[allow(non_camel_case_types)]
 pub type asprof_error_t = *const std::ffi::c_char;
@@ -66,9 +65,10 @@ pub async fn main() -> Result<(), anyhow::Error> {
     }
     let lf = std::fs::OpenOptions::new()
         .create(true)
+        .truncate(true)
         .write(true)
         .open("performance.pr")?;
-    pollcatch::enable_poll_timing(Box::new(lf));
+    pollcatch::enable_poll_timing(Box::new(lf))?;
 
     if !err.is_null() {
         unsafe {
@@ -0,0 +1,129 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ToTokens, quote};
+use syn::{
+    Block, ImplItem, Item, ItemFn, ItemImpl, ReturnType, Type, parse_macro_input, spanned::Spanned,
+};
+
+/// Times the polls of an `async fn`, like wrapping it in `PollTimingFuture::new`. Re-exported
+/// as `pollcatch::attr::poll_timed`.
+///
+/// `async fn foo(..) -> T` becomes `fn foo(..) -> PollTimingFuture<impl Future<Output = T>>`.
+/// With `#[async_trait]`, put `#[poll_timed]` either on the methods or above the
+/// `#[async_trait]` of the impl block.
+#[proc_macro_attribute]
+pub fn poll_timed(args: TokenStream, item: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return syn::Error::new(
+            TokenStream2::from(args).span(),
+            "#[poll_timed] takes no arguments",
+        )
+        .into_compile_error()
+        .into();
+    }
+    let result = match parse_macro_input!(item as Item) {
+        Item::Fn(f) => timed_fn(f),
+        Item::Impl(i) if i.attrs.iter().any(is_async_trait) => Ok(timed_async_trait_impl(i)),
+        item => Err(syn::Error::new(
+            item.span(),
+            "#[poll_timed] can only be used on async functions and #[async_trait] impls",
+        )),
+    };
+    result.unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn is_async_trait(attr: &syn::Attribute) -> bool {
+    attr.path()
+        .segments
+        .last()
+        .is_some_and(|s| s.ident == "async_trait")
+}
+
+fn output_type(output: &ReturnType) -> Type {
+    match output {
+        ReturnType::Default => syn::parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    }
+}
+
+/// Wraps `block` into an async block whose output is `ty`. The unreachable return helps type
+/// inference with `?`, and is left out for `impl Trait` outputs, which can't be named in a
+/// `let`.
+fn async_block(block: &Block, ty: &Type) -> TokenStream2 {
+    let return_hint = if ty
+        .to_token_stream()
+        .into_iter()
+        .any(|t| t.to_string() == "impl")
+    {
+        quote!()
+    } else {
+        quote! {
+            #[allow(unreachable_code, clippy::diverging_sub_expression)]
+            if false {
+                let __pollcatch_return: #ty = loop {};
+                return __pollcatch_return;
+            }
+        }
+    };
+    let stmts = &block.stmts;
+    quote! {
+        async move {
+            #return_hint
+            #(#stmts)*
+        }
+    }
+}
+
+/// Whether `output` is a `Pin<Box<dyn Future ..>>`, as in the methods `#[async_trait]`
+/// has already expanded
+fn is_boxed_future(output: &ReturnType) -> bool {
+    let ReturnType::Type(_, ty) = output else {
+        return false;
+    };
+    let Type::Path(path) = &**ty else {
+        return false;
+    };
+    path.path.segments.last().is_some_and(|s| s.ident == "Pin")
+}
+
+fn timed_fn(mut f: ItemFn) -> syn::Result<TokenStream2> {
+    let block = &f.block;
+    if f.sig.asyncness.is_some() {
+        let ty = output_type(&f.sig.output);
+        let body = async_block(block, &ty);
+        f.sig.asyncness = None;
+        // this crate is on edition 2024, so that the `impl Future` captures all the lifetimes
+        // of the arguments, like the future of an `async fn` does
+        f.sig.output = syn::parse_quote! {
+            -> ::pollcatch::PollTimingFuture<impl ::core::future::Future<Output = #ty>>
+        };
+        f.block = syn::parse_quote!({ ::pollcatch::PollTimingFuture::new(#body) });
+    } else if is_boxed_future(&f.sig.output) {
+        // the braces of a one-line body are the user's, so they'd be linted as unused around
+        // an argument
+        f.block = syn::parse_quote!({
+            #[allow(unused_braces)]
+            let __pollcatch_future = #block;
+            ::std::boxed::Box::pin(::pollcatch::PollTimingFuture::new(__pollcatch_future))
+        });
+    } else {
+        return Err(syn::Error::new(
+            f.sig.fn_token.span(),
+            "#[poll_timed] can only be used on async functions",
+        ));
+    }
+    Ok(f.into_token_stream())
+}
+
+/// Wraps the bodies of the async methods, leaving their signatures to `#[async_trait]`
+fn timed_async_trait_impl(mut i: ItemImpl) -> TokenStream2 {
+    for item in &mut i.items {
+        if let ImplItem::Fn(f) = item
+            && f.sig.asyncness.is_some()
+        {
+            let body = async_block(&f.block, &output_type(&f.sig.output));
+            f.block = syn::parse_quote!({ ::pollcatch::PollTimingFuture::new(#body).await });
+        }
+    }
+    i.into_token_stream()
+}
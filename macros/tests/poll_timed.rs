@@ -0,0 +1,124 @@
+//! Checks that `#[poll_timed]` compiles, without warnings, on the kinds of functions it
+//! supports, and that the functions still return what they did
+
+#![deny(warnings)]
+
+use std::{future::Future, pin::Pin};
+
+use async_trait::async_trait;
+use pollcatch::PollTimingFuture;
+use pollcatch_macros::poll_timed;
+
+#[poll_timed]
+async fn plain() -> u32 {
+    1
+}
+
+#[poll_timed]
+async fn unit() {}
+
+#[poll_timed]
+async fn fallible(s: &str) -> Result<u32, std::num::ParseIntError> {
+    let n: u32 = s.parse()?;
+    Ok(n + 1)
+}
+
+#[poll_timed]
+async fn borrowed<'a>(s: &'a str, t: &str) -> &'a str {
+    let _ = t;
+    s.trim()
+}
+
+#[poll_timed]
+async fn opaque() -> impl std::fmt::Debug {
+    3
+}
+
+struct Adder(u32);
+
+impl Adder {
+    #[poll_timed]
+    async fn add(&self, n: u32) -> u32 {
+        self.0 + n
+    }
+
+    #[poll_timed]
+    async fn add_mut(&mut self, n: u32) {
+        self.0 += n;
+    }
+}
+
+#[async_trait]
+trait Counter {
+    async fn count(&self, s: &str) -> Result<usize, String>;
+
+    async fn one(&self) -> usize;
+}
+
+struct Outer;
+
+// `#[poll_timed]` above `#[async_trait]` wraps the bodies before `#[async_trait]` boxes them
+#[poll_timed]
+#[async_trait]
+impl Counter for Outer {
+    async fn count(&self, s: &str) -> Result<usize, String> {
+        if s.is_empty() {
+            return Err("empty".to_owned());
+        }
+        Ok(s.len())
+    }
+
+    async fn one(&self) -> usize {
+        1
+    }
+}
+
+struct Inner;
+
+// `#[poll_timed]` on the methods wraps the boxed futures `#[async_trait]` returns
+#[async_trait]
+impl Counter for Inner {
+    #[poll_timed]
+    async fn count(&self, s: &str) -> Result<usize, String> {
+        let n = s.parse::<usize>().map_err(|e| e.to_string())?;
+        Ok(n)
+    }
+
+    // on one line, the braces of the body are unused around an argument if kept as is
+    #[rustfmt::skip]
+    #[poll_timed]
+    async fn one(&self) -> usize { 1 }
+}
+
+fn is_timed<F: Future>(_: &PollTimingFuture<F>) {}
+
+#[tokio::test]
+async fn functions() {
+    let future = plain();
+    is_timed(&future);
+    assert_eq!(future.await, 1);
+    unit().await;
+    assert_eq!(fallible("41").await, Ok(42));
+    assert!(fallible("x").await.is_err());
+    let (s, t) = (String::from(" a "), String::from("b"));
+    assert_eq!(borrowed(&s, &t).await, "a");
+    assert_eq!(format!("{:?}", opaque().await), "3");
+}
+
+#[tokio::test]
+async fn methods() {
+    let mut adder = Adder(1);
+    assert_eq!(adder.add(2).await, 3);
+    adder.add_mut(2).await;
+    assert_eq!(adder.0, 3);
+}
+
+#[tokio::test]
+async fn async_trait_orderings() {
+    let future: Pin<Box<dyn Future<Output = _> + Send + '_>> = Outer.count("ab");
+    assert_eq!(future.await, Ok(2));
+    assert_eq!(Outer.count("").await, Err("empty".to_owned()));
+    assert_eq!(Inner.count("5").await, Ok(5));
+    assert!(Inner.count("x").await.is_err());
+    assert_eq!(Outer.one().await + Inner.one().await, 2);
+}
@@ -0,0 +1,203 @@
+//! Alternate signal stacks, so that the signal handler can run on a thread whose stack is
+//! almost exhausted.
+//!
+//! An alternate stack is per thread, and only used for actions with `SA_ONSTACK`.
+
+use std::{cell::RefCell, io};
+
+/// An alternate signal stack installed on the current thread, uninstalled and unmapped when
+/// the thread exits or by [`uninstall`]
+struct AltStack {
+    /// The mapping, starting with a guard page
+    ptr: *mut libc::c_void,
+    len: usize,
+    /// The alternate stack this one replaced, put back by [`uninstall`]
+    previous: libc::stack_t,
+}
+
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        // safety: the stack is disabled before it's unmapped, so no handler runs on it then
+        unsafe {
+            let disable = libc::stack_t {
+                ss_sp: std::ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: 0,
+            };
+            libc::sigaltstack(&disable, std::ptr::null_mut());
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+thread_local! {
+    static ALT_STACK: RefCell<Option<AltStack>> = const { RefCell::new(None) };
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+const MAP_STACK: libc::c_int = libc::MAP_STACK;
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+const MAP_STACK: libc::c_int = 0;
+
+fn page_size() -> usize {
+    // safety: plain libc call
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Installs an alternate signal stack of at least `stack_size` bytes on the current thread,
+/// unless it already has one that large, e.g. the one Rust's standard library installs to
+/// report stack overflows
+pub(crate) fn install(stack_size: usize) -> io::Result<()> {
+    // safety: querying the current alternate stack
+    let current = unsafe {
+        let mut current: libc::stack_t = std::mem::zeroed();
+        if libc::sigaltstack(std::ptr::null(), &mut current) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        current
+    };
+    if current.ss_flags & libc::SS_DISABLE == 0 && current.ss_size >= stack_size {
+        return Ok(());
+    }
+    if current.ss_flags & libc::SS_ONSTACK != 0 {
+        return Err(io::Error::other(
+            "can't replace the alternate signal stack while running on it",
+        ));
+    }
+    let page_size = page_size();
+    let stack_size = stack_size.max(libc::SIGSTKSZ).next_multiple_of(page_size);
+    let len = page_size + stack_size;
+    // safety: a fresh anonymous mapping, whose first page becomes a guard page
+    unsafe {
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | MAP_STACK,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let new = libc::stack_t {
+            ss_sp: ptr.cast::<u8>().add(page_size).cast(),
+            ss_flags: 0,
+            ss_size: stack_size,
+        };
+        if libc::mprotect(ptr, page_size, libc::PROT_NONE) != 0
+            || libc::sigaltstack(&new, std::ptr::null_mut()) != 0
+        {
+            let e = io::Error::last_os_error();
+            // not through `AltStack`, which would disable the alternate stack still installed
+            libc::munmap(ptr, len);
+            return Err(e);
+        }
+        let mut stack = AltStack {
+            ptr,
+            len,
+            previous: current,
+        };
+        // replacing a stack this installed before drops that one, which disables the new
+        // one, so take the old one out first
+        let old = ALT_STACK.with_borrow_mut(Option::take);
+        if let Some(old) = old {
+            stack.previous = old.previous;
+            libc::munmap(old.ptr, old.len);
+            std::mem::forget(old);
+        }
+        ALT_STACK.with_borrow_mut(|slot| *slot = Some(stack));
+    }
+    Ok(())
+}
+
+/// Uninstalls and unmaps the alternate signal stack that [`install`] installed on the current
+/// thread, if any, putting back the one it replaced
+pub(crate) fn uninstall() -> io::Result<()> {
+    let Some(stack) = ALT_STACK.with_borrow_mut(Option::take) else {
+        return Ok(());
+    };
+    // safety: querying the current alternate stack
+    let current = unsafe {
+        let mut current: libc::stack_t = std::mem::zeroed();
+        if libc::sigaltstack(std::ptr::null(), &mut current) != 0 {
+            let e = io::Error::last_os_error();
+            ALT_STACK.with_borrow_mut(|slot| *slot = Some(stack));
+            return Err(e);
+        }
+        current
+    };
+    if current.ss_flags & libc::SS_ONSTACK != 0 {
+        ALT_STACK.with_borrow_mut(|slot| *slot = Some(stack));
+        return Err(io::Error::other(
+            "can't uninstall the alternate signal stack while running on it",
+        ));
+    }
+    // safety: the stack is no longer used once the previous one is back, and if another one
+    // replaced it since, it's not used at all
+    unsafe {
+        let ours = stack
+            .ptr
+            .cast::<u8>()
+            .add(page_size())
+            .cast::<libc::c_void>();
+        if current.ss_flags & libc::SS_DISABLE == 0 && current.ss_sp == ours {
+            let mut previous = stack.previous;
+            // only `SS_DISABLE` can be set, not the `SS_ONSTACK` reported by the query
+            previous.ss_flags &= libc::SS_DISABLE;
+            libc::sigaltstack(&previous, std::ptr::null_mut());
+        }
+        libc::munmap(stack.ptr, stack.len);
+    }
+    std::mem::forget(stack);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{install, uninstall};
+
+    fn current_size() -> usize {
+        // safety: querying the current alternate stack
+        unsafe {
+            let mut current: libc::stack_t = std::mem::zeroed();
+            assert_eq!(libc::sigaltstack(std::ptr::null(), &mut current), 0);
+            if current.ss_flags & libc::SS_DISABLE != 0 {
+                0
+            } else {
+                current.ss_size
+            }
+        }
+    }
+
+    #[test]
+    fn install_grows() {
+        std::thread::spawn(|| {
+            install(64 * 1024).unwrap();
+            assert!(current_size() >= 64 * 1024);
+            // already large enough
+            install(16 * 1024).unwrap();
+            assert!(current_size() >= 64 * 1024);
+            install(256 * 1024).unwrap();
+            assert!(current_size() >= 256 * 1024);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn uninstall_restores_previous() {
+        std::thread::spawn(|| {
+            let before = current_size();
+            install(before + 64 * 1024).unwrap();
+            install(before + 256 * 1024).unwrap();
+            uninstall().unwrap();
+            assert_eq!(current_size(), before);
+            // nothing left to uninstall
+            uninstall().unwrap();
+            assert_eq!(current_size(), before);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -1,6 +1,6 @@
 // copied from quanta crate
 
-use crate::stats::Variance;
+use crate::stats::{OrderedWindow, Variance};
 
 // Run 500 rounds of calibration before we start actually seeing what the numbers look like.
 const MINIMUM_CAL_ROUNDS: u64 = 500;
@@ -11,6 +11,59 @@ const MAXIMUM_CAL_ERROR_NS: u64 = 10;
 // Don't run the calibration loop for longer than 200ms of wall time.
 const MAXIMUM_CAL_TIME_NS: u64 = 200 * 1000 * 1000;
 
+// Look for outliers among the last 255 measurements.
+const OUTLIER_WINDOW: usize = 255;
+
+// Measurements more than 3 interquartile ranges from the median are outliers.
+const OUTLIER_IQRS: f64 = 3.0;
+
+// With a stable clock, most measurements are the same and the interquartile range is 0, so
+// count it as at least 1ns, to keep the measurements that are just a tick off.
+const MIN_OUTLIER_IQR_NS: f64 = 1.0;
+
+// A hypervisor can steal the CPU in the middle of a measurement, so under one, calibrate for
+// up to 2s and reject outliers more aggressively.
+const HYPERVISOR_MAXIMUM_CAL_TIME_NS: u64 = 2000 * 1000 * 1000;
+const HYPERVISOR_OUTLIER_IQRS: f64 = 1.5;
+
+/// Parameters of the TSC calibration. The defaults may not be achievable on embedded or
+/// heavily loaded systems.
+#[derive(Debug, Copy, Clone)]
+pub struct CalibrationConfig {
+    /// Rounds to run before checking whether the error is within bounds. The measurements
+    /// of as many rounds before those only serve to tell the outliers among the later ones.
+    pub min_rounds: u64,
+    /// The calibration is done once its mean error is within this many nanoseconds
+    pub max_error_ns: u64,
+    /// Give up on getting within bounds after this many nanoseconds
+    pub max_time_ns: u64,
+    /// Measurements more than this many interquartile ranges from the median are skipped
+    pub outlier_iqrs: f64,
+}
+
+impl CalibrationConfig {
+    /// The parameters used when running under a hypervisor: a longer window and more
+    /// aggressive outlier rejection than the defaults
+    pub fn hypervisor() -> Self {
+        CalibrationConfig {
+            max_time_ns: HYPERVISOR_MAXIMUM_CAL_TIME_NS,
+            outlier_iqrs: HYPERVISOR_OUTLIER_IQRS,
+            ..CalibrationConfig::default()
+        }
+    }
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        CalibrationConfig {
+            min_rounds: MINIMUM_CAL_ROUNDS,
+            max_error_ns: MAXIMUM_CAL_ERROR_NS,
+            max_time_ns: MAXIMUM_CAL_TIME_NS,
+            outlier_iqrs: OUTLIER_IQRS,
+        }
+    }
+}
+
 #[inline]
 fn mul_div_po2_u64(value: u64, numer: u64, denom: u32) -> u64 {
     // Modified muldiv routine where the denominator has to be a power of two. `denom` is expected
@@ -21,6 +74,19 @@ fn mul_div_po2_u64(value: u64, numer: u64, denom: u32) -> u64 {
     v as u64
 }
 
+/// How well a calibration matches the reference clock
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct CalibrationResult {
+    /// The mean of the calibrated source time minus the reference time, over the rounds
+    pub mean_error_ns: f64,
+    pub std_dev_ns: f64,
+    pub min_error_ns: f64,
+    pub max_error_ns: f64,
+    pub rounds: u64,
+    /// Whether the error got within bounds before the deadline
+    pub converged: bool,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct Calibration {
     pub ref_time: u64,
@@ -50,9 +116,62 @@ impl Calibration {
         scaled + self.ref_time
     }
 
-    pub(crate) fn calibrate(&mut self, reference: &impl Fn() -> u64, source: &impl Fn() -> u64) {
+    pub(crate) fn scale_src_duration_to_ref(&self, delta: u64) -> u64 {
+        mul_div_po2_u64(delta, self.scale_factor, self.scale_shift)
+    }
+
+    /// The inverse of `scale_src_duration_to_ref`
+    pub(crate) fn scale_ref_duration_to_src(&self, delta: u64) -> u64 {
+        let scaled = (u128::from(delta) << self.scale_shift) / u128::from(self.scale_factor.max(1));
+        scaled.try_into().unwrap_or(u64::MAX)
+    }
+
+    /// Measures the scale from source to reference ticks over `duration_ns` of the reference
+    /// clock, as a `scale_factor` with this calibration's `scale_shift`. A quick check of
+    /// whether the calibration still holds.
+    pub(crate) fn measure_scale_factor(
+        &self,
+        reference: &impl Fn() -> u64,
+        source: &impl Fn() -> u64,
+        duration_ns: u64,
+    ) -> u64 {
+        let ref_start = reference();
+        let src_start = source();
+        let mut ref_end = ref_start;
+        while ref_end.wrapping_sub(ref_start) < duration_ns {
+            ref_end = reference();
+        }
+        let src_d = source().wrapping_sub(src_start).max(1);
+        let scaled =
+            (u128::from(ref_end.wrapping_sub(ref_start)) << self.scale_shift) / u128::from(src_d);
+        scaled.try_into().unwrap_or(u64::MAX)
+    }
+
+    /// Sets the scale from source to reference ticks to `numer / denom`, for sources with a
+    /// known frequency
+    pub(crate) fn set_ratio(
+        &mut self,
+        reference: &impl Fn() -> u64,
+        source: &impl Fn() -> u64,
+        numer: u32,
+        denom: u32,
+    ) {
+        self.reset_timebases(reference, source);
+        self.scale_shift = 32;
+        self.scale_factor = ((u64::from(numer) << 32) / u64::from(denom.max(1))).max(1);
+    }
+
+    pub(crate) fn calibrate(
+        &mut self,
+        reference: &impl Fn() -> u64,
+        source: &impl Fn() -> u64,
+        config: &CalibrationConfig,
+    ) -> CalibrationResult {
         let mut variance = Variance::default();
-        let deadline = reference() + MAXIMUM_CAL_TIME_NS;
+        let mut window = OrderedWindow::new(OUTLIER_WINDOW);
+        let mut converged = false;
+        let mut rounds = 0;
+        let deadline = reference() + config.max_time_ns;
 
         self.reset_timebases(reference, source);
 
@@ -80,7 +199,24 @@ impl Calibration {
             let r_time = reference();
             let s_raw = source();
             let s_time = self.scale_src_to_ref(s_raw);
-            variance.add(s_time as f64 - r_time as f64);
+            let error = s_time as f64 - r_time as f64;
+
+            // Skip measurements that are far off from the recent ones, e.g. because we were
+            // preempted in the middle of them, once there are enough recent ones to tell.
+            window.add(error);
+            rounds += 1;
+            if rounds <= config.min_rounds {
+                continue;
+            }
+            let (q1, median, q3) = (
+                window.quantile(0.25),
+                window.median(),
+                window.quantile(0.75),
+            );
+            if (error - median).abs() > config.outlier_iqrs * (q3 - q1).max(MIN_OUTLIER_IQR_NS) {
+                continue;
+            }
+            variance.add(error);
 
             // If we've collected enough samples, check what the mean and mean error are.  If we're
             // already within the target bounds, we can break out of the calibration loop early.
@@ -88,16 +224,26 @@ impl Calibration {
                 let mean = variance.mean().abs();
                 let mean_error = variance.mean_error().abs();
                 let mwe = variance.mean_with_error();
-                let samples = variance.samples();
+                let samples = variance.count();
 
-                if samples > MINIMUM_CAL_ROUNDS
-                    && mwe < MAXIMUM_CAL_ERROR_NS as f64
+                if samples > config.min_rounds
+                    && mwe < config.max_error_ns as f64
                     && mean_error / mean <= 1.0
                 {
+                    converged = true;
                     break;
                 }
             }
         }
+
+        CalibrationResult {
+            mean_error_ns: variance.mean(),
+            std_dev_ns: variance.std_dev(),
+            min_error_ns: variance.min(),
+            max_error_ns: variance.max(),
+            rounds: variance.count(),
+            converged,
+        }
     }
 
     fn adjust_cal_ratio(&mut self, reference: &impl Fn() -> u64, source: &impl Fn() -> u64) {
@@ -135,3 +281,113 @@ impl Default for Calibration {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Calibration, CalibrationConfig};
+    use std::cell::Cell;
+
+    #[test]
+    fn outliers() {
+        // a source running at 3 times the reference, that is sometimes read late
+        let now = Cell::new(0);
+        let reads = Cell::new(0);
+        let reference = || {
+            now.set(now.get() + 100);
+            now.get()
+        };
+        let source = || {
+            reads.set(reads.get() + 1);
+            let late = if reads.get() % 50 == 0 { 1_000_000 } else { 0 };
+            (now.get() + late) * 3
+        };
+        let mut calibration = Calibration::default();
+        let result = calibration.calibrate(&reference, &source, &CalibrationConfig::default());
+        assert!(result.converged, "{:?}", result);
+        // 1ms later on the source is 1ms later on the reference
+        let scaled = calibration.scale_src_to_ref(calibration.src_time + 3_000_000);
+        assert!(
+            scaled.abs_diff(calibration.ref_time + 1_000_000) < 10,
+            "{}",
+            scaled
+        );
+    }
+
+    #[test]
+    fn stable_clock() {
+        // a source running at exactly 3 times the reference, read a nanosecond late now and
+        // then, so that the interquartile range of the errors is 0
+        let now = Cell::new(0);
+        let reads = Cell::new(0);
+        let reference = || {
+            now.set(now.get() + 100);
+            now.get()
+        };
+        let source = || {
+            reads.set(reads.get() + 1);
+            let late = if reads.get() % 10 == 0 { 1 } else { 0 };
+            (now.get() + late) * 3
+        };
+        let config = CalibrationConfig {
+            // never done, to take all the measurements
+            max_error_ns: 0,
+            ..CalibrationConfig::default()
+        };
+        let result = Calibration::default().calibrate(&reference, &source, &config);
+        // the late measurements aren't outliers
+        assert!(result.max_error_ns > result.min_error_ns, "{:?}", result);
+    }
+
+    #[test]
+    fn set_ratio() {
+        let mut calibration = Calibration::default();
+        // Apple Silicon: 24 MHz
+        calibration.set_ratio(&|| 1000, &|| 3000, 125, 3);
+        let scaled = calibration.scale_src_to_ref(3000 + 24_000_000);
+        assert!(scaled.abs_diff(1000 + 1_000_000_000) <= 1, "{}", scaled);
+    }
+
+    #[test]
+    fn duration_round_trip() {
+        let calibration = Calibration {
+            ref_time: 0,
+            src_time: 0,
+            // 3 ticks per nanosecond
+            scale_factor: (1 << 32) / 3,
+            scale_shift: 32,
+        };
+        let ticks = calibration.scale_ref_duration_to_src(1_000_000);
+        assert!(ticks.abs_diff(3_000_000) < 3, "{}", ticks);
+        let ns = calibration.scale_src_duration_to_ref(ticks);
+        assert!(ns.abs_diff(1_000_000) < 3, "{}", ns);
+    }
+
+    #[test]
+    fn measure_scale_factor() {
+        let now = Cell::new(0);
+        let reference = || {
+            now.set(now.get() + 100);
+            now.get()
+        };
+        let calibration = Calibration {
+            ref_time: 0,
+            src_time: 0,
+            // 3 ticks per nanosecond
+            scale_factor: (1 << 32) / 3,
+            scale_shift: 32,
+        };
+        let measured = calibration.measure_scale_factor(&reference, &|| now.get() * 3, 1_000_000);
+        assert!(
+            measured.abs_diff(calibration.scale_factor) < calibration.scale_factor / 1000,
+            "{}",
+            measured
+        );
+        // the source slowed down to 2 ticks per nanosecond
+        let measured = calibration.measure_scale_factor(&reference, &|| now.get() * 2, 1_000_000);
+        assert!(
+            measured.abs_diff((1 << 32) / 2) < (1 << 32) / 2000,
+            "{}",
+            measured
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Saving a calibration to a file, to skip calibrating at startup when it's still valid.
+//!
+//! The file holds the scale of the calibration and the CPU it was made on, but not its
+//! epochs, which only mean something in the process that calibrated.
+
+use std::{io, path::Path};
+
+use crate::{calibration::Calibration, cpu_info::CpuInfo};
+
+const MAGIC: [u8; 4] = *b"PCCL";
+/// magic, scale factor, scale shift, TSC frequency, CPU model
+const LEN: usize = 4 + 8 + 4 + 8 + 48;
+
+pub(crate) fn save(path: &Path, calibration: &Calibration, cpu: &CpuInfo) -> io::Result<()> {
+    let mut data = Vec::with_capacity(LEN);
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&calibration.scale_factor.to_le_bytes());
+    data.extend_from_slice(&calibration.scale_shift.to_le_bytes());
+    data.extend_from_slice(&cpu.tsc_hz.to_le_bytes());
+    data.extend_from_slice(&cpu.cpu_model);
+    std::fs::write(path, data)
+}
+
+/// Loads the calibration saved at `path`, with its epochs at `ref_time` and `src_time`.
+/// Returns `None` if it was made on another kind of CPU.
+pub(crate) fn load(
+    path: &Path,
+    cpu: &CpuInfo,
+    ref_time: u64,
+    src_time: u64,
+) -> io::Result<Option<Calibration>> {
+    let data = std::fs::read(path)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a saved calibration");
+    if data.len() != LEN || data[..4] != MAGIC {
+        return Err(invalid());
+    }
+    let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    let scale_factor = u64_at(4);
+    let scale_shift = u32::from_le_bytes(data[12..16].try_into().unwrap());
+    let tsc_hz = u64_at(16);
+    if scale_factor == 0 || scale_shift > 64 {
+        return Err(invalid());
+    }
+    if tsc_hz != cpu.tsc_hz || data[24..] != cpu.cpu_model {
+        return Ok(None);
+    }
+    Ok(Some(Calibration {
+        ref_time,
+        src_time,
+        scale_factor,
+        scale_shift,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, save};
+    use crate::{calibration::Calibration, cpu_info::CpuInfo};
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("pollcatch-cal-{}", std::process::id()));
+        let cpu = |model: &[u8]| {
+            let mut cpu_model = [0; 48];
+            cpu_model[..model.len()].copy_from_slice(model);
+            CpuInfo {
+                tsc_hz: 2_000_000_000,
+                cpu_model,
+                cpu_flags: 0,
+            }
+        };
+        let calibration = Calibration {
+            ref_time: 1,
+            src_time: 2,
+            scale_factor: (1 << 32) / 3,
+            scale_shift: 32,
+        };
+        save(&path, &calibration, &cpu(b"some CPU")).unwrap();
+        let loaded = load(&path, &cpu(b"some CPU"), 10, 20).unwrap().unwrap();
+        assert_eq!(
+            (loaded.ref_time, loaded.src_time),
+            (10, 20),
+            "the epochs are the caller's"
+        );
+        assert_eq!(loaded.scale_factor, calibration.scale_factor);
+        assert_eq!(loaded.scale_shift, calibration.scale_shift);
+        assert!(load(&path, &cpu(b"another CPU"), 10, 20).unwrap().is_none());
+
+        std::fs::write(&path, b"garbage").unwrap();
+        let err = load(&path, &cpu(b"some CPU"), 10, 20).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//! What the decoder needs to know about the CPU to make sense of the timestamps of [`tsc`]
+//! without a calibration, e.g. when the process died before calibrating.
+//!
+//! [`tsc`]: crate::tsc
+
+/// The TSC is invariant: it ticks at the same rate in every P-, C- and T-state
+pub const FLAG_INVARIANT_TSC: u64 = 1 << 0;
+/// Running under a hypervisor, whose TSC may be emulated or scaled
+pub const FLAG_HYPERVISOR: u64 = 1 << 1;
+/// `rdtscp` is available
+pub const FLAG_RDTSCP: u64 = 1 << 2;
+
+pub struct CpuInfo {
+    /// Ticks of `tsc::now` per second, or 0 if unknown
+    pub tsc_hz: u64,
+    /// Null-terminated, empty if unknown
+    pub cpu_model: [u8; 48],
+    pub cpu_flags: u64,
+}
+
+pub fn cpu_info() -> CpuInfo {
+    let mut info = _cpu_info();
+    if info.tsc_hz == 0 {
+        if let Some((numer, denom)) = crate::tsc::frequency() {
+            // ticks * numer / denom = nanoseconds
+            info.tsc_hz = 1_000_000_000 * u64::from(denom) / u64::from(numer.max(1));
+        }
+    }
+    info
+}
+
+#[cfg(all(
+    target_arch = "x86_64",
+    target_feature = "sse2",
+    not(target_os = "macos")
+))]
+fn _cpu_info() -> CpuInfo {
+    use core::arch::x86_64::__cpuid;
+
+    // leaves past the maximum ones the CPU reports are never queried
+    let max_leaf = __cpuid(0).eax;
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    let mut cpu_flags = 0;
+    if __cpuid(1).ecx & (1 << 31) != 0 {
+        cpu_flags |= FLAG_HYPERVISOR;
+    }
+    if max_extended_leaf >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 27) != 0 {
+        cpu_flags |= FLAG_RDTSCP;
+    }
+    if max_extended_leaf >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0 {
+        cpu_flags |= FLAG_INVARIANT_TSC;
+    }
+
+    let mut cpu_model = [0; 48];
+    if max_extended_leaf >= 0x8000_0004 {
+        for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+            let r = __cpuid(leaf);
+            for (j, reg) in [r.eax, r.ebx, r.ecx, r.edx].into_iter().enumerate() {
+                let offset = i * 16 + j * 4;
+                cpu_model[offset..offset + 4].copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+        // the brand string is null-terminated if shorter than 48 bytes, make it always be
+        cpu_model[47] = 0;
+    }
+
+    // the crystal clock and the TSC's ratio to it
+    let mut tsc_hz = 0;
+    if max_leaf >= 0x15 {
+        let r = __cpuid(0x15);
+        if r.eax != 0 && r.ecx != 0 {
+            tsc_hz = u64::from(r.ecx) * u64::from(r.ebx) / u64::from(r.eax);
+        }
+    }
+    // hypervisors that report the TSC frequency, in kHz, such as KVM and VMware
+    if tsc_hz == 0 && cpu_flags & FLAG_HYPERVISOR != 0 && __cpuid(0x4000_0000).eax >= 0x4000_0010 {
+        tsc_hz = u64::from(__cpuid(0x4000_0010).eax) * 1_000;
+    }
+    // the base frequency in MHz, which the TSC ticks at on Intel CPUs without leaf 0x15
+    if tsc_hz == 0 && max_leaf >= 0x16 {
+        tsc_hz = u64::from(__cpuid(0x16).eax & 0xffff) * 1_000_000;
+    }
+    CpuInfo {
+        tsc_hz,
+        cpu_model,
+        cpu_flags,
+    }
+}
+
+// `cntvct_el0` ticks at the frequency in `cntfrq_el0`, which the firmware sets
+#[cfg(all(target_arch = "aarch64", not(target_os = "macos")))]
+fn _cpu_info() -> CpuInfo {
+    let tsc_hz: u64;
+
+    unsafe {
+        ::core::arch::asm!("mrs {}, cntfrq_el0", out(reg) tsc_hz);
+    }
+
+    CpuInfo {
+        tsc_hz,
+        cpu_model: [0; 48],
+        cpu_flags: 0,
+    }
+}
+
+#[cfg(not(any(
+    all(
+        target_arch = "x86_64",
+        target_feature = "sse2",
+        not(target_os = "macos")
+    ),
+    all(target_arch = "aarch64", not(target_os = "macos")),
+)))]
+fn _cpu_info() -> CpuInfo {
+    CpuInfo {
+        tsc_hz: 0,
+        cpu_model: [0; 48],
+        cpu_flags: 0,
+    }
+}
@@ -0,0 +1,15 @@
+use std::io;
+
+use thiserror::Error;
+
+/// Why poll timing could not be enabled or disabled
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum PollTimingError {
+    #[error("failed to install or restore the signal handler")]
+    SigactionFailed(#[source] io::Error),
+    #[error("failed to create the pthread key (error {0})")]
+    PthreadKeyFailed(i32),
+    #[error("failed to calibrate the TSC against the monotonic clock")]
+    CalibrationFailed,
+}
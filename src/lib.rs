@@ -1,50 +1,571 @@
 use std::{
-    future::Future,
-    io::Write,
-    mem::MaybeUninit,
+    cell::RefCell,
+    ffi::c_int,
+    fmt,
+    future::{Future, IntoFuture},
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
     pin::Pin,
-    sync::{atomic, LazyLock, Once, OnceLock},
+    sync::{
+        atomic::{self, AtomicPtr, AtomicU64},
+        mpsc::Sender,
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+#[cfg(unix)]
+mod altstack;
 mod calibration;
+mod calibration_cache;
+mod cpu_info;
+mod error;
+mod pr_builder;
+mod ring;
+mod signal_ring;
 mod stats;
+#[cfg(not(unix))]
+mod stub;
 mod tsc;
+#[cfg(all(feature = "vdso", target_os = "linux", target_pointer_width = "64"))]
+mod vdso;
 mod writer;
 
+pub use calibration::CalibrationConfig;
+pub use error::PollTimingError;
+pub use stats::{Ewma, HdrHistogram};
+pub use writer::{CalibrationData, Event, ExecutorEventKind, ProcessInfoData};
+
+#[cfg(unix)]
+use libc::SIGPROF;
+#[cfg(not(unix))]
+use stub::*;
+#[cfg(not(unix))]
+pub use stub::{
+    disable_sigaltstack, enable_sigaltstack, read_timestamp_pthread_key,
+    write_timestamp_pthread_key,
+};
+
+/// Attribute macros, which need the `macros` feature. They are kept out of the crate root
+/// because [`poll_timed!`] takes the name there.
+#[cfg(feature = "macros")]
+pub mod attr {
+    pub use pollcatch_macros::poll_timed;
+}
+
+/// The PR file format, for tools that write PR files of their own, like the decoder when it
+/// merges them. Needs the `pr-format` feature.
+#[cfg(feature = "pr-format")]
+pub mod pr {
+    pub use crate::pr_builder::RecordBuilder;
+    pub use crate::writer::{write_pr_header, write_record};
+}
+
+/// Internals for the benchmarks in `benches/`. Not part of the API.
+#[doc(hidden)]
+pub mod __bench {
+    pub fn tsc_now() -> u64 {
+        crate::tsc::now()
+    }
+
+    pub fn nanotime() -> u64 {
+        crate::nanotime()
+    }
+
+    /// Writes the events of `rx` to `f` like the performance writer thread, until all the
+    /// senders are dropped
+    pub fn writer_fn(
+        rx: std::sync::mpsc::Receiver<crate::Event>,
+        f: Box<dyn std::io::Write + Send>,
+    ) -> std::io::Result<()> {
+        crate::writer::writer_fn(rx, f, &Default::default())
+    }
+}
+
+#[cfg(not(feature = "noop"))]
 pin_project_lite::pin_project! {
     /// A future that times the time since the last poll
     pub struct PollTimingFuture<F> {
         #[pin]
-        inner: F
+        inner: F,
+        min_duration_ns: u64,
+        long_poll_span_ns: Option<u64>,
+        poll_time_counter: Option<Arc<AtomicU64>>,
+        label: Option<&'static str>,
     }
 }
 
-static PERFORMANCE_WRITER: OnceLock<std::sync::mpsc::Sender<writer::Event>> = OnceLock::new();
+#[cfg(feature = "noop")]
+pin_project_lite::pin_project! {
+    /// A future that times the time since the last poll. With the `noop` feature, it only
+    /// polls the wrapped future.
+    pub struct PollTimingFuture<F> {
+        #[pin]
+        inner: F,
+    }
+}
 
-pub fn start_performance_writer(f: Box<dyn Write + Send>) {
-    PERFORMANCE_WRITER.get_or_init(|| writer::start_writer(f));
+/// Like a `OnceLock<Arc<Writer>>`, but can be cleared in the child of a `fork`, where the
+/// writer thread doesn't exist. Cleared writers are leaked, since another thread may have been
+/// using one.
+struct WriterSlot {
+    writer: AtomicPtr<Arc<writer::Writer>>,
+    /// Held while setting the writer
+    init: Mutex<()>,
 }
 
-static ENABLE_POLL_LOCK: Once = Once::new();
+impl WriterSlot {
+    const fn new() -> Self {
+        WriterSlot {
+            writer: AtomicPtr::new(std::ptr::null_mut()),
+            init: Mutex::new(()),
+        }
+    }
 
-// Technically this doesn't need to be a separate LazyLock due to the Once. However,
-// different implementations have this as something that is not an Once, so keeping
-// it a LazyLock.
-static TIMESTAMP_PTHREAD_KEY: LazyLock<libc::c_int> = LazyLock::new(|| unsafe {
-    let mut key = !0;
-    if libc::pthread_key_create(&mut key, None) < 0 {
-        -1
-    } else {
-        key as libc::c_int
+    fn get_writer(&self) -> Option<&Arc<writer::Writer>> {
+        // safety: the writer is never freed once set
+        unsafe { self.writer.load(atomic::Ordering::Acquire).as_ref() }
+    }
+
+    fn get(&self) -> Option<&writer::EventSender> {
+        self.get_writer().map(|writer| &writer.sender)
+    }
+
+    fn get_or_init(&self, f: impl FnOnce() -> writer::Writer) -> &Arc<writer::Writer> {
+        let _init = self.init.lock().unwrap();
+        if let Some(writer) = self.get_writer() {
+            return writer;
+        }
+        let writer = Box::into_raw(Box::new(Arc::new(f())));
+        self.writer.store(writer, atomic::Ordering::Release);
+        // safety: just leaked
+        unsafe { &*writer }
+    }
+
+    /// Sets the writer `f` starts, failing with `AlreadyExists` without calling `f` if a
+    /// writer is set already
+    fn try_init(
+        &self,
+        f: impl FnOnce() -> io::Result<writer::Writer>,
+    ) -> io::Result<&Arc<writer::Writer>> {
+        let _init = self.init.lock().unwrap();
+        if self.get_writer().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a performance writer is already running",
+            ));
+        }
+        let writer = Box::into_raw(Box::new(Arc::new(f()?)));
+        self.writer.store(writer, atomic::Ordering::Release);
+        // safety: just leaked
+        Ok(unsafe { &*writer })
+    }
+
+    /// Async-signal-safe, for after `fork`
+    #[cfg(unix)]
+    fn clear(&self) {
+        // the thread doesn't exist in the child, so its handle can't even be dropped, which
+        // leaking the writer takes care of
+        self.writer
+            .store(std::ptr::null_mut(), atomic::Ordering::Release);
+    }
+}
+
+static PERFORMANCE_WRITER: WriterSlot = WriterSlot::new();
+
+/// Starts the performance writer, which writes the performance data (the PR file) to `f`
+/// from a dedicated thread. `f` can be any writer, such as a file, a `TcpStream` to a
+/// collector, or an encoder wrapping either.
+///
+/// Only the first call starts a writer, later ones drop `f` and return a handle to the running
+/// writer. [`enable_poll_timing`] calls this with its log file.
+///
+/// The returned [`WriterHandle`] can be dropped, which leaves the writer running:
+///
+/// ```
+/// let path = std::env::temp_dir().join(format!("doc-handle-{}.pr", std::process::id()));
+/// let writer = pollcatch::start_performance_writer(Box::new(std::fs::File::create(&path)?));
+/// pollcatch::enable_poll_timing(Box::new(std::io::sink()))?;
+/// // ... run the workload ...
+/// writer.stop()?;
+/// // the PR file is complete now
+/// # std::fs::remove_file(&path)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn start_performance_writer(f: Box<dyn Write + Send>) -> WriterHandle {
+    WriterHandle::new(PERFORMANCE_WRITER.get_or_init(|| writer::start_writer(f)))
+}
+
+/// Like [`start_performance_writer`], but compresses the performance data with zstd. The
+/// decoder decompresses it transparently.
+#[cfg(feature = "zstd")]
+pub fn start_performance_writer_compressed(f: Box<dyn Write + Send>) -> WriterHandle {
+    WriterHandle::new(PERFORMANCE_WRITER.get_or_init(|| writer::start_writer_compressed(f)))
+}
+
+/// Like [`start_performance_writer`], but keeps only the most recent performance data, like a
+/// flight recorder: `f` is resized to exactly `capacity_bytes` and memory-mapped, and once it
+/// is full, new events overwrite the oldest ones. This includes the session and process
+/// events written at startup.
+///
+/// Unlike [`start_performance_writer`], this fails with [`io::ErrorKind::AlreadyExists`] if
+/// a writer is already running, and leaves `f` untouched then.
+pub fn start_performance_writer_ring(
+    f: &std::fs::File,
+    capacity_bytes: usize,
+) -> io::Result<WriterHandle> {
+    let writer = PERFORMANCE_WRITER.try_init(|| writer::start_writer_ring(f, capacity_bytes))?;
+    Ok(WriterHandle::new(writer))
+}
+
+/// Like [`start_performance_writer`], but appends to the PR file at `path` instead of
+/// overwriting it, so that a service that restarts, e.g. under systemd, keeps the data of its
+/// previous runs. The file is created if it doesn't exist, and must otherwise be an
+/// uncompressed PR file.
+///
+/// Each run starts with its own session start event, which the decoder uses to tell the runs
+/// apart. Only one process may write to the file at a time. If the previous one was killed in
+/// the middle of a write, decode the file with `--skip-corrupt`.
+///
+/// Fails with [`io::ErrorKind::AlreadyExists`] if a writer is already running, without
+/// opening the file.
+///
+/// Call this before [`enable_poll_timing`], whose log file is then unused:
+///
+/// ```no_run
+/// pollcatch::start_performance_writer_append("/var/log/my-service.pr".as_ref())?;
+/// pollcatch::enable_poll_timing(Box::new(std::io::sink()))?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn start_performance_writer_append(path: &Path) -> io::Result<WriterHandle> {
+    let writer = PERFORMANCE_WRITER.try_init(|| writer::start_writer_append(path))?;
+    Ok(WriterHandle::new(writer))
+}
+
+/// Like [`start_performance_writer`], but sends the performance data to a collector at
+/// `addr`, one UDP datagram per record, from an ephemeral local port. This is for collecting
+/// the data of many processes centrally rather than in a file next to each.
+///
+/// There is no PR file header: the collector should keep the datagrams of each sender in a
+/// file of their own, after a header, to decode them. Datagrams that are lost on the way show
+/// up as lost events in the decoder.
+///
+/// Fails with [`io::ErrorKind::AlreadyExists`] if a writer is already running.
+pub fn start_performance_writer_udp(addr: SocketAddr) -> io::Result<WriterHandle> {
+    let writer = PERFORMANCE_WRITER.try_init(|| writer::start_writer_udp(addr))?;
+    Ok(WriterHandle::new(writer))
+}
+
+/// Starts a performance writer that writes into an in-memory buffer rather than a file, for
+/// tests and for embedding.
+///
+/// Unlike [`start_performance_writer`], the writer is not used by [`enable_poll_timing`];
+/// events are sent to it through the returned sender. The buffer is filled as the writer
+/// flushes, which happens at least once a flush interval, on [`Event::Flush`], and once all
+/// senders are dropped.
+pub fn start_performance_writer_in_memory() -> (Sender<Event>, Arc<Mutex<Vec<u8>>>) {
+    writer::start_writer_in_memory()
+}
+
+/// Like [`start_performance_writer`], but writes the performance data using tokio's async
+/// file I/O, from a tokio task rather than a dedicated OS thread.
+///
+/// Must be called from within a tokio runtime, and before [`enable_poll_timing`] (whose
+/// log file is then unused). The task ends with the session, see
+/// [`stop_performance_writer`], or when the runtime shuts down, after which the events are
+/// dropped.
+#[cfg(feature = "tokio")]
+pub fn start_async_writer(f: tokio::fs::File) -> WriterHandle {
+    WriterHandle::new(PERFORMANCE_WRITER.get_or_init(|| writer::start_async_writer(f)))
+}
+
+/// How long [`stop_performance_writer`] waits for the writer to write the end of the session
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ends the recording: disables poll timing, writes an end-of-session event with the number
+/// of sampled and recorded polls, and waits for the performance writer to flush and exit.
+/// Returns whether it did so in time.
+///
+/// The decoder warns about PR files without the end-of-session event, which were cut short,
+/// such as by a crash. The writer can't be restarted, so events sent after this are dropped.
+///
+/// # Panics
+///
+/// If the writer's thread panicked, this resumes the panic, so that the bug doesn't go
+/// unnoticed.
+pub fn stop_performance_writer() -> bool {
+    let Some(writer) = PERFORMANCE_WRITER.get_writer() else {
+        return false;
+    };
+    let ended = end_session(writer).is_ok();
+    writer.join_exited(ended);
+    ended
+}
+
+/// Disables poll timing and sends `writer` an end-of-session event, waiting for it to be
+/// written
+fn end_session(writer: &writer::Writer) -> io::Result<()> {
+    disable_poll_timing().ok();
+    writer
+        .sender
+        .send(writer::Event::EndOfSession {
+            session_id: *SESSION_ID.lock().unwrap(),
+            total_polls: SAMPLED_POLLS.load(atomic::Ordering::Relaxed),
+            total_long_polls: RECORDED_POLLS.load(atomic::Ordering::Relaxed),
+        })
+        .map_err(|_| writer_exited())?;
+    if !writer.state.wait_session_ended(STOP_TIMEOUT) {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the performance writer didn't end the session in time",
+        ));
+    }
+    Ok(())
+}
+
+fn writer_exited() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "the performance writer has exited",
+    )
+}
+
+/// A handle to the running performance writer, returned by [`start_performance_writer`]
+/// and its variants.
+///
+/// Dropping it leaves the writer running, and all handles refer to the same writer.
+#[derive(Clone)]
+pub struct WriterHandle {
+    writer: Arc<writer::Writer>,
+}
+
+impl WriterHandle {
+    fn new(writer: &Arc<writer::Writer>) -> Self {
+        WriterHandle {
+            writer: writer.clone(),
+        }
+    }
+
+    /// Makes the writer flush the events sent to it so far, like [`flush_performance_writer`].
+    /// Returns without waiting for the flush, or an error if the writer has exited.
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer
+            .sender
+            .send(writer::Event::Flush)
+            .map_err(|_| writer_exited())
+    }
+
+    /// Returns the number of records the writer has written so far, some of which may still be
+    /// buffered until it flushes
+    pub fn events_written(&self) -> u64 {
+        self.writer
+            .state
+            .events_written
+            .load(atomic::Ordering::Relaxed)
+    }
+
+    /// Ends the recording like [`stop_performance_writer`], and waits for the writer to exit.
+    /// Returns the error the writer exited with, if any, or an error if it didn't end the
+    /// session in time or had already exited.
+    ///
+    /// # Panics
+    ///
+    /// If the writer's thread panicked, this resumes the panic.
+    pub fn stop(self) -> io::Result<()> {
+        let ended = end_session(&self.writer);
+        match self.writer.join_exited(ended.is_ok()) {
+            Some(Err(e)) => Err(e),
+            _ => ended,
+        }
+    }
+}
+
+impl fmt::Debug for WriterHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriterHandle")
+            .field("events_written", &self.events_written())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Calls [`stop_performance_writer`] when dropped, so the recording ends cleanly when
+/// `main` returns or unwinds.
+///
+/// ```
+/// let _session = pollcatch::SessionGuard::new();
+/// // the recording ends when `_session` goes out of scope, at the end of `main`
+/// ```
+#[must_use]
+#[derive(Debug, Default)]
+pub struct SessionGuard {
+    _private: (),
+}
+
+impl SessionGuard {
+    pub fn new() -> Self {
+        SessionGuard::default()
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            // a second panic would abort
+            std::panic::catch_unwind(stop_performance_writer).ok();
+        } else {
+            stop_performance_writer();
+        }
+    }
+}
+
+/// Returns the number of performance writers that stopped because of an I/O error.
+///
+/// Transient errors are retried first, see [`set_writer_timeout_retries`].
+pub fn writer_error_count() -> u64 {
+    writer::WRITER_ERRORS.load(atomic::Ordering::Relaxed)
+}
+
+/// Sets how many times the performance writer retries a write that timed out before giving
+/// up, 3 by default. Interrupted and would-block writes are always retried.
+pub fn set_writer_timeout_retries(retries: u32) {
+    writer::TIMED_OUT_RETRIES.store(retries, atomic::Ordering::Relaxed);
+}
+
+/// Makes the performance writer flush the events sent to it so far, rather than when its
+/// flush interval is up. Returns without waiting for the flush.
+///
+/// See [`PollTimingConfig::with_flush_interval`].
+pub fn flush_performance_writer() {
+    if let Some(ch) = PERFORMANCE_WRITER.get() {
+        ch.send(writer::Event::Flush).ok();
+    }
+}
+
+/// Returns the number of sampled polls that were not recorded because their thread moved to
+/// another CPU between the start of the poll and the signal. The TSCs of different sockets
+/// may not agree, which would make the duration wrong.
+pub fn cpu_migration_skipped() -> u64 {
+    CPU_MIGRATION_SKIPPED.load(atomic::Ordering::Relaxed)
+}
+
+/// Measures how many TSC ticks timing a poll adds to the poll, as the least of 1000 tries at
+/// running what lies between the two TSC reads of a sampled poll that does nothing, and
+/// subtracts that from the durations of the polls recorded from then on. Returns the ticks.
+///
+/// [`enable_poll_timing`] calls this, before which timing does nothing and costs nothing.
+/// Calling it again is useful if the CPU frequency changed since.
+pub fn calibrate_overhead() -> u64 {
+    let mut overhead = u64::MAX;
+    for _ in 0..OVERHEAD_CALIBRATION_POLLS {
+        let before = tsc::now_serialized();
+        // the key is written at the start and read at the end, here it's read both times so
+        // that a sample of the calling thread isn't lost. `write_timestamp` then reads the
+        // monotonic clock before the end.
+        std::hint::black_box(read_timestamp_pthread_key());
+        std::hint::black_box(read_timestamp_pthread_key());
+        std::hint::black_box(nanotime());
+        overhead = overhead.min(tsc::now().saturating_sub(before));
     }
-});
+    POLL_OVERHEAD_TICKS.store(overhead, atomic::Ordering::Relaxed);
+    overhead
+}
+
+/// The TSC ticks timing a poll adds to it, see [`calibrate_overhead`]
+static POLL_OVERHEAD_TICKS: AtomicU64 = AtomicU64::new(0);
+const OVERHEAD_CALIBRATION_POLLS: u64 = 1000;
+
+static CPU_MIGRATION_SKIPPED: AtomicU64 = AtomicU64::new(0);
+static SAMPLED_POLLS: AtomicU64 = AtomicU64::new(0);
+static RECORDED_POLLS: AtomicU64 = AtomicU64::new(0);
+/// `CPU_MIGRATION_SKIPPED` when the skip rate was last checked
+static CPU_MIGRATION_SKIPPED_CHECKED: AtomicU64 = AtomicU64::new(0);
+/// Check the skip rate every this many sampled polls
+const CPU_MIGRATION_CHECK_INTERVAL: u64 = 1000;
+
+/// The CPU the calling thread runs on, async-signal-safe (glibc and musl read it from the
+/// vDSO or rseq area, falling back to a syscall)
+#[cfg(target_os = "linux")]
+#[inline]
+fn current_cpu() -> Option<u32> {
+    u32::try_from(unsafe { libc::sched_getcpu() }).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+fn current_cpu() -> Option<u32> {
+    None
+}
+
+/// The pthread key value the signal handler writes: the low bit marks the poll as sampled,
+/// and the rest is the CPU the signal arrived on plus one, or 0 if it is unknown
+fn sampled_key_value(cpu: Option<u32>) -> usize {
+    1 | cpu.map_or(0, |cpu| (cpu as usize + 1) << 1)
+}
+
+/// Whether a sampled poll moved CPUs, counting it if so
+fn cpu_migrated(start_cpu: Option<u32>, key_value: usize) -> bool {
+    let sampled = SAMPLED_POLLS.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+    let signal_cpu = (key_value >> 1).checked_sub(1);
+    let migrated =
+        matches!((start_cpu, signal_cpu), (Some(start), Some(signal)) if start as usize != signal);
+    if migrated {
+        CPU_MIGRATION_SKIPPED.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+    if sampled.is_multiple_of(CPU_MIGRATION_CHECK_INTERVAL) {
+        let skipped = CPU_MIGRATION_SKIPPED.load(atomic::Ordering::Relaxed);
+        let since_check = skipped
+            .saturating_sub(CPU_MIGRATION_SKIPPED_CHECKED.swap(skipped, atomic::Ordering::Relaxed));
+        // more than 1%
+        if since_check * 100 > CPU_MIGRATION_CHECK_INTERVAL {
+            tracing::warn!(
+                message = "many sampled polls skipped because of CPU migrations",
+                skipped = since_check,
+                sampled = CPU_MIGRATION_CHECK_INTERVAL,
+            );
+        }
+    }
+    migrated
+}
+
+#[cfg(unix)]
+type SavedAction = libc::sigaction;
+
+/// While poll timing is enabled, the signal it samples with and the action it replaced
+static ENABLE_POLL_LOCK: Mutex<Option<(c_int, SavedAction)>> = Mutex::new(None);
+/// Whether `ENABLE_POLL_LOCK` holds `Some`, for [`is_poll_timing_enabled`] to read without
+/// locking. Only written with the lock held, except in the child of a `fork`.
+static POLL_TIMING_ENABLED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+// Technically this doesn't need to be a separate LazyLock due to the lock. However,
+// different implementations have this as something that is not a lock, so keeping
+// it a LazyLock.
+//
+// The key holds a timestamp rather than a pointer to anything, so there is nothing to clean
+// up when a thread exits, and no destructor. The signal rings are in a key of their own, whose
+// destructor frees them.
+#[cfg(unix)]
+static TIMESTAMP_PTHREAD_KEY: std::sync::LazyLock<Result<libc::pthread_key_t, libc::c_int>> =
+    std::sync::LazyLock::new(|| unsafe {
+        let mut key = 0;
+        match libc::pthread_key_create(&mut key, None) {
+            0 => Ok(key),
+            err => Err(err),
+        }
+    });
 
 static TIMESTAMP_PTHREAD_KEY_ASYNC_SIGNAL_SAFE: std::sync::atomic::AtomicIsize =
     std::sync::atomic::AtomicIsize::new(-1);
+#[cfg(unix)]
 static SIGACTION: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+/// The `sa_flags` of the action in `SIGACTION`, which tell how to call it
+#[cfg(unix)]
+static SIGACTION_FLAGS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
 
+#[cfg(unix)]
 fn empty_sigset() -> libc::sigset_t {
-    let mut result: MaybeUninit<libc::sigset_t> = MaybeUninit::zeroed();
+    let mut result: std::mem::MaybeUninit<libc::sigset_t> = std::mem::MaybeUninit::zeroed();
     unsafe {
         if libc::sigemptyset(result.as_mut_ptr()) != 0 {
             panic!();
@@ -53,25 +574,42 @@ fn empty_sigset() -> libc::sigset_t {
     }
 }
 
+#[cfg(unix)]
 #[allow(non_camel_case_types)]
 type sigaction_t = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+/// The handler of an action without `SA_SIGINFO`
+#[cfg(unix)]
+#[allow(non_camel_case_types)]
+type sighandler_t = extern "C" fn(libc::c_int);
 
+#[cfg(unix)]
 extern "C" fn my_action(sig: libc::c_int, info: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
     unsafe {
-        write_timestamp_pthread_key(1);
+        write_timestamp_pthread_key(sampled_key_value(current_cpu()));
+        signal_ring::push_current(tsc::now());
         let sig_fn = SIGACTION.load(atomic::Ordering::Acquire);
         if sig_fn != 0 && sig_fn != libc::SIG_DFL && sig_fn != libc::SIG_IGN {
-            std::mem::transmute::<usize, sigaction_t>(sig_fn)(sig, info, ucontext);
+            // calling a handler with the wrong signature is UB, even if the extra arguments
+            // happen to be ignored on common ABIs
+            let flags = SIGACTION_FLAGS.load(atomic::Ordering::Relaxed) as c_int;
+            if flags & libc::SA_SIGINFO != 0 {
+                std::mem::transmute::<usize, sigaction_t>(sig_fn)(sig, info, ucontext);
+            } else {
+                std::mem::transmute::<usize, sighandler_t>(sig_fn)(sig);
+            }
         }
     }
 }
 
-fn enable_poll_timing_pthread_key() {
+#[cfg(unix)]
+fn enable_poll_timing_pthread_key() -> Result<(), PollTimingError> {
     // reading a #[thread_local] is not async signal safe, which is why we use a
     // LazyLock (to synchronize writers of the pthread key), an AtomicI64
     // to synchronize readers of the pthread key, and a pthread key to synchronize threads.
+    #[cfg(not(feature = "fast-tls"))]
+    signal_ring::init_pthread_key().map_err(PollTimingError::PthreadKeyFailed)?;
     // force the pthread key
-    let pthread_key = *TIMESTAMP_PTHREAD_KEY as isize;
+    let pthread_key = (*TIMESTAMP_PTHREAD_KEY).map_err(PollTimingError::PthreadKeyFailed)? as isize;
     // and write it to the variable. Use an *atomic* write here to ensure that no thread
     // will try to access the pthread-key before it is defined.
     //
@@ -81,9 +619,49 @@ fn enable_poll_timing_pthread_key() {
     // This assumes that it's OK to use lock-free atomics from signals as per C11
     TIMESTAMP_PTHREAD_KEY_ASYNC_SIGNAL_SAFE
         .store(pthread_key, std::sync::atomic::Ordering::Release);
+    Ok(())
+}
+
+/// The size of the alternate signal stack that [`enable_poll_timing`] installs with
+/// [`PollTimingConfig::with_sigaltstack`]
+#[cfg(unix)]
+const DEFAULT_SIGALTSTACK_SIZE: usize = 64 * 1024;
+
+/// Installs an alternate signal stack of at least `stack_size` bytes on the calling thread,
+/// for the signal handler to run on with [`PollTimingConfig::with_sigaltstack`]. Does
+/// nothing if the thread already has one that large. The stack is freed when the thread
+/// exits, or by [`disable_sigaltstack`].
+///
+/// Call it on each thread that polls futures, e.g. the worker threads of a tokio runtime:
+///
+/// ```
+/// let runtime = tokio::runtime::Builder::new_multi_thread()
+///     .on_thread_start(|| {
+///         pollcatch::enable_sigaltstack(64 * 1024).ok();
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+#[cfg(unix)]
+pub fn enable_sigaltstack(stack_size: usize) -> io::Result<()> {
+    altstack::install(stack_size)
+}
+
+/// Uninstalls and frees the alternate signal stack that [`enable_sigaltstack`] installed on the
+/// calling thread, putting back the one it replaced. Does nothing if there is none.
+/// [`disable_poll_timing`] does this on the thread that calls it.
+#[cfg(unix)]
+pub fn disable_sigaltstack() -> io::Result<()> {
+    altstack::uninstall()
 }
 
-fn enable_poll_timing_signal_handler(signum: libc::c_int) {
+/// Returns the action that was replaced. With `on_altstack`, the handler runs on the
+/// alternate signal stack of threads that have one.
+#[cfg(unix)]
+fn enable_poll_timing_signal_handler(
+    signum: c_int,
+    on_altstack: bool,
+) -> Result<SavedAction, PollTimingError> {
     // safety: my_action is safe to call
     unsafe {
         // Null out the signal action to ensure nothing unintended happens.
@@ -91,10 +669,14 @@ fn enable_poll_timing_signal_handler(signum: libc::c_int) {
         // is sigaction / signal handler. store-acquire is not a thing).
         SIGACTION.store(0, atomic::Ordering::Relaxed);
 
+        let mut sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        if on_altstack {
+            sa_flags |= libc::SA_ONSTACK;
+        }
         let act: libc::sigaction = libc::sigaction {
-            sa_sigaction: my_action as usize,
+            sa_sigaction: my_action as sigaction_t as usize,
             sa_mask: empty_sigset(),
-            sa_flags: libc::SA_SIGINFO | libc::SA_RESTART,
+            sa_flags,
             sa_restorer: None,
         };
         let mut oldact: libc::sigaction = libc::sigaction {
@@ -104,17 +686,274 @@ fn enable_poll_timing_signal_handler(signum: libc::c_int) {
             sa_restorer: None,
         };
         if libc::sigaction(signum, &act, &mut oldact) != 0 {
-            panic!("sigaction {:?}", std::io::Error::last_os_error());
+            return Err(PollTimingError::SigactionFailed(
+                std::io::Error::last_os_error(),
+            ));
         }
+        // the flags are published along with the handler by the release store
+        SIGACTION_FLAGS.store(oldact.sa_flags as usize, atomic::Ordering::Relaxed);
         // if a signal handler gets the new signal handler,
         SIGACTION.store(oldact.sa_sigaction, atomic::Ordering::Release);
+        Ok(oldact)
+    }
+}
+
+#[cfg(unix)]
+fn disable_poll_timing_signal_handler(
+    signum: c_int,
+    oldact: &SavedAction,
+) -> Result<(), PollTimingError> {
+    // safety: restoring an action that was installed before
+    unsafe {
+        if libc::sigaction(signum, oldact, std::ptr::null_mut()) != 0 {
+            return Err(PollTimingError::SigactionFailed(
+                std::io::Error::last_os_error(),
+            ));
+        }
     }
+    // a handler that is still running might skip calling the old action, which is
+    // indistinguishable from the signal arriving just before it was restored
+    SIGACTION.store(0, atomic::Ordering::Relaxed);
+    Ok(())
 }
 
-fn calibrate_clock_and_send_to_performance_writer() {
+/// Runs in the child of a `fork`, on the thread that forked, which is its only one. The
+/// writer thread is gone, so this stops recording until poll timing is enabled again.
+/// Async-signal-safe, like everything after a `fork` has to be.
+#[cfg(unix)]
+extern "C" fn reset_after_fork() {
+    PERFORMANCE_WRITER.clear();
+    TIMESTAMP_PTHREAD_KEY_ASYNC_SIGNAL_SAFE.store(-1, atomic::Ordering::Release);
+    POLL_TIMING_ENABLED.store(false, atomic::Ordering::Release);
+    // a thread that held the lock when forking is gone and won't release it, so give up then
+    if let Ok(mut enabled) = ENABLE_POLL_LOCK.try_lock() {
+        if let Some((signum, oldact)) = enabled.take() {
+            disable_poll_timing_signal_handler(signum, &oldact).ok();
+        }
+    }
+    // the thread has another tid in the child, and a new writer needs its name again
+    TID.with(|tid| tid.set(0));
+    THREAD_NAME_SENT.with(|sent| sent.set(false));
+}
+
+#[cfg(unix)]
+fn register_fork_handler() {
+    static REGISTERED: std::sync::Once = std::sync::Once::new();
+    REGISTERED.call_once(|| {
+        // safety: `reset_after_fork` is async-signal-safe
+        unsafe {
+            libc::pthread_atfork(None, None, Some(reset_after_fork));
+        }
+    });
+}
+
+fn random_u64() -> u64 {
+    // RandomState is seeded from the OS on first use, which is random enough for an id
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// The id of the last session started, which [`stop_performance_writer`] ends
+static SESSION_ID: Mutex<u128> = Mutex::new(0);
+
+fn send_session_start_to_performance_writer() {
+    if let Some(ch) = PERFORMANCE_WRITER.get() {
+        let wall_time_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let session_id = (u128::from(random_u64()) << 64) | u128::from(random_u64());
+        *SESSION_ID.lock().unwrap() = session_id;
+        ch.send(writer::Event::SessionStart {
+            session_id,
+            wall_time_ns,
+            pid: std::process::id(),
+        })
+        .ok();
+    }
+}
+
+/// Copies `src` into a zero-padded array, truncating it so that it stays null-terminated
+fn to_fixed_cstr<const N: usize>(src: &[u8]) -> [u8; N] {
+    let mut result = [0; N];
+    let len = src.len().min(N - 1);
+    result[..len].copy_from_slice(&src[..len]);
+    result
+}
+
+#[cfg(unix)]
+fn hostname() -> Vec<u8> {
+    let mut buf = [0u8; 256];
+    // safety: the buffer is valid for its length
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return Vec::new();
+    }
+    buf.iter().copied().take_while(|&b| b != 0).collect()
+}
+
+/// The start time of this process in `/proc/self/stat`, its 22nd field
+fn process_start_time() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // after the command name, which can contain spaces and parentheses
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+fn cmdline() -> Vec<u8> {
+    let mut cmdline = std::fs::read("/proc/self/cmdline").unwrap_or_default();
+    // arguments are null-separated, with a trailing null
+    if cmdline.last() == Some(&0) {
+        cmdline.pop();
+    }
+    for b in cmdline.iter_mut() {
+        if *b == 0 {
+            *b = b' ';
+        }
+    }
+    cmdline
+}
+
+fn send_process_info_to_performance_writer() {
+    if let Some(ch) = PERFORMANCE_WRITER.get() {
+        ch.send(writer::Event::ProcessInfo {
+            data: Box::new(writer::ProcessInfoData {
+                pid: std::process::id(),
+                hostname: to_fixed_cstr(&hostname()),
+                cmdline: to_fixed_cstr(&cmdline()),
+                start_time: process_start_time().unwrap_or(0),
+            }),
+        })
+        .ok();
+    }
+}
+
+fn send_cpu_info_to_performance_writer() {
+    if let Some(ch) = PERFORMANCE_WRITER.get() {
+        let info = cpu_info::cpu_info();
+        ch.send(writer::Event::CpuInfo {
+            tsc_hz: info.tsc_hz,
+            cpu_model: info.cpu_model,
+            cpu_flags: info.cpu_flags,
+        })
+        .ok();
+    }
+}
+
+/// The calibration parameters for this machine, when they aren't configured
+fn default_calibration_config() -> CalibrationConfig {
+    let hypervisor = cpu_info::cpu_info().cpu_flags & cpu_info::FLAG_HYPERVISOR != 0;
+    tracing::info!(message = "checked for a hypervisor", hypervisor);
+    if hypervisor {
+        CalibrationConfig::hypervisor()
+    } else {
+        CalibrationConfig::default()
+    }
+}
+
+fn calibrate_clock(
+    config: &CalibrationConfig,
+) -> Result<calibration::Calibration, PollTimingError> {
     let mut calibration: calibration::Calibration = calibration::Calibration::default();
-    calibration.calibrate(&nanotime, &tsc::now);
+    if let Some((numer, denom)) = tsc::frequency() {
+        // exact, no need to measure it
+        calibration.set_ratio(&nanotime, &tsc::now, numer, denom);
+        return Ok(calibration);
+    }
+    let result = calibration.calibrate(&nanotime, &tsc::now, config);
+    tracing::info!(
+        message = "calibrated TSC to the monotonic clock",
+        mean_error_ns = result.mean_error_ns,
+        std_dev_ns = result.std_dev_ns,
+        min_error_ns = result.min_error_ns,
+        max_error_ns = result.max_error_ns,
+        rounds = result.rounds,
+        converged = result.converged,
+    );
+    if !result.converged {
+        tracing::warn!(
+            "TSC calibration did not converge, poll durations may be inaccurate. \
+             Consider extending the calibration window with PollTimingConfig::with_calibration."
+        );
+    }
+    if result.rounds == 0 || calibration.scale_factor == 0 {
+        return Err(PollTimingError::CalibrationFailed);
+    }
+    Ok(calibration)
+}
 
+/// How long the calibration loaded from a cache is checked against the clock
+const CACHE_VERIFICATION_NS: u64 = 10_000_000;
+/// How far the measured rate of the TSC can be from a cached calibration for it to be used
+const MAX_CACHE_ERROR: f64 = 0.01;
+
+/// Loads the calibration saved at `path`, checking that it was made on the same kind of CPU
+/// and still matches the clock
+fn load_verified_calibration(path: &Path) -> io::Result<calibration::Calibration> {
+    let Some(calibration) =
+        calibration_cache::load(path, &cpu_info::cpu_info(), nanotime(), tsc::now())?
+    else {
+        return Err(io::Error::other(
+            "the calibration was made on another kind of CPU",
+        ));
+    };
+    let measured = calibration.measure_scale_factor(&nanotime, &tsc::now, CACHE_VERIFICATION_NS);
+    let error = measured as f64 / calibration.scale_factor as f64 - 1.0;
+    if error.abs() > MAX_CACHE_ERROR {
+        return Err(io::Error::other(format!(
+            "the calibration is off from the TSC by {:.1}%",
+            error * 100.0
+        )));
+    }
+    Ok(calibration)
+}
+
+/// Like `calibrate_clock`, but uses the calibration saved at `path` if it was made on the
+/// same kind of CPU and still matches the clock, and otherwise saves the new one there
+fn calibrate_clock_cached(
+    config: &CalibrationConfig,
+    path: &Path,
+) -> Result<calibration::Calibration, PollTimingError> {
+    match load_verified_calibration(path) {
+        Ok(calibration) => {
+            tracing::info!(
+                message = "using the cached TSC calibration",
+                path = %path.display()
+            );
+            return Ok(calibration);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => tracing::info!(
+            message = "not using the cached TSC calibration, calibrating",
+            error = %e
+        ),
+    }
+    let calibration = calibrate_clock(config)?;
+    if let Err(e) = calibration_cache::save(path, &calibration, &cpu_info::cpu_info()) {
+        tracing::warn!(message = "failed to save the TSC calibration", error = %e);
+    }
+    Ok(calibration)
+}
+
+/// Where [`enable_poll_timing_with_config`] can get a calibration from, other than calibrating
+#[derive(Debug, Clone)]
+enum CachedCalibration {
+    /// A cache file, from [`PollTimingConfig::with_calibration_cache`]
+    Path(PathBuf),
+    /// From [`PollTimingConfig::with_saved_calibration`]
+    Saved(calibration::Calibration),
+}
+
+fn calibrate_clock_and_send_to_performance_writer(
+    config: &CalibrationConfig,
+    cache: Option<&CachedCalibration>,
+) -> Result<calibration::Calibration, PollTimingError> {
+    let calibration = match cache {
+        Some(CachedCalibration::Path(path)) => calibrate_clock_cached(config, path)?,
+        Some(CachedCalibration::Saved(calibration)) => *calibration,
+        None => calibrate_clock(config)?,
+    };
+
+    *CALIBRATION.write().unwrap_or_else(|e| e.into_inner()) = Some(calibration);
     if let Some(ch) = PERFORMANCE_WRITER.get() {
         ch.send(writer::Event::CalibrateTscToMonotonic {
             data: writer::CalibrationData {
@@ -126,29 +965,397 @@ fn calibrate_clock_and_send_to_performance_writer() {
         })
         .ok();
     }
+    Ok(calibration)
+}
+
+fn send_wall_clock_anchor_to_performance_writer() {
+    if let Some(ch) = PERFORMANCE_WRITER.get() {
+        let tsc = tsc::now();
+        let monotonic_ns = nanotime();
+        let realtime_ns = realtime_ns();
+        ch.send(writer::Event::WallClockAnchor {
+            tsc,
+            monotonic_ns,
+            realtime_ns,
+        })
+        .ok();
+    }
+}
+
+/// A long poll, as passed to the callback set with
+/// [`PollTimingConfig::with_long_poll_callback`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct LongPoll {
+    pub duration: Duration,
+    /// An exponentially weighted moving average of the durations of the long polls on this
+    /// thread, including this one
+    pub smoothed_duration: Duration,
+    /// The OS thread id of the thread that polled
+    pub tid: u32,
+}
+
+type LongPollCallback = Arc<dyn Fn(&LongPoll) + Send + Sync>;
+
+/// How much each long poll moves `LongPoll::smoothed_duration`
+const LONG_POLL_EWMA_ALPHA: f64 = 0.1;
+
+/// Configuration for [`enable_poll_timing_with_config`]
+#[derive(Clone)]
+pub struct PollTimingConfig {
+    signal: c_int,
+    calibration: Option<CalibrationConfig>,
+    min_recorded_duration: Duration,
+    long_poll_callback: Option<LongPollCallback>,
+    drift_check_interval: Option<Duration>,
+    calibration_cache: Option<CachedCalibration>,
+    sigaltstack: bool,
+    flush_interval: Duration,
+}
+
+impl Default for PollTimingConfig {
+    fn default() -> Self {
+        PollTimingConfig {
+            signal: SIGPROF,
+            calibration: None,
+            min_recorded_duration: Duration::ZERO,
+            long_poll_callback: None,
+            drift_check_interval: Some(DEFAULT_DRIFT_CHECK_INTERVAL),
+            calibration_cache: None,
+            sigaltstack: false,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+        }
+    }
+}
+
+impl fmt::Debug for PollTimingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PollTimingConfig")
+            .field("signal", &self.signal)
+            .field("calibration", &self.calibration)
+            .field("min_recorded_duration", &self.min_recorded_duration)
+            .field("long_poll_callback", &self.long_poll_callback.is_some())
+            .field("drift_check_interval", &self.drift_check_interval)
+            .field("calibration_cache", &self.calibration_cache)
+            .field("sigaltstack", &self.sigaltstack)
+            .field("flush_interval", &self.flush_interval)
+            .finish()
+    }
+}
+
+impl PollTimingConfig {
+    /// Sets the signal the profiler samples with, `SIGPROF` by default
+    pub fn with_signal(mut self, signal: c_int) -> Self {
+        self.signal = signal;
+        self
+    }
+
+    /// Sets the parameters of the TSC calibration done when poll timing is enabled. By default,
+    /// they are [`CalibrationConfig::hypervisor`] when running under a hypervisor, and
+    /// [`CalibrationConfig::default`] otherwise.
+    pub fn with_calibration(mut self, calibration: CalibrationConfig) -> Self {
+        self.calibration = Some(calibration);
+        self
+    }
+
+    /// Only record polls that take at least `duration`. All sampled polls are recorded by
+    /// default.
+    pub fn with_min_recorded_duration(mut self, duration: Duration) -> Self {
+        self.min_recorded_duration = duration;
+        self
+    }
+
+    /// Calls `callback` on the polling thread after each recorded poll, e.g. to act on long
+    /// polls without decoding the PR file. The callback should be quick, since it delays the
+    /// task that was polled.
+    pub fn with_long_poll_callback(
+        mut self,
+        callback: impl Fn(&LongPoll) + Send + Sync + 'static,
+    ) -> Self {
+        self.long_poll_callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets how often a background thread checks whether the TSC still runs at the calibrated
+    /// rate, e.g. because of CPU frequency scaling, or `None` not to check. Once a minute by
+    /// default.
+    ///
+    /// If the rate is off by more than 5%, the TSC is calibrated again, and the new calibration
+    /// is written to the PR file for the decoder and the long poll callback to use from then
+    /// on. The minimum recorded duration keeps using the calibration done when poll timing was
+    /// enabled.
+    pub fn with_drift_check_interval(mut self, interval: Option<Duration>) -> Self {
+        self.drift_check_interval = interval;
+        self
+    }
+
+    /// Caches the TSC calibration in the file at `path`, to skip most of the up to 200ms it
+    /// takes when poll timing is enabled.
+    ///
+    /// A calibration saved there is used if it was made on the same kind of CPU and matches
+    /// a quick 10ms measurement of the TSC. Otherwise, the TSC is calibrated as usual and the
+    /// new calibration saved there. See also [`save_calibration`].
+    pub fn with_calibration_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.calibration_cache = Some(CachedCalibration::Path(path.into()));
+        self
+    }
+
+    /// Uses a calibration loaded with [`load_calibration`] instead of calibrating the TSC when
+    /// poll timing is enabled. Replaces [`PollTimingConfig::with_calibration_cache`].
+    pub fn with_saved_calibration(mut self, calibration: SavedCalibration) -> Self {
+        self.calibration_cache = Some(CachedCalibration::Saved(calibration.0));
+        self
+    }
+
+    /// Runs the signal handler on the alternate signal stack of threads that have one, so
+    /// that a signal that arrives when a thread's stack is almost exhausted doesn't overflow
+    /// it. Off by default.
+    ///
+    /// An alternate stack is per thread. Threads spawned by the standard library have a small
+    /// one, which is enough for the handler. Enabling poll timing installs one on the calling
+    /// thread, and [`enable_sigaltstack`] installs one on others.
+    pub fn with_sigaltstack(mut self, enabled: bool) -> Self {
+        self.sigaltstack = enabled;
+        self
+    }
+
+    /// Sets how long the performance writer buffers events before flushing them to the log
+    /// file, 1 second by default. A shorter interval keeps less in memory, and loses less if
+    /// the process is killed, at the cost of more writes. Intervals under 1ms count as 1ms.
+    /// Enabling poll timing again with a different interval applies it to the running writer
+    /// from its next event. See also [`flush_performance_writer`].
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+}
+
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_DRIFT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How long the quick measurement that checks for drift takes
+const DRIFT_CHECK_NS: u64 = 1_000_000;
+/// How far the measured rate of the TSC can be from the calibrated one
+const MAX_DRIFT: f64 = 0.05;
+static DRIFT_MONITOR: std::sync::Once = std::sync::Once::new();
+
+/// Whether the rate of the TSC measured now is within `MAX_DRIFT` of `calibration`
+fn within_drift(calibration: &calibration::Calibration) -> bool {
+    let measured = calibration.measure_scale_factor(&nanotime, &tsc::now, DRIFT_CHECK_NS);
+    let drift = measured as f64 / calibration.scale_factor as f64 - 1.0;
+    drift.abs() <= MAX_DRIFT
+}
+
+/// Checks for drift from the current calibration every `interval` until the session ends,
+/// calibrating again when there is some
+fn spawn_drift_monitor(interval: Duration, config: CalibrationConfig) {
+    DRIFT_MONITOR.call_once(|| {
+        let monitor = move || {
+            let session_ended = || match PERFORMANCE_WRITER.get_writer() {
+                Some(writer) => writer.state.wait_session_ended(interval),
+                None => {
+                    std::thread::sleep(interval);
+                    false
+                }
+            };
+            while !session_ended() {
+                let Some(calibration) = current_calibration() else {
+                    continue;
+                };
+                // measure twice, in case the thread was preempted in the middle of the first
+                if !is_poll_timing_enabled()
+                    || within_drift(&calibration)
+                    || within_drift(&calibration)
+                {
+                    continue;
+                }
+                tracing::warn!("the TSC drifted from its calibration, calibrating it again");
+                if let Err(e) = calibrate_clock_and_send_to_performance_writer(&config, None) {
+                    tracing::warn!(message = "TSC calibration failed", error = %e);
+                }
+            }
+        };
+        if let Err(e) = std::thread::Builder::new()
+            .name("pollcatch-drift".to_owned())
+            .spawn(monitor)
+        {
+            tracing::warn!(message = "failed to start the TSC drift monitor", error = %e);
+        }
+    });
+}
+
+/// Saves the TSC calibration that poll timing uses to the file at `path`, in the format of
+/// [`PollTimingConfig::with_calibration_cache`], e.g. to ship it along with a service to
+/// machines of the same kind. [`load_calibration`] loads it back.
+///
+/// Fails with [`io::ErrorKind::NotFound`] if poll timing was never enabled.
+pub fn save_calibration(path: &Path) -> io::Result<()> {
+    let calibration = current_calibration().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "there is no calibration, poll timing was never enabled",
+        )
+    })?;
+    calibration_cache::save(path, &calibration, &cpu_info::cpu_info())
+}
+
+/// A TSC calibration loaded with [`load_calibration`]
+#[derive(Debug, Clone, Copy)]
+pub struct SavedCalibration(calibration::Calibration);
+
+/// Loads the calibration saved at `path` by [`save_calibration`], to enable poll timing with
+/// [`PollTimingConfig::with_saved_calibration`] without calibrating the TSC.
+///
+/// Like with [`PollTimingConfig::with_calibration_cache`], this fails if the calibration was
+/// made on another kind of CPU, or doesn't match a quick 10ms measurement of the TSC.
+pub fn load_calibration(path: &Path) -> io::Result<SavedCalibration> {
+    load_verified_calibration(path).map(SavedCalibration)
+}
+
+/// The latest calibration, done when poll timing was last enabled or when the TSC drifted
+static CALIBRATION: RwLock<Option<calibration::Calibration>> = RwLock::new(None);
+static MIN_RECORDED_TICKS: AtomicU64 = AtomicU64::new(0);
+/// The callback of the configuration poll timing was last enabled with
+static LONG_POLL_CALLBACK: RwLock<Option<LongPollCallback>> = RwLock::new(None);
+
+fn current_calibration() -> Option<calibration::Calibration> {
+    *CALIBRATION.read().unwrap_or_else(|e| e.into_inner())
+}
+
+thread_local! {
+    static LONG_POLL_EWMA: RefCell<Ewma> = RefCell::new(Ewma::new(LONG_POLL_EWMA_ALPHA));
 }
 
 /// Enables poll timing.
 ///
-/// Until this function is called, poll timing will not be measured.
+/// Until this function is called, poll timing will not be measured. With the `noop` feature,
+/// this does nothing.
 ///
-/// This function is fine if called multiple times.
-pub fn enable_poll_timing(log_file: Box<dyn Write + Send>) {
-    ENABLE_POLL_LOCK.call_once(|| {
-        start_performance_writer(log_file);
-        calibrate_clock_and_send_to_performance_writer();
-        enable_poll_timing_pthread_key();
-        enable_poll_timing_signal_handler(libc::SIGPROF);
-    });
+/// This function is fine if called multiple times. If it fails, poll timing stays disabled
+/// and it can be called again.
+///
+/// This calibrates the TSC against the monotonic clock, which blocks the caller for up to
+/// 200ms, or up to 2s under a hypervisor (see [`CalibrationConfig::hypervisor`]). Configure a
+/// shorter calibration with [`PollTimingConfig::with_calibration`], or reuse a saved one with
+/// [`PollTimingConfig::with_calibration_cache`], to block for less.
+///
+/// The child of a `fork` doesn't have the parent's performance writer thread, so poll timing
+/// is disabled in it. Call this again in the child, with a log file of its own, to time its
+/// polls too.
+pub fn enable_poll_timing(log_file: Box<dyn Write + Send>) -> Result<(), PollTimingError> {
+    enable_poll_timing_with_config(PollTimingConfig::default(), log_file)
+}
+
+/// Like [`enable_poll_timing`], with a configuration. While poll timing is enabled, further
+/// calls do nothing. Enabling it again after [`disable_poll_timing`] calibrates the TSC again
+/// and uses the new configuration, replacing the long poll callback, except for the drift check
+/// interval: the drift monitor started by the first call keeps running.
+pub fn enable_poll_timing_with_config(
+    config: PollTimingConfig,
+    log_file: Box<dyn Write + Send>,
+) -> Result<(), PollTimingError> {
+    if cfg!(any(feature = "noop", not(unix))) {
+        return Ok(());
+    }
+    let mut enabled = ENABLE_POLL_LOCK.lock().unwrap();
+    if enabled.is_some() {
+        return Ok(());
+    }
+    register_fork_handler();
+    writer::FLUSH_INTERVAL_MS.store(
+        config
+            .flush_interval
+            .as_millis()
+            .try_into()
+            .unwrap_or(u64::MAX),
+        atomic::Ordering::Relaxed,
+    );
+    start_performance_writer(log_file);
+    send_session_start_to_performance_writer();
+    send_process_info_to_performance_writer();
+    // before calibrating, which can fail
+    send_cpu_info_to_performance_writer();
+    let calibration_config = config
+        .calibration
+        .unwrap_or_else(default_calibration_config);
+    let calibration = calibrate_clock_and_send_to_performance_writer(
+        &calibration_config,
+        config.calibration_cache.as_ref(),
+    )?;
+    let min_recorded_ns = config.min_recorded_duration.as_nanos().try_into();
+    MIN_RECORDED_TICKS.store(
+        calibration.scale_ref_duration_to_src(min_recorded_ns.unwrap_or(u64::MAX)),
+        atomic::Ordering::Relaxed,
+    );
+    // a known frequency doesn't drift
+    if let (Some(interval), None) = (config.drift_check_interval, tsc::frequency()) {
+        spawn_drift_monitor(interval, calibration_config);
+    }
+    *LONG_POLL_CALLBACK
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = config.long_poll_callback;
+    send_wall_clock_anchor_to_performance_writer();
+    enable_poll_timing_pthread_key()?;
+    if config.sigaltstack {
+        if let Err(e) = enable_sigaltstack(DEFAULT_SIGALTSTACK_SIZE) {
+            tracing::warn!(message = "failed to install an alternate signal stack", error = %e);
+        }
+    }
+    let oldact = match enable_poll_timing_signal_handler(config.signal, config.sigaltstack) {
+        Ok(oldact) => oldact,
+        Err(e) => {
+            // stay disabled: polls in progress don't record themselves, as on disable
+            TIMESTAMP_PTHREAD_KEY_ASYNC_SIGNAL_SAFE.store(-1, atomic::Ordering::Release);
+            if config.sigaltstack {
+                disable_sigaltstack().ok();
+            }
+            return Err(e);
+        }
+    };
+    *enabled = Some((config.signal, oldact));
+    POLL_TIMING_ENABLED.store(true, atomic::Ordering::Release);
+    calibrate_overhead();
+    Ok(())
+}
+
+/// Disables poll timing, restoring the signal action that [`enable_poll_timing`] replaced.
+///
+/// The performance writer keeps running, so poll timing can be enabled again, writing to the
+/// same file. This function is fine if called when poll timing is not enabled.
+///
+/// The alternate signal stack installed on the calling thread, if any, is freed, as with
+/// [`disable_sigaltstack`]. Those of other threads are freed when they exit.
+pub fn disable_poll_timing() -> Result<(), PollTimingError> {
+    let mut enabled = ENABLE_POLL_LOCK.lock().unwrap();
+    let Some((signum, oldact)) = *enabled else {
+        return Ok(());
+    };
+    disable_poll_timing_signal_handler(signum, &oldact)?;
+    // polls in progress see the key as not initialized and don't record themselves
+    TIMESTAMP_PTHREAD_KEY_ASYNC_SIGNAL_SAFE.store(-1, std::sync::atomic::Ordering::Release);
+    *enabled = None;
+    POLL_TIMING_ENABLED.store(false, atomic::Ordering::Release);
+    if let Err(e) = disable_sigaltstack() {
+        tracing::warn!(message = "failed to uninstall the alternate signal stack", error = %e);
+    }
+    Ok(())
+}
+
+/// Returns whether poll timing is enabled, i.e. polls are being timed.
+///
+/// Another thread may enable or disable poll timing concurrently, so the result can be out
+/// of date by the time it's used.
+pub fn is_poll_timing_enabled() -> bool {
+    POLL_TIMING_ENABLED.load(atomic::Ordering::Acquire) && PERFORMANCE_WRITER.get().is_some()
 }
 
 /// async-signal safe. returns 0 if key is not initialized
+#[cfg(unix)]
 pub fn read_timestamp_pthread_key() -> usize {
     unsafe {
         let key =
             TIMESTAMP_PTHREAD_KEY_ASYNC_SIGNAL_SAFE.load(std::sync::atomic::Ordering::Acquire);
         if key >= 0 {
-            libc::pthread_getspecific(key as libc::pthread_key_t) as usize
+            get_timestamp(key as libc::pthread_key_t)
         } else {
             0
         }
@@ -156,27 +1363,292 @@ pub fn read_timestamp_pthread_key() -> usize {
 }
 
 /// Write the timestamp pthread key. no-op if key is not initialized.
+#[cfg(unix)]
 pub fn write_timestamp_pthread_key(time: usize) {
     unsafe {
         let key =
             TIMESTAMP_PTHREAD_KEY_ASYNC_SIGNAL_SAFE.load(std::sync::atomic::Ordering::Acquire);
         if key >= 0 {
-            libc::pthread_setspecific(key as libc::pthread_key_t, time as *const libc::c_void);
+            set_timestamp(key as libc::pthread_key_t, time);
         }
     }
 }
 
+#[cfg(all(unix, not(feature = "fast-tls")))]
+#[inline]
+unsafe fn get_timestamp(key: libc::pthread_key_t) -> usize {
+    libc::pthread_getspecific(key) as usize
+}
+
+#[cfg(all(unix, not(feature = "fast-tls")))]
+#[inline]
+unsafe fn set_timestamp(key: libc::pthread_key_t, time: usize) {
+    libc::pthread_setspecific(key, time as *const libc::c_void);
+}
+
+// With `fast-tls`, the timestamp is in a thread-local instead of the pthread key, which then
+// only marks poll timing as enabled. `#[thread_local]` is unstable, but a const-initialized
+// `thread_local!` without a destructor compiles down to the same plain TLS access. This is
+// only async-signal-safe if the TLS block is allocated when the thread starts, which is not
+// the case for libraries loaded with `dlopen` under the global-dynamic TLS model.
+#[cfg(all(unix, feature = "fast-tls"))]
+thread_local! {
+    static TIMESTAMP: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(all(unix, feature = "fast-tls"))]
+#[inline]
+unsafe fn get_timestamp(_key: libc::pthread_key_t) -> usize {
+    TIMESTAMP.get()
+}
+
+#[cfg(all(unix, feature = "fast-tls"))]
+#[inline]
+unsafe fn set_timestamp(_key: libc::pthread_key_t, time: usize) {
+    TIMESTAMP.set(time);
+}
+
+/// Times the polls of a future expression, shorthand for [`PollTimingFuture::new`], or for
+/// [`PollTimingFuture::with_label`] with a `label = ` first.
+///
+/// ```
+/// # async fn f() {
+/// let n = pollcatch::poll_timed!(async { 1 }).await;
+/// let m = pollcatch::poll_timed!(label = "fetch", async { 2 }).await;
+/// # }
+/// ```
+#[macro_export]
+macro_rules! poll_timed {
+    (label = $label:expr, $future:expr $(,)?) => {
+        $crate::PollTimingFuture::with_label($future, $label)
+    };
+    ($future:expr $(,)?) => {
+        $crate::PollTimingFuture::new($future)
+    };
+}
+
 impl<F> PollTimingFuture<F> {
     /// Wrap a future into a PollTimingFuture
     pub fn new(inner: F) -> Self {
-        PollTimingFuture { inner }
+        Self::new_with_threshold(inner, 0)
+    }
+
+    /// Like [`PollTimingFuture::new`], but only records polls that take at least
+    /// `min_duration_ns`, on top of [`PollTimingConfig::with_min_recorded_duration`]
+    pub fn new_with_threshold(inner: F, min_duration_ns: u64) -> Self {
+        #[cfg(feature = "noop")]
+        let _ = min_duration_ns;
+        PollTimingFuture {
+            inner,
+            #[cfg(not(feature = "noop"))]
+            min_duration_ns,
+            #[cfg(not(feature = "noop"))]
+            long_poll_span_ns: None,
+            #[cfg(not(feature = "noop"))]
+            poll_time_counter: None,
+            #[cfg(not(feature = "noop"))]
+            label: None,
+        }
+    }
+
+    /// Like [`PollTimingFuture::new`], but records the polls with `label`, like the blocks
+    /// of [`time_block`], so that the decoder shows which future was slow
+    pub fn with_label(inner: F, label: &'static str) -> Self {
+        #[cfg(feature = "noop")]
+        let _ = label;
+        PollTimingFuture {
+            #[cfg(not(feature = "noop"))]
+            label: Some(label),
+            ..Self::new(inner)
+        }
+    }
+
+    /// Like [`PollTimingFuture::new`], but also adds the duration of every poll, in
+    /// nanoseconds, to `counter`, whether or not poll timing is enabled. The counter can be
+    /// shared between futures and read at any time, e.g. to alert on the time spent polling
+    /// without decoding the PR file.
+    ///
+    /// The counter stays at 0 with the `noop` feature, which doesn't time polls, and on
+    /// non-unix targets other than `wasm32` with the `wasm` feature, which have no clock to
+    /// time them with.
+    ///
+    /// ```
+    /// use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+    ///
+    /// # async fn f() {
+    /// let counter = Arc::new(AtomicU64::new(0));
+    /// pollcatch::PollTimingFuture::new_with_counter(async {}, counter.clone()).await;
+    /// let poll_time_ns = counter.load(Ordering::Relaxed);
+    /// # }
+    /// ```
+    pub fn new_with_counter(inner: F, counter: Arc<AtomicU64>) -> Self {
+        #[cfg(feature = "noop")]
+        let _ = counter;
+        PollTimingFuture {
+            #[cfg(not(feature = "noop"))]
+            poll_time_counter: Some(counter),
+            ..Self::new(inner)
+        }
+    }
+
+    /// Emits a `long_poll_detected` span for the polls that take at least `threshold_ns`, see
+    /// [`PollTimingLayer::with_long_poll_span`]
+    fn with_long_poll_span(self, threshold_ns: Option<u64>) -> Self {
+        #[cfg(feature = "noop")]
+        let _ = threshold_ns;
+        PollTimingFuture {
+            #[cfg(not(feature = "noop"))]
+            long_poll_span_ns: threshold_ns,
+            ..self
+        }
+    }
+
+    /// Like [`PollTimingFuture::new`], for values that implement [`IntoFuture`] but not
+    /// [`Future`], such as request builders
+    pub fn from_into_future<I: IntoFuture<IntoFuture = F>>(inner: I) -> Self {
+        Self::new(inner.into_future())
+    }
+
+    /// Returns the wrapped future
+    pub fn inner(&self) -> &F {
+        &self.inner
     }
+
+    /// Returns the label the polls are recorded with, from [`PollTimingFuture::with_label`].
+    /// Always `None` with the `noop` feature.
+    pub fn label(&self) -> Option<&'static str> {
+        #[cfg(not(feature = "noop"))]
+        return self.label;
+        #[cfg(feature = "noop")]
+        None
+    }
+
+    /// Unwraps the wrapped future
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+/// Like [`tokio::task::spawn`], but wraps `future` in a [`PollTimingFuture`], so that
+/// replacing `tokio::task::spawn` with `pollcatch::spawn` instruments every task.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let answer = pollcatch::spawn(async { 42 }).await.unwrap();
+/// # assert_eq!(answer, 42);
+/// # }
+/// ```
+#[cfg(feature = "tokio")]
+#[track_caller]
+pub fn spawn<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::task::spawn(PollTimingFuture::new(future))
+}
+
+/// Like [`tokio::task::spawn_local`], but wraps `future` in a [`PollTimingFuture`]. Must be
+/// called from within a [`tokio::task::LocalSet`].
+#[cfg(feature = "tokio")]
+#[track_caller]
+pub fn spawn_local<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    tokio::task::spawn_local(PollTimingFuture::new(future))
+}
+
+/// Like [`tokio::task::spawn_blocking`], but times `f` like a [`PollTimingGuard`] scope, so a
+/// sample that lands in it is recorded as a poll. Blocking tasks are usually long on purpose,
+/// so only use this for ones that shouldn't be.
+#[cfg(feature = "tokio")]
+#[track_caller]
+pub fn spawn_blocking<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let _guard = PollTimingGuard::start();
+        f()
+    })
 }
 
+#[cfg(all(
+    unix,
+    not(all(feature = "vdso", target_os = "linux", target_pointer_width = "64"))
+))]
 fn nanotime() -> u64 {
+    clock_ns(libc::CLOCK_MONOTONIC)
+}
+
+#[cfg(all(feature = "vdso", target_os = "linux", target_pointer_width = "64"))]
+fn nanotime() -> u64 {
+    nanotime_vdso()
+}
+
+/// `nanotime`, calling the vDSO directly rather than going through libc, which might not
+#[cfg(all(feature = "vdso", target_os = "linux", target_pointer_width = "64"))]
+fn nanotime_vdso() -> u64 {
+    match vdso::clock_gettime(libc::CLOCK_MONOTONIC) {
+        Some(ts) => (ts.tv_sec as u64)
+            .wrapping_mul(1_000_000_000)
+            .wrapping_add(ts.tv_nsec as u64),
+        None => clock_ns(libc::CLOCK_MONOTONIC),
+    }
+}
+
+#[cfg(unix)]
+fn realtime_ns() -> u64 {
+    clock_ns(libc::CLOCK_REALTIME)
+}
+
+// Cached per thread, since the syscall is a good part of the cost of recording a poll.
+// No thread has a tid of 0.
+#[cfg(unix)]
+thread_local! {
+    static TID: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+}
+
+// Whether the name of the thread was sent to the performance writer
+thread_local! {
+    static THREAD_NAME_SENT: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Sends the name of the current thread the first time one of its polls is recorded, so
+/// that the decoder can show it even after the process is gone
+fn send_thread_name(ch: &writer::EventSender, tid: u32) {
+    if THREAD_NAME_SENT.replace(true) {
+        return;
+    }
+    if let Some(name) = std::thread::current().name() {
+        ch.send(writer::Event::ThreadName {
+            tid,
+            name: to_fixed_cstr(name.as_bytes()),
+        })
+        .ok();
+    }
+}
+
+#[cfg(unix)]
+fn gettid() -> u32 {
+    TID.with(|tid| {
+        if tid.get() == 0 {
+            tid.set(unsafe { libc::syscall(libc::SYS_gettid) as u32 });
+        }
+        tid.get()
+    })
+}
+
+#[cfg(unix)]
+#[inline]
+fn clock_ns(clock: libc::clockid_t) -> u64 {
     unsafe {
-        let mut ts = MaybeUninit::uninit();
-        if libc::clock_gettime(libc::CLOCK_MONOTONIC, ts.as_mut_ptr()) != 0 {
+        let mut ts = std::mem::MaybeUninit::uninit();
+        if libc::clock_gettime(clock, ts.as_mut_ptr()) != 0 {
             0
         } else {
             let ts = ts.assume_init();
@@ -187,34 +1659,327 @@ fn nanotime() -> u64 {
     }
 }
 
+/// What `timestamping` times
+#[derive(Copy, Clone)]
+enum Timed {
+    Poll,
+    /// A poll that returned `Poll::Ready`
+    #[cfg_attr(feature = "noop", allow(dead_code))]
+    PollReady,
+    ServicePollReady,
+    /// A block of [`time_block`]
+    LabeledBlock(&'static str),
+}
+
 #[cold]
 #[inline(never)]
-fn write_timestamp(before: u64) {
+fn write_timestamp(
+    timed: Timed,
+    before: u64,
+    start_cpu: Option<u32>,
+    key_value: usize,
+    min_duration_ns: u64,
+) {
     if let Some(ch) = PERFORMANCE_WRITER.get() {
-        let tid = unsafe { libc::syscall(libc::SYS_gettid) as u32 };
-
         let clock_end = nanotime();
         let end = tsc::now();
-        ch.send(writer::Event::Poll {
-            start: before,
-            end,
-            clock_end,
-            tid,
+        if cpu_migrated(start_cpu, key_value) {
+            return;
+        }
+        // the ticks spent timing the poll are not the poll's
+        let start = before
+            .saturating_add(POLL_OVERHEAD_TICKS.load(atomic::Ordering::Relaxed))
+            .min(end);
+        let ticks = end - start;
+        if ticks < MIN_RECORDED_TICKS.load(atomic::Ordering::Relaxed) {
+            return;
+        }
+        let calibration = current_calibration();
+        if let Some(calibration) = calibration {
+            if calibration.scale_src_duration_to_ref(ticks) < min_duration_ns {
+                return;
+            }
+        }
+        let tid = gettid();
+        send_thread_name(ch, tid);
+        let event = match timed {
+            Timed::Poll => writer::Event::Poll {
+                start,
+                end,
+                clock_end,
+                tid,
+            },
+            Timed::PollReady => writer::Event::PollReady {
+                start,
+                end,
+                clock_end,
+                tid,
+            },
+            Timed::ServicePollReady => writer::Event::ServicePollReady {
+                start,
+                end,
+                clock_end,
+                tid,
+            },
+            Timed::LabeledBlock(label) => writer::Event::LabeledBlock {
+                start,
+                end,
+                clock_end,
+                tid,
+                label,
+            },
+        };
+        ch.send(event).ok();
+        RECORDED_POLLS.fetch_add(1, atomic::Ordering::Relaxed);
+        signal_ring::with_current(|ring| {
+            ring.drain(|tsc| {
+                ch.send(writer::Event::Signal { tsc, tid }).ok();
+            })
+        });
+        let callback = LONG_POLL_CALLBACK
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let (Some(callback), Some(calibration)) = (callback, calibration) {
+            let duration = calibration.scale_src_duration_to_ref(ticks);
+            let smoothed = LONG_POLL_EWMA.with_borrow_mut(|ewma| ewma.update(duration as f64));
+            callback(&LongPoll {
+                duration: Duration::from_nanos(duration),
+                smoothed_duration: Duration::from_nanos(smoothed as u64),
+                tid,
+            });
+        }
+    }
+}
+
+/// Times a scope of synchronous code the way [`PollTimingFuture`] times polls: if the
+/// profiler samples the thread before the guard is dropped, the scope is recorded as a poll.
+///
+/// ```
+/// let _guard = pollcatch::PollTimingGuard::start();
+/// // e.g. a long deserialization inside an async block
+/// ```
+///
+/// The guard has to be dropped on the thread that started it.
+///
+/// Scopes can be nested, e.g. a [`PollTimingFuture`] wrapping another one: a sample is only
+/// recorded for the innermost scope it lands in, so the same slow code isn't counted twice.
+#[must_use = "the scope is timed until the guard is dropped"]
+pub struct PollTimingGuard {
+    /// The TSC at the start, or `None` if poll timing was disabled then
+    before: Option<u64>,
+    start_cpu: Option<u32>,
+    /// The pthread key's value in the enclosing scope, if nested, to give it back a sample
+    /// that landed before this scope started
+    outer_key_value: usize,
+    /// The pthread key is per thread
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+thread_local! {
+    /// How many timed scopes are active on this thread
+    static POLL_TIMING_DEPTH: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+}
+
+impl PollTimingGuard {
+    #[inline]
+    pub fn start() -> Self {
+        if cfg!(feature = "noop") || !is_poll_timing_enabled() {
+            return PollTimingGuard {
+                before: None,
+                start_cpu: None,
+                outer_key_value: 0,
+                _not_send: std::marker::PhantomData,
+            };
+        }
+        let depth = POLL_TIMING_DEPTH.replace(POLL_TIMING_DEPTH.get() + 1);
+        let outer_key_value = if depth > 0 {
+            read_timestamp_pthread_key()
+        } else {
+            // drop the signals received outside of polls
+            signal_ring::with_current(|ring| ring.drain(|_| {}));
+            0
+        };
+        // serialized so that the scope can't start before it. The end is read with plain
+        // `now`, which is cheaper and only runs after the branch on the key anyway.
+        let start_cpu = current_cpu();
+        let before = tsc::now_serialized();
+        write_timestamp_pthread_key(0);
+        PollTimingGuard {
+            before: Some(before),
+            start_cpu,
+            outer_key_value,
+            _not_send: std::marker::PhantomData,
+        }
+    }
+
+    /// Records the scope as `timed()` if it was sampled. Dropping the guard afterwards does
+    /// nothing.
+    #[inline]
+    fn finish(&mut self, timed: impl FnOnce() -> Timed, min_duration_ns: u64) {
+        let Some(before) = self.before.take() else {
+            return;
+        };
+        let depth = POLL_TIMING_DEPTH.get() - 1;
+        POLL_TIMING_DEPTH.set(depth);
+        let key_value = read_timestamp_pthread_key();
+        let sampled = key_value & 1 == 1;
+        if sampled {
+            write_timestamp(timed(), before, self.start_cpu, key_value, min_duration_ns);
+        }
+        if depth > 0 {
+            // a sample recorded here is not the enclosing scope's anymore
+            write_timestamp_pthread_key(if sampled { 0 } else { self.outer_key_value });
+        }
+    }
+}
+
+impl Drop for PollTimingGuard {
+    #[inline]
+    fn drop(&mut self) {
+        self.finish(|| Timed::Poll, 0);
+    }
+}
+
+/// Runs `f`, timing it like a [`PollTimingGuard`] scope, and records it with `label` so that
+/// the decoder shows which block was slow.
+///
+/// ```
+/// let value: u64 = pollcatch::time_block("parse config", || "42".parse().unwrap());
+/// ```
+#[inline]
+pub fn time_block<R>(label: &'static str, f: impl FnOnce() -> R) -> R {
+    let mut guard = PollTimingGuard::start();
+    let res = f();
+    guard.finish(|| Timed::LabeledBlock(label), 0);
+    res
+}
+
+/// Records an event of the executor on this thread, such as its worker thread parking, so that
+/// the decoder can show whether a poll started late because of the executor rather than the
+/// future. Meant to be called from the executor's hooks:
+///
+/// ```
+/// use pollcatch::{record_executor_event, ExecutorEventKind};
+///
+/// let runtime = tokio::runtime::Builder::new_multi_thread()
+///     .on_thread_park(|| record_executor_event(ExecutorEventKind::Park))
+///     .on_thread_unpark(|| record_executor_event(ExecutorEventKind::Unpark))
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// Unlike polls, executor events are recorded whether or not a profiling signal arrived, so
+/// this does a channel send each time. It does nothing while poll timing is disabled.
+pub fn record_executor_event(kind: ExecutorEventKind) {
+    if cfg!(feature = "noop") || !is_poll_timing_enabled() {
+        return;
+    }
+    if let Some(ch) = PERFORMANCE_WRITER.get() {
+        ch.send(writer::Event::ExecutorEvent {
+            kind,
+            tid: gettid(),
+            tsc: tsc::now(),
         })
         .ok();
     }
 }
 
-fn timestamping<R, F: FnOnce() -> R>(f: F) -> R {
-    let before = tsc::now();
-    write_timestamp_pthread_key(0);
-    let res = f();
-    if read_timestamp_pthread_key() == 1 {
-        write_timestamp(before);
+/// Marks the PR timeline with `message`, such as "deploy started" or "cache cleared", so that
+/// the decoder prints it between the long polls around it. `tag` is for the application to
+/// tell kinds of annotations apart. Messages are truncated to 63 bytes.
+///
+/// ```
+/// pollcatch::annotate(1, "connection pool exhausted");
+/// ```
+///
+/// This does nothing while poll timing is disabled.
+pub fn annotate(tag: u32, message: &str) {
+    if cfg!(feature = "noop") || !is_poll_timing_enabled() {
+        return;
     }
+    if let Some(ch) = PERFORMANCE_WRITER.get() {
+        ch.send(writer::Event::UserAnnotation {
+            timestamp_tsc: tsc::now(),
+            tag,
+            message: to_fixed_cstr(message.as_bytes()),
+        })
+        .ok();
+    }
+}
+
+/// Records how long `f` takes if it's sampled, as told by `timed` from its result
+#[cfg(not(feature = "noop"))]
+#[inline]
+fn timestamping<R, F: FnOnce() -> R>(
+    timed: impl FnOnce(&R) -> Timed,
+    min_duration_ns: u64,
+    f: F,
+) -> R {
+    let mut guard = PollTimingGuard::start();
+    let res = f();
+    guard.finish(|| timed(&res), min_duration_ns);
     res
 }
 
+#[cfg(feature = "noop")]
+#[inline(always)]
+fn timestamping<R, F: FnOnce() -> R>(
+    _timed: impl FnOnce(&R) -> Timed,
+    _min_duration_ns: u64,
+    f: F,
+) -> R {
+    f()
+}
+
+#[cfg(feature = "futures-core")]
+impl<F: futures_core::future::FusedFuture> futures_core::future::FusedFuture
+    for PollTimingFuture<F>
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_terminated()
+    }
+}
+
+/// Emits a `long_poll_detected` span, for [`PollTimingLayer::with_long_poll_span`]
+#[cfg(not(feature = "noop"))]
+#[cold]
+#[inline(never)]
+fn report_long_poll_span(start_tsc: u64, duration_ns: u64) {
+    let tid = gettid();
+    // created and closed right away, the subscribers get it when it closes
+    drop(tracing::span!(
+        tracing::Level::WARN,
+        "long_poll_detected",
+        duration_ns,
+        tid,
+        start_tsc
+    ));
+}
+
+#[cfg(not(feature = "noop"))]
+fn timed_poll<T>(label: Option<&'static str>, res: &std::task::Poll<T>) -> Timed {
+    match label {
+        Some(label) => Timed::LabeledBlock(label),
+        None if res.is_ready() => Timed::PollReady,
+        None => Timed::Poll,
+    }
+}
+
+#[cfg(feature = "noop")]
+impl<F: Future> Future for PollTimingFuture<F> {
+    type Output = F::Output;
+
+    #[inline(always)]
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        self.project().inner.poll(cx)
+    }
+}
+
+#[cfg(not(feature = "noop"))]
 impl<F: Future> Future for PollTimingFuture<F> {
     type Output = F::Output;
 
@@ -223,24 +1988,97 @@ impl<F: Future> Future for PollTimingFuture<F> {
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
         let this = self.project();
-        timestamping(|| this.inner.poll(cx))
+        let label = *this.label;
+        let timed = |res: &_| timed_poll(label, res);
+        if this.long_poll_span_ns.is_none() && this.poll_time_counter.is_none() {
+            return timestamping(timed, *this.min_duration_ns, || this.inner.poll(cx));
+        }
+        // every poll is measured here, not just the sampled ones
+        let start_tsc = tsc::now();
+        let start_ns = nanotime();
+        let res = timestamping(timed, *this.min_duration_ns, || this.inner.poll(cx));
+        let duration_ns = nanotime().saturating_sub(start_ns);
+        if let Some(counter) = this.poll_time_counter {
+            counter.fetch_add(duration_ns, atomic::Ordering::Relaxed);
+        }
+        if this
+            .long_poll_span_ns
+            .is_some_and(|span_ns| duration_ns >= span_ns)
+        {
+            report_long_poll_span(start_tsc, duration_ns);
+        }
+        res
     }
 }
 
 /// A tower layer that adds long poll detection
-pub struct PollTimingLayer;
+#[derive(Clone, Debug)]
+pub struct PollTimingLayer {
+    time_poll_ready: bool,
+    min_duration_ns: u64,
+    long_poll_span_ns: Option<u64>,
+}
+
+impl Default for PollTimingLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PollTimingLayer {
+    /// The layer with the default settings. Before 0.2, `PollTimingLayer` was a unit struct,
+    /// and this replaces writing it as a value, also in `const` and `static` items.
+    pub const fn new() -> Self {
+        PollTimingLayer {
+            time_poll_ready: true,
+            min_duration_ns: 0,
+            long_poll_span_ns: None,
+        }
+    }
+
+    /// Whether to also time `poll_ready` of the wrapped services, which is recorded
+    /// separately from their futures. On by default.
+    pub fn with_poll_ready_timing(mut self, enabled: bool) -> Self {
+        self.time_poll_ready = enabled;
+        self
+    }
+
+    /// Only record polls of the wrapped services that take at least `min_duration_ns`, on
+    /// top of [`PollTimingConfig::with_min_recorded_duration`]
+    pub fn with_threshold(mut self, min_duration_ns: u64) -> Self {
+        self.min_duration_ns = min_duration_ns;
+        self
+    }
+
+    /// Emits a `long_poll_detected` tracing span, with the `duration_ns`, `tid` and
+    /// `start_tsc` of the poll, for each poll of the services' futures that takes at least
+    /// `threshold_ns`. Unlike the PR file, this measures every poll, even with poll timing
+    /// disabled.
+    pub fn with_long_poll_span(mut self, threshold_ns: u64) -> Self {
+        self.long_poll_span_ns = Some(threshold_ns);
+        self
+    }
+}
 
 impl<S> tower_layer::Layer<S> for PollTimingLayer {
     type Service = PollTimingService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        PollTimingService { inner }
+        PollTimingService {
+            inner,
+            time_poll_ready: self.time_poll_ready,
+            min_duration_ns: self.min_duration_ns,
+            long_poll_span_ns: self.long_poll_span_ns,
+        }
     }
 }
 
 /// A tower service that adds long poll detection
 pub struct PollTimingService<S> {
     inner: S,
+    time_poll_ready: bool,
+    min_duration_ns: u64,
+    long_poll_span_ns: Option<u64>,
 }
 
 impl<S, Request> tower_service::Service<Request> for PollTimingService<S>
@@ -255,11 +2093,20 @@ where
         &mut self,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        // not timestamping here - leave that to the top-level future
-        self.inner.poll_ready(cx)
+        if self.time_poll_ready {
+            // e.g. waiting for a semaphore can block too
+            timestamping(
+                |_| Timed::ServicePollReady,
+                self.min_duration_ns,
+                || self.inner.poll_ready(cx),
+            )
+        } else {
+            self.inner.poll_ready(cx)
+        }
     }
 
     fn call(&mut self, req: Request) -> Self::Future {
-        PollTimingFuture::new(self.inner.call(req))
+        PollTimingFuture::new_with_threshold(self.inner.call(req), self.min_duration_ns)
+            .with_long_poll_span(self.long_poll_span_ns)
     }
 }
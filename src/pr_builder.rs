@@ -0,0 +1,100 @@
+use std::io::{self, Write};
+
+/// Serializes a single PR record.
+///
+/// A record is a `u32` size (including the 8 header bytes), a `u32` kind, and a body made of
+/// `tag: u8, len: u16, value: [u8; len]` fields. Readers skip fields with tags they don't know,
+/// so fields can be added to an event without changing its kind.
+///
+/// The first field, with tag 0, is the sequence number of the record, which increases by one
+/// with each record a writer writes. This lets the decoder detect lost records.
+pub struct RecordBuilder {
+    buf: Vec<u8>,
+}
+
+impl RecordBuilder {
+    pub fn new(kind: u32, seq: u64) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&[0; 4]); // size, filled in by `finish`
+        buf.extend_from_slice(&kind.to_le_bytes());
+        RecordBuilder { buf }.u64(0, seq)
+    }
+
+    /// Adds a field, truncated to the `u16::MAX` bytes a field can hold
+    pub fn bytes(mut self, tag: u8, value: &[u8]) -> Self {
+        let value = &value[..value.len().min(u16::MAX as usize)];
+        let len = value.len() as u16;
+        self.buf.push(tag);
+        self.buf.extend_from_slice(&len.to_le_bytes());
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn u32(self, tag: u8, value: u32) -> Self {
+        self.bytes(tag, &value.to_le_bytes())
+    }
+
+    pub fn u64(self, tag: u8, value: u64) -> Self {
+        self.bytes(tag, &value.to_le_bytes())
+    }
+
+    pub fn u128(self, tag: u8, value: u128) -> Self {
+        self.bytes(tag, &value.to_le_bytes())
+    }
+
+    /// Adds a string field, truncated at a char boundary to fit
+    pub fn str(self, tag: u8, value: &str) -> Self {
+        self.bytes(tag, truncate_str(value, u16::MAX as usize).as_bytes())
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+
+    pub fn write_to(self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&self.finish())
+    }
+}
+
+/// The longest prefix of `s` that is at most `max_len` bytes and ends at a char boundary
+pub(crate) fn truncate_str(s: &str, max_len: usize) -> &str {
+    let mut len = s.len().min(max_len);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    &s[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecordBuilder;
+
+    #[test]
+    fn basic() {
+        let record = RecordBuilder::new(7, 1).u32(1, 2).bytes(3, b"ab").finish();
+        assert_eq!(
+            record,
+            [
+                31, 0, 0, 0, // size
+                7, 0, 0, 0, // kind
+                0, 8, 0, 1, 0, 0, 0, 0, 0, 0, 0, // sequence number
+                1, 4, 0, 2, 0, 0, 0, // tag 1
+                3, 2, 0, b'a', b'b', // tag 3
+            ]
+        );
+    }
+
+    #[test]
+    fn long_string_truncated() {
+        // 'é' is 2 bytes, so `u16::MAX` falls in the middle of one
+        let value = "é".repeat(40_000);
+        let record = RecordBuilder::new(0, 0).str(1, &value).finish();
+        let field = &record[19..];
+        let len = u16::from_le_bytes([field[1], field[2]]) as usize;
+        assert_eq!(len, u16::MAX as usize - 1);
+        assert_eq!(field.len(), 3 + len);
+        assert!(std::str::from_utf8(&field[3..]).is_ok());
+    }
+}
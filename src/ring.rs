@@ -0,0 +1,119 @@
+use memmap2::MmapMut;
+use std::{fs::File, io};
+
+/// Written at the start of ring-buffer PR files, instead of the usual header
+const RING_MAGIC: [u8; 8] = *b"PCPRRING";
+/// The magic, then the write head and the offset of the oldest record as `u64`s. The head
+/// isn't in the first 8 bytes of the file: those hold the magic, which tells ring files
+/// apart from the others, and the offset of the oldest record is needed too, since it's
+/// rarely right after the head once records of different sizes overwrite each other.
+const HEADER_LEN: usize = 24;
+
+/// A fixed-size memory-mapped PR file that overwrites its oldest records when full.
+///
+/// Offsets are relative to the end of the header. Records never wrap around: a record that
+/// doesn't fit at the end goes at the start instead, and the unused end is marked with a
+/// zero size if there's room for one. Readers go from the oldest record to the write head,
+/// jumping to the start at the end marker.
+pub(crate) struct Ring {
+    mmap: MmapMut,
+    head: usize,
+    oldest: usize,
+}
+
+impl Ring {
+    pub fn new(f: &File, capacity_bytes: usize) -> io::Result<Self> {
+        if capacity_bytes < HEADER_LEN + 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ring buffer capacity too small",
+            ));
+        }
+        f.set_len(capacity_bytes as u64)?;
+        // safety: the file is ours for as long as the writer runs
+        let mut mmap = unsafe { MmapMut::map_mut(f)? };
+        mmap.fill(0);
+        mmap[..RING_MAGIC.len()].copy_from_slice(&RING_MAGIC);
+        Ok(Ring {
+            mmap,
+            head: 0,
+            oldest: 0,
+        })
+    }
+
+    /// The size of the record at `pos`, 0 at the end marker
+    fn size_at(&self, pos: usize) -> usize {
+        match self.mmap[HEADER_LEN..].get(pos..pos + 4) {
+            Some(size) => u32::from_le_bytes(size.try_into().unwrap()) as usize,
+            None => 0,
+        }
+    }
+
+    pub fn push(&mut self, record: &[u8]) {
+        let capacity = self.mmap.len() - HEADER_LEN;
+        let len = record.len();
+        if len > capacity {
+            tracing::warn!(message = "record larger than the ring buffer", len);
+            return;
+        }
+        if self.head + len > capacity {
+            if let Some(end) = self.mmap[HEADER_LEN..].get_mut(self.head..self.head + 4) {
+                end.fill(0);
+            }
+            // the records after the head are gone, the oldest is now at the start
+            self.oldest = 0;
+            self.head = 0;
+        }
+        // drop the records the new one overwrites
+        while self.oldest >= self.head && self.oldest < self.head + len {
+            match self.size_at(self.oldest) {
+                // nothing older left after the head, continue from the start
+                0 => {
+                    self.oldest = 0;
+                    break;
+                }
+                size => self.oldest += size,
+            }
+        }
+        self.mmap[HEADER_LEN + self.head..][..len].copy_from_slice(record);
+        self.head += len;
+        self.mmap[8..16].copy_from_slice(&(self.head as u64).to_le_bytes());
+        self.mmap[16..24].copy_from_slice(&(self.oldest as u64).to_le_bytes());
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.mmap.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ring;
+
+    fn record(size: u8) -> Vec<u8> {
+        let mut record = vec![size; size as usize];
+        record[..4].copy_from_slice(&(size as u32).to_le_bytes());
+        record
+    }
+
+    #[test]
+    fn wrap_around() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join(format!("pollcatch-ring-{}.pr", std::process::id()));
+        let f = std::fs::File::create_new(&path)?;
+        // 40 bytes of records
+        let mut ring = Ring::new(&f, 64)?;
+        std::fs::remove_file(&path)?;
+        ring.push(&record(16));
+        ring.push(&record(16));
+        assert_eq!((ring.head, ring.oldest), (32, 0));
+        // doesn't fit at the end, overwrites the first record
+        ring.push(&record(12));
+        assert_eq!((ring.head, ring.oldest), (12, 16));
+        ring.push(&record(8));
+        assert_eq!((ring.head, ring.oldest), (20, 32));
+        // overwrites the second record, and reaches the end marker
+        ring.push(&record(16));
+        assert_eq!((ring.head, ring.oldest), (36, 0));
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Capacity of a `SignalRing`, a power of two
+const SIGNAL_RING_LEN: usize = 16;
+
+/// The timestamps of the signals a thread received, appended by the signal handler and drained
+/// by the same thread once its poll is done.
+///
+/// The signal handler can interrupt the drain but not the other way around, so a single
+/// producer and a single consumer are enough. Timestamps that don't fit are dropped.
+pub(crate) struct SignalRing {
+    buf: [AtomicU64; SIGNAL_RING_LEN],
+    /// Total number of timestamps pushed, only written by the producer
+    head: AtomicUsize,
+    /// Total number of timestamps drained, only written by the consumer
+    tail: AtomicUsize,
+}
+
+impl SignalRing {
+    pub const fn new() -> Self {
+        SignalRing {
+            buf: [const { AtomicU64::new(0) }; SIGNAL_RING_LEN],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// async-signal safe
+    pub fn push(&self, timestamp: u64) {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= SIGNAL_RING_LEN {
+            return;
+        }
+        self.buf[head % SIGNAL_RING_LEN].store(timestamp, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Calls `f` with each timestamp, oldest first, and removes them
+    pub fn drain(&self, mut f: impl FnMut(u64)) {
+        let head = self.head.load(Ordering::Acquire);
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        while tail != head {
+            f(self.buf[tail % SIGNAL_RING_LEN].load(Ordering::Relaxed));
+            tail = tail.wrapping_add(1);
+        }
+        self.tail.store(tail, Ordering::Release);
+    }
+}
+
+// The ring of the current thread. With `fast-tls`, it's in a thread-local, which has the same
+// caveat as the timestamp: it's only async-signal-safe if the TLS block is allocated when the
+// thread starts.
+#[cfg(all(unix, feature = "fast-tls"))]
+thread_local! {
+    static SIGNAL_RING: SignalRing = const { SignalRing::new() };
+}
+
+/// Appends `timestamp` to the current thread's ring, if it has one. async-signal safe.
+#[cfg(all(unix, feature = "fast-tls"))]
+pub(crate) fn push_current(timestamp: u64) {
+    SIGNAL_RING.with(|ring| ring.push(timestamp));
+}
+
+/// Calls `f` with the current thread's ring
+#[cfg(all(unix, feature = "fast-tls"))]
+pub(crate) fn with_current(f: impl FnOnce(&SignalRing)) {
+    SIGNAL_RING.with(f);
+}
+
+/// Without `fast-tls`, the ring is allocated on the thread's first poll and kept in a pthread
+/// key, which the signal handler can read. The key's destructor frees it when the thread exits,
+/// after the key is cleared, so a signal then doesn't see it.
+#[cfg(all(unix, not(feature = "fast-tls")))]
+static SIGNAL_RING_PTHREAD_KEY: std::sync::LazyLock<Result<libc::pthread_key_t, libc::c_int>> =
+    std::sync::LazyLock::new(|| unsafe {
+        let mut key = 0;
+        match libc::pthread_key_create(&mut key, Some(drop_ring)) {
+            0 => Ok(key),
+            err => Err(err),
+        }
+    });
+
+/// `SIGNAL_RING_PTHREAD_KEY` once created, or -1, for the signal handler
+#[cfg(all(unix, not(feature = "fast-tls")))]
+static SIGNAL_RING_PTHREAD_KEY_ASYNC_SIGNAL_SAFE: std::sync::atomic::AtomicIsize =
+    std::sync::atomic::AtomicIsize::new(-1);
+
+/// The number of rings allocated and not yet freed, to check that exiting threads free theirs
+#[cfg(all(test, unix, not(feature = "fast-tls")))]
+static LIVE_RINGS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(all(unix, not(feature = "fast-tls")))]
+extern "C" fn drop_ring(ring: *mut libc::c_void) {
+    // safety: the key only holds rings from `Box::into_raw`, and is null by now, so neither
+    // the signal handler nor another destructor call sees this one
+    drop(unsafe { Box::from_raw(ring.cast::<SignalRing>()) });
+    #[cfg(test)]
+    LIVE_RINGS.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Creates the pthread key of the rings, if it wasn't already
+#[cfg(all(unix, not(feature = "fast-tls")))]
+pub(crate) fn init_pthread_key() -> Result<(), libc::c_int> {
+    let key = (*SIGNAL_RING_PTHREAD_KEY)? as isize;
+    SIGNAL_RING_PTHREAD_KEY_ASYNC_SIGNAL_SAFE.store(key, Ordering::Release);
+    Ok(())
+}
+
+/// The current thread's ring, or null if it has none. async-signal safe.
+#[cfg(all(unix, not(feature = "fast-tls")))]
+fn current() -> *const SignalRing {
+    let key = SIGNAL_RING_PTHREAD_KEY_ASYNC_SIGNAL_SAFE.load(Ordering::Acquire);
+    if key < 0 {
+        return std::ptr::null();
+    }
+    // safety: the key was created
+    unsafe { libc::pthread_getspecific(key as libc::pthread_key_t).cast() }
+}
+
+/// Appends `timestamp` to the current thread's ring, if it has one. async-signal safe.
+#[cfg(all(unix, not(feature = "fast-tls")))]
+pub(crate) fn push_current(timestamp: u64) {
+    // safety: the ring is only freed when the thread exits, after the key is cleared
+    if let Some(ring) = unsafe { current().as_ref() } {
+        ring.push(timestamp);
+    }
+}
+
+/// Calls `f` with the current thread's ring, allocating it on the first call. Does nothing if
+/// the pthread key wasn't created, or the ring can't be stored in it.
+#[cfg(all(unix, not(feature = "fast-tls")))]
+pub(crate) fn with_current(f: impl FnOnce(&SignalRing)) {
+    let mut ring = current();
+    if ring.is_null() {
+        let key = SIGNAL_RING_PTHREAD_KEY_ASYNC_SIGNAL_SAFE.load(Ordering::Acquire);
+        if key < 0 {
+            return;
+        }
+        let new = Box::into_raw(Box::new(SignalRing::new()));
+        // safety: the key was created, and the ring is freed by its destructor
+        if unsafe { libc::pthread_setspecific(key as libc::pthread_key_t, new.cast()) } != 0 {
+            // safety: not stored anywhere
+            drop(unsafe { Box::from_raw(new) });
+            return;
+        }
+        #[cfg(test)]
+        LIVE_RINGS.fetch_add(1, Ordering::Relaxed);
+        ring = new;
+    }
+    // safety: the ring is only freed when the thread exits
+    f(unsafe { &*ring });
+}
+
+/// No-op, since there are no signals
+#[cfg(not(unix))]
+pub(crate) fn with_current(_f: impl FnOnce(&SignalRing)) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{SignalRing, SIGNAL_RING_LEN};
+
+    #[test]
+    fn push_drain() {
+        let ring = SignalRing::new();
+        for i in 0..SIGNAL_RING_LEN as u64 + 3 {
+            ring.push(i);
+        }
+        let mut drained = vec![];
+        ring.drain(|t| drained.push(t));
+        // the last 3 didn't fit
+        assert_eq!(drained, (0..SIGNAL_RING_LEN as u64).collect::<Vec<_>>());
+        ring.push(100);
+        ring.drain(|t| drained.push(t));
+        assert_eq!(drained.last(), Some(&100));
+        ring.drain(|_| panic!("drained twice"));
+    }
+
+    // one test, since the rings of other tests' threads would throw off the count
+    #[cfg(all(unix, not(feature = "fast-tls")))]
+    #[test]
+    fn pthread_key_rings() {
+        use super::{init_pthread_key, push_current, with_current, LIVE_RINGS};
+        use std::sync::atomic::Ordering;
+
+        std::thread::spawn(|| {
+            init_pthread_key().unwrap();
+            // the ring is only allocated on the first poll
+            push_current(1);
+            with_current(|ring| ring.drain(|_| panic!("pushed before there was a ring")));
+            push_current(2);
+            push_current(3);
+            let mut drained = vec![];
+            with_current(|ring| ring.drain(|t| drained.push(t)));
+            assert_eq!(drained, [2, 3]);
+        })
+        .join()
+        .unwrap();
+
+        // each thread frees its ring when it exits
+        let threads: Vec<_> = (0..100)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    with_current(|_| {});
+                    push_current(i);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(LIVE_RINGS.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,5 +1,7 @@
 // copied from quanta crate
 
+use std::collections::VecDeque;
+
 /// Estimates the arithmetic mean (and the error) for a set of samples.
 ///
 /// This type is written and maintained internally as it is trivial to implement and doesn't warrant
@@ -10,16 +12,31 @@
 /// time and space complexity.
 ///
 /// [welfords]: https://en.wikipedia.org/wiki/Algorithms_for_calculating_variance#Welford%27s_online_algorithm
-#[derive(Default)]
 pub(crate) struct Variance {
     mean: f64,
     mean2: f64,
     n: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for Variance {
+    fn default() -> Self {
+        Variance {
+            mean: 0.0,
+            mean2: 0.0,
+            n: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
 }
 
 impl Variance {
     #[inline]
     pub fn add(&mut self, sample: f64) {
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
         self.n += 1;
         let n_f = self.n as f64;
         let delta_sq = (sample - self.mean).powi(2);
@@ -32,15 +49,19 @@ impl Variance {
         self.mean
     }
 
+    /// The sample standard deviation
     #[inline]
-    pub fn mean_error(&self) -> f64 {
+    pub fn std_dev(&self) -> f64 {
         if self.n < 2 {
             return 0.0;
         }
 
-        let n_f = self.n as f64;
-        let sd = (self.mean2 / (n_f - 1.0)).sqrt();
-        sd / n_f.sqrt()
+        (self.mean2 / (self.n as f64 - 1.0)).sqrt()
+    }
+
+    #[inline]
+    pub fn mean_error(&self) -> f64 {
+        self.std_dev() / (self.n as f64).sqrt()
     }
 
     #[inline]
@@ -55,14 +76,221 @@ impl Variance {
     }
 
     #[inline]
-    pub fn samples(&self) -> u64 {
+    pub fn count(&self) -> u64 {
         self.n
     }
+
+    /// The smallest sample, infinity if there are none
+    #[inline]
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    /// The largest sample, negative infinity if there are none
+    #[inline]
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+}
+
+/// The last `capacity` samples, also kept sorted to find their median and quartiles.
+pub(crate) struct OrderedWindow {
+    capacity: usize,
+    window: VecDeque<f64>,
+    sorted: Vec<f64>,
+}
+
+impl OrderedWindow {
+    pub fn new(capacity: usize) -> Self {
+        OrderedWindow {
+            capacity,
+            window: VecDeque::with_capacity(capacity),
+            sorted: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn add(&mut self, sample: f64) {
+        if self.window.len() == self.capacity {
+            let oldest = self.window.pop_front().unwrap();
+            let index = self.sorted.partition_point(|&s| s < oldest);
+            self.sorted.remove(index);
+        }
+        self.window.push_back(sample);
+        let index = self.sorted.partition_point(|&s| s < sample);
+        self.sorted.insert(index, sample);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.window.len()
+    }
+
+    /// The sample at quantile `q` (between 0 and 1), rounding to the nearest sample.
+    /// Must not be called on an empty window.
+    pub fn quantile(&self, q: f64) -> f64 {
+        let index = ((self.sorted.len() - 1) as f64 * q).round() as usize;
+        self.sorted[index]
+    }
+
+    #[inline]
+    pub fn median(&self) -> f64 {
+        self.quantile(0.5)
+    }
+}
+
+/// A high dynamic range histogram, as in the HdrHistogram paper.
+///
+/// Values are bucketed by their power of two, and each power of two is subdivided into
+/// `2^significant_bits` sub-buckets, so recorded values are accurate to a relative error of
+/// `2^-significant_bits` whatever their magnitude.
+#[derive(Clone, Debug)]
+pub struct HdrHistogram {
+    /// The counts of the sub-buckets, `2^significant_bits` per bucket
+    buckets: Vec<u64>,
+    significant_bits: u32,
+    min_value: u64,
+    max_value: u64,
+    count: u64,
+    total: u128,
+}
+
+impl HdrHistogram {
+    /// Creates a histogram with `2^significant_bits` sub-buckets per power of two.
+    /// `significant_bits` must be less than 32.
+    pub fn new(significant_bits: u32) -> Self {
+        assert!(significant_bits < 32, "too many significant bits");
+        // bucket 0 holds the values below `2^significant_bits` exactly, and each bucket after
+        // that one power of two
+        let bucket_count = (u64::BITS - significant_bits + 1) as usize;
+        HdrHistogram {
+            buckets: vec![0; bucket_count << significant_bits],
+            significant_bits,
+            min_value: u64::MAX,
+            max_value: 0,
+            count: 0,
+            total: 0,
+        }
+    }
+
+    fn index_of(&self, value: u64) -> usize {
+        let bits = u64::BITS - value.leading_zeros();
+        if bits <= self.significant_bits {
+            return value as usize;
+        }
+        let bucket = bits - self.significant_bits;
+        let sub_bucket = (value >> (bucket - 1)) as usize - (1 << self.significant_bits);
+        ((bucket as usize) << self.significant_bits) + sub_bucket
+    }
+
+    /// The largest value that falls into the sub-bucket at `index`
+    fn highest_value_at(&self, index: usize) -> u64 {
+        let bucket = (index >> self.significant_bits) as u32;
+        if bucket == 0 {
+            return index as u64;
+        }
+        let sub_bucket = (index & ((1 << self.significant_bits) - 1)) as u64;
+        let lowest = ((1 << self.significant_bits) + sub_bucket) << (bucket - 1);
+        lowest + ((1 << (bucket - 1)) - 1)
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let index = self.index_of(value);
+        self.buckets[index] += 1;
+        self.min_value = self.min_value.min(value);
+        self.max_value = self.max_value.max(value);
+        self.count += 1;
+        self.total += u128::from(value);
+    }
+
+    /// The value below which `p` percent of the recorded values fall, 0 if there are none
+    pub fn percentile(&self, p: f64) -> u64 {
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return self
+                    .highest_value_at(index)
+                    .clamp(self.min_value, self.max_value);
+            }
+        }
+        0
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.total as f64 / self.count as f64
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The smallest recorded value, `u64::MAX` if there are none
+    #[inline]
+    pub fn min(&self) -> u64 {
+        self.min_value
+    }
+
+    /// The largest recorded value, 0 if there are none
+    #[inline]
+    pub fn max(&self) -> u64 {
+        self.max_value
+    }
+
+    /// The non-empty sub-buckets, as the largest value in each and its count
+    pub fn iter_recorded(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count != 0)
+            .map(|(index, &count)| (self.highest_value_at(index), count))
+    }
+}
+
+/// An exponentially weighted moving average.
+///
+/// Each sample moves the average `alpha` of the way towards it, so a larger `alpha` (up to 1)
+/// forgets old samples faster.
+#[derive(Clone, Debug)]
+pub struct Ewma {
+    alpha: f64,
+    value: f64,
+    initialized: bool,
+}
+
+impl Ewma {
+    pub fn new(alpha: f64) -> Self {
+        Ewma {
+            alpha,
+            value: 0.0,
+            initialized: false,
+        }
+    }
+
+    /// Adds a sample and returns the new average. The first sample becomes the average.
+    pub fn update(&mut self, sample: f64) -> f64 {
+        if self.initialized {
+            self.value += self.alpha * (sample - self.value);
+        } else {
+            self.value = sample;
+            self.initialized = true;
+        }
+        self.value
+    }
+
+    /// The average, if there have been any samples
+    pub fn current(&self) -> Option<f64> {
+        self.initialized.then_some(self.value)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Variance;
+    use super::{Ewma, HdrHistogram, OrderedWindow, Variance};
 
     #[test]
     fn basic() {
@@ -76,5 +304,66 @@ mod tests {
 
         let expected_mean_error = 2.5019;
         assert!((variance.mean_error() - expected_mean_error).abs() < 0.001);
+
+        let expected_std_dev = 5.5946;
+        assert!((variance.std_dev() - expected_std_dev).abs() < 0.001);
+        assert_eq!(variance.count(), 5);
+        assert_eq!(variance.min(), 5.0);
+        assert_eq!(variance.max(), 20.0);
+    }
+
+    #[test]
+    fn ordered_window() {
+        let mut window = OrderedWindow::new(5);
+        for input in [9.0, 1.0, 5.0, 3.0, 7.0, 100.0, 2.0] {
+            window.add(input);
+        }
+        // 9 and 1 were evicted
+        assert_eq!(window.len(), 5);
+        assert_eq!(window.median(), 5.0);
+        assert_eq!(window.quantile(0.0), 2.0);
+        assert_eq!(window.quantile(0.25), 3.0);
+        assert_eq!(window.quantile(1.0), 100.0);
+    }
+
+    #[test]
+    fn hdr_histogram() {
+        let mut histogram = HdrHistogram::new(3);
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.mean(), 500.5);
+        assert_eq!(histogram.percentile(0.0), 1);
+        assert_eq!(histogram.percentile(100.0), 1000);
+        // within 1/8 of the exact percentiles
+        for (p, exact) in [(50.0, 500), (90.0, 900), (99.0, 990)] {
+            let value = histogram.percentile(p);
+            assert!(value.abs_diff(exact) <= exact / 8, "p{}: {}", p, value);
+        }
+        // exact below 8
+        assert_eq!(
+            histogram.iter_recorded().take(3).collect::<Vec<_>>(),
+            [(1, 1), (2, 1), (3, 1)]
+        );
+        assert_eq!(
+            histogram
+                .iter_recorded()
+                .map(|(_, count)| count)
+                .sum::<u64>(),
+            1000
+        );
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.percentile(100.0), u64::MAX);
+    }
+
+    #[test]
+    fn ewma() {
+        let mut ewma = Ewma::new(0.5);
+        assert_eq!(ewma.current(), None);
+        assert_eq!(ewma.update(8.0), 8.0);
+        assert_eq!(ewma.update(4.0), 6.0);
+        assert_eq!(ewma.update(4.0), 5.0);
+        assert_eq!(ewma.current(), Some(5.0));
     }
 }
@@ -0,0 +1,79 @@
+//! Stand-ins for the Unix signal, pthread and clock APIs on other platforms, such as Windows
+//! and wasm.
+//!
+//! Poll timing needs signals, so there enabling it does nothing, and the wrappers just poll
+//! what they wrap. This mostly lets crates that use pollcatch compile everywhere. On wasm
+//! with the `wasm` feature, the clock is `performance.now()`, so that
+//! `PollTimingLayer::with_long_poll_span` works, with up to millisecond precision.
+
+use crate::PollTimingError;
+use std::{ffi::c_int, io};
+
+#[derive(Copy, Clone)]
+pub struct SavedAction;
+
+pub const SIGPROF: c_int = 0;
+
+fn unsupported() -> PollTimingError {
+    PollTimingError::SigactionFailed(io::ErrorKind::Unsupported.into())
+}
+
+pub fn enable_poll_timing_pthread_key() -> Result<(), PollTimingError> {
+    Ok(())
+}
+
+pub fn enable_poll_timing_signal_handler(
+    _signum: c_int,
+    _on_altstack: bool,
+) -> Result<SavedAction, PollTimingError> {
+    Err(unsupported())
+}
+
+/// No-op, since there are no signals
+pub fn enable_sigaltstack(_stack_size: usize) -> io::Result<()> {
+    Ok(())
+}
+
+/// No-op, since there are no signals
+pub fn disable_sigaltstack() -> io::Result<()> {
+    Ok(())
+}
+
+pub fn disable_poll_timing_signal_handler(
+    _signum: c_int,
+    _oldact: &SavedAction,
+) -> Result<(), PollTimingError> {
+    Err(unsupported())
+}
+
+/// No-op, since there is no `fork`
+pub fn register_fork_handler() {}
+
+/// Always 0, since there is no pthread key
+pub fn read_timestamp_pthread_key() -> usize {
+    0
+}
+
+/// No-op, since there is no pthread key
+pub fn write_timestamp_pthread_key(_time: usize) {}
+
+pub fn hostname() -> Vec<u8> {
+    Vec::new()
+}
+
+pub fn nanotime() -> u64 {
+    if cfg!(all(target_arch = "wasm32", feature = "wasm")) {
+        // in nanoseconds
+        crate::tsc::now()
+    } else {
+        0
+    }
+}
+
+pub fn realtime_ns() -> u64 {
+    0
+}
+
+pub fn gettid() -> u32 {
+    0
+}
@@ -4,13 +4,105 @@ pub fn now() -> u64 {
     _now()
 }
 
-#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+/// Like [`now`], but not reordered with the instructions around it, for the start of a
+/// measured interval. Otherwise the read can run early or late enough that the interval
+/// misses some of the instructions it should cover.
+#[inline]
+pub fn now_serialized() -> u64 {
+    _now_serialized()
+}
+
+/// The ratio of ticks of [`now`] to nanoseconds as `(numerator, denominator)`, on platforms
+/// where it is known exactly rather than having to be calibrated
+#[inline]
+pub fn frequency() -> Option<(u32, u32)> {
+    _frequency()
+}
+
+#[cfg(not(any(target_os = "macos", all(target_arch = "wasm32", feature = "wasm"))))]
+#[inline]
+fn _frequency() -> Option<(u32, u32)> {
+    None
+}
+
+// `performance.now()` is in milliseconds, but browsers coarsen it to somewhere between 5us
+// and 1ms to mitigate timing attacks, so this is best-effort. It needs a `window`, and is 0
+// elsewhere, e.g. in web workers.
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+#[inline]
+fn _now() -> u64 {
+    web_sys::window()
+        .and_then(|window| window.performance())
+        .map_or(0, |performance| (performance.now() * 1_000_000.0) as u64)
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+#[inline]
+fn _frequency() -> Option<(u32, u32)> {
+    // already in nanoseconds
+    Some((1, 1))
+}
+
+// Apple recommends `mach_absolute_time` over reading the counters directly. Its timebase is
+// exact: 1/1 on Intel, 125/3 on Apple Silicon.
+#[cfg(target_os = "macos")]
+mod mach {
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct MachTimebaseInfo {
+        pub numer: u32,
+        pub denom: u32,
+    }
+
+    extern "C" {
+        pub fn mach_absolute_time() -> u64;
+        pub fn mach_timebase_info(info: *mut MachTimebaseInfo) -> libc::c_int;
+    }
+}
+
+#[cfg(target_os = "macos")]
+#[inline]
+fn _now() -> u64 {
+    unsafe { mach::mach_absolute_time() }
+}
+
+#[cfg(target_os = "macos")]
+fn _frequency() -> Option<(u32, u32)> {
+    let mut info = mach::MachTimebaseInfo::default();
+    match unsafe { mach::mach_timebase_info(&mut info) } {
+        0 if info.denom != 0 => Some((info.numer, info.denom)),
+        _ => None,
+    }
+}
+
+#[cfg(all(
+    target_arch = "x86_64",
+    target_feature = "sse2",
+    not(target_os = "macos")
+))]
 #[inline]
 fn _now() -> u64 {
     unsafe { ::core::arch::x86_64::_rdtsc() }
 }
 
-#[cfg(target_arch = "aarch64")]
+// The first `lfence` waits for the earlier instructions to complete before `rdtsc` reads the
+// counter, and the second keeps the later ones from starting before it.
+#[cfg(all(
+    target_arch = "x86_64",
+    target_feature = "sse2",
+    not(target_os = "macos")
+))]
+#[inline]
+fn _now_serialized() -> u64 {
+    unsafe {
+        ::core::arch::x86_64::_mm_lfence();
+        let count = ::core::arch::x86_64::_rdtsc();
+        ::core::arch::x86_64::_mm_lfence();
+        count
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", not(target_os = "macos")))]
 #[inline]
 fn _now() -> u64 {
     let count: u64;
@@ -22,11 +114,75 @@ fn _now() -> u64 {
     count
 }
 
+// `isb` is the aarch64 counterpart of `lfence`, `cntvct_el0` can be read speculatively
+// otherwise
+#[cfg(all(target_arch = "aarch64", not(target_os = "macos")))]
+#[inline]
+fn _now_serialized() -> u64 {
+    let count: u64;
+
+    unsafe {
+        ::core::arch::asm!("isb", "mrs {}, cntvct_el0", "isb", out(reg) count);
+    }
+
+    count
+}
+
+// The `time` CSR counts wall-clock time at a fixed platform frequency rather than CPU cycles,
+// so this reads the same underlying clock as `CLOCK_MONOTONIC`. The calibration still works,
+// it finds a near-exact ratio between the two, and converges quickly since there is no
+// frequency drift to average out.
+#[cfg(target_arch = "riscv64")]
+#[inline]
+fn _now() -> u64 {
+    let count: u64;
+
+    unsafe {
+        ::core::arch::asm!("rdtime {}", out(reg) count, options(nomem, nostack));
+    }
+
+    count
+}
+
+// `mftb` is the extended mnemonic for `mfspr rD, 268`, which reads the whole 64-bit time base
+// in one go on 64-bit processors. On POWER8 and later, the time base is invariant and
+// synchronized across threads. It ticks at a fixed frequency (512 MHz on POWER8 and POWER9,
+// see `timebase` in /proc/cpuinfo), and the kernel derives `CLOCK_MONOTONIC` from it, so the
+// calibration finds the exact ratio.
+#[cfg(target_arch = "powerpc64")]
+#[inline]
+fn _now() -> u64 {
+    let count: u64;
+
+    unsafe {
+        ::core::arch::asm!("mftb {}", out(reg) count, options(nomem, nostack));
+    }
+
+    count
+}
+
 #[cfg(not(any(
     all(target_arch = "x86_64", target_feature = "sse2"),
     target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "powerpc64",
+    target_os = "macos",
+    all(target_arch = "wasm32", feature = "wasm"),
 )))]
 #[inline]
 fn _now() -> u64 {
     0
 }
+
+#[cfg(not(any(
+    all(
+        target_arch = "x86_64",
+        target_feature = "sse2",
+        not(target_os = "macos")
+    ),
+    all(target_arch = "aarch64", not(target_os = "macos")),
+)))]
+#[inline]
+fn _now_serialized() -> u64 {
+    _now()
+}
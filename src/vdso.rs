@@ -0,0 +1,114 @@
+//! Finds `clock_gettime` in the vDSO the kernel maps into every process, following the
+//! kernel's `tools/testing/selftests/vDSO/parse_vdso.c`.
+
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+type ClockGettime = unsafe extern "C" fn(libc::clockid_t, *mut libc::timespec) -> libc::c_int;
+
+#[cfg(any(
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    target_arch = "s390x"
+))]
+const CLOCK_GETTIME: &CStr = c"__kernel_clock_gettime";
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    target_arch = "s390x"
+)))]
+const CLOCK_GETTIME: &CStr = c"__vdso_clock_gettime";
+
+const DT_NULL: i64 = 0;
+const DT_HASH: i64 = 4;
+const DT_STRTAB: i64 = 5;
+const DT_SYMTAB: i64 = 6;
+const STT_FUNC: u8 = 2;
+
+#[repr(C)]
+struct Elf64Dyn {
+    d_tag: i64,
+    d_val: u64,
+}
+
+static VDSO_CLOCK_GETTIME: OnceLock<Option<ClockGettime>> = OnceLock::new();
+
+/// Reads `clock` through the vDSO, or `None` if there is no vDSO `clock_gettime`
+#[inline]
+pub(crate) fn clock_gettime(clock: libc::clockid_t) -> Option<libc::timespec> {
+    let clock_gettime = (*VDSO_CLOCK_GETTIME.get_or_init(|| unsafe { find_clock_gettime() }))?;
+    let mut ts = std::mem::MaybeUninit::uninit();
+    // safety: the vDSO function has the signature of `clock_gettime`
+    unsafe {
+        if clock_gettime(clock, ts.as_mut_ptr()) != 0 {
+            return None;
+        }
+        Some(ts.assume_init())
+    }
+}
+
+/// Looks up the vDSO `clock_gettime` through the dynamic symbol table of the vDSO image.
+///
+/// safety: `AT_SYSINFO_EHDR` has to point at the vDSO, which the kernel guarantees
+unsafe fn find_clock_gettime() -> Option<ClockGettime> {
+    let base = libc::getauxval(libc::AT_SYSINFO_EHDR) as usize;
+    if base == 0 {
+        return None;
+    }
+    let ehdr = &*(base as *const libc::Elf64_Ehdr);
+    let phdrs = std::slice::from_raw_parts(
+        (base + ehdr.e_phoff as usize) as *const libc::Elf64_Phdr,
+        ehdr.e_phnum.into(),
+    );
+    // the vDSO is mapped as a whole, so the offset of its load segment gives the bias
+    // between its virtual addresses and the mapping
+    let load = phdrs.iter().find(|p| p.p_type == libc::PT_LOAD)?;
+    let bias = (base + load.p_offset as usize).wrapping_sub(load.p_vaddr as usize);
+    let dynamic = phdrs.iter().find(|p| p.p_type == libc::PT_DYNAMIC)?;
+
+    let (mut strtab, mut symtab, mut hash) = (None, None, None);
+    let mut dyn_ = (base + dynamic.p_offset as usize) as *const Elf64Dyn;
+    while (*dyn_).d_tag != DT_NULL {
+        let addr = bias.wrapping_add((*dyn_).d_val as usize);
+        match (*dyn_).d_tag {
+            DT_STRTAB => strtab = Some(addr as *const libc::c_char),
+            DT_SYMTAB => symtab = Some(addr as *const libc::Elf64_Sym),
+            DT_HASH => hash = Some(addr as *const u32),
+            _ => {}
+        }
+        dyn_ = dyn_.add(1);
+    }
+    let (strtab, symtab, hash) = (strtab?, symtab?, hash?);
+
+    // the second word of the hash table is the number of symbols
+    let nsyms = *hash.add(1) as usize;
+    std::slice::from_raw_parts(symtab, nsyms)
+        .iter()
+        .find(|sym| {
+            sym.st_shndx != 0
+                && sym.st_info & 0xf == STT_FUNC
+                && CStr::from_ptr(strtab.add(sym.st_name as usize)) == CLOCK_GETTIME
+        })
+        .map(|sym| std::mem::transmute::<usize, ClockGettime>(bias + sym.st_value as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn monotonic() {
+        let Some(vdso) = super::clock_gettime(libc::CLOCK_MONOTONIC) else {
+            // no vDSO, e.g. under some emulators
+            return;
+        };
+        let mut ts = std::mem::MaybeUninit::uninit();
+        let libc = unsafe {
+            assert_eq!(
+                libc::clock_gettime(libc::CLOCK_MONOTONIC, ts.as_mut_ptr()),
+                0
+            );
+            ts.assume_init()
+        };
+        assert!((libc.tv_sec, libc.tv_nsec) >= (vdso.tv_sec, vdso.tv_nsec));
+        assert!(libc.tv_sec - vdso.tv_sec <= 1);
+    }
+}
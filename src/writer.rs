@@ -1,10 +1,26 @@
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::{
+    pr_builder::{truncate_str, RecordBuilder},
+    ring::Ring,
+};
 use std::{
-    io::{BufWriter, Write},
-    sync::mpsc::{RecvError, RecvTimeoutError},
+    fs::OpenOptions,
+    io::{BufWriter, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        mpsc::{RecvError, RecvTimeoutError, SendError},
+        Arc, Condvar, Mutex,
+    },
     time::{Duration, Instant},
 };
 
+/// Written at the start of every PR file, followed by a compression byte
+const PR_MAGIC: [u8; 4] = *b"PCPR";
+const COMPRESSION_NONE: u8 = 0;
+#[cfg(feature = "zstd")]
+const COMPRESSION_ZSTD: u8 = 1;
+
 pub enum Event {
     Poll {
         start: u64,
@@ -14,8 +30,99 @@ pub enum Event {
     },
     /// monotonic time = (tsc-time - src-epoch) * mul >> shift + ref-epoch
     CalibrateTscToMonotonic { data: CalibrationData },
+    /// Start of a new process run, written before any other event of that run
+    SessionStart {
+        session_id: u128,
+        wall_time_ns: u64,
+        pid: u32,
+    },
+    /// The profiled process. Boxed to keep poll events small
+    ProcessInfo { data: Box<ProcessInfoData> },
+    /// The same instant on the TSC, monotonic and realtime clocks
+    WallClockAnchor {
+        tsc: u64,
+        monotonic_ns: u64,
+        realtime_ns: u64,
+    },
+    /// The writer failed and is exiting. `error_code` is the OS error, or 0 if there is none
+    WriterError { error_code: u32 },
+    /// Like `Poll`, for a `poll_ready` of a service wrapped in `PollTimingLayer`
+    ServicePollReady {
+        start: u64,
+        end: u64,
+        clock_end: u64,
+        tid: u32,
+    },
+    /// A profiling signal received during the poll written before it
+    Signal { tsc: u64, tid: u32 },
+    /// The name of a thread, written before its first poll. Null-terminated
+    ThreadName { tid: u32, name: [u8; 16] },
+    /// Like `Poll`, for a poll that returned `Poll::Ready`, which completed the future
+    PollReady {
+        start: u64,
+        end: u64,
+        clock_end: u64,
+        tid: u32,
+    },
+    /// Like `Poll`, for a block timed with `time_block` or a poll of a labeled future
+    LabeledBlock {
+        start: u64,
+        end: u64,
+        clock_end: u64,
+        tid: u32,
+        label: &'static str,
+    },
+    /// An event of the executor on a thread, recorded with `record_executor_event`
+    ExecutorEvent {
+        kind: ExecutorEventKind,
+        tid: u32,
+        tsc: u64,
+    },
+    /// A marker recorded with `annotate`. The message is null-terminated
+    UserAnnotation {
+        timestamp_tsc: u64,
+        tag: u32,
+        message: [u8; 64],
+    },
+    /// The recording ended cleanly, with `stop_performance_writer`. The writer flushes and
+    /// exits after writing it, so files without it at the end were cut short.
+    EndOfSession {
+        session_id: u128,
+        /// The polls a profiling signal landed in
+        total_polls: u64,
+        /// The polls recorded in the file
+        total_long_polls: u64,
+    },
+    /// The CPU, from CPUID or the like. `tsc_hz` is the frequency of the TSC, or 0 if
+    /// unknown, which lets the decoder convert TSC durations without a calibration.
+    /// `cpu_model` is null-terminated, and `cpu_flags` tell whether the TSC is invariant (1),
+    /// whether running under a hypervisor (2) and whether `rdtscp` is available (4)
+    CpuInfo {
+        tsc_hz: u64,
+        cpu_model: [u8; 48],
+        cpu_flags: u64,
+    },
+    /// Not written: makes the writer flush what it has buffered
+    Flush,
 }
 
+/// What happened in an [`Event::ExecutorEvent`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExecutorEventKind {
+    /// The worker thread is going to sleep waiting for work
+    Park = 0,
+    /// The worker thread woke up
+    Unpark = 1,
+    /// A task was spawned
+    SpawnTask = 2,
+    /// A task was dropped
+    DropTask = 3,
+}
+
+/// Labels are truncated to this many bytes
+const MAX_LABEL_LEN: usize = 256;
+
 pub struct CalibrationData {
     pub src_epoch: u64,
     pub ref_epoch: u64,
@@ -23,22 +130,28 @@ pub struct CalibrationData {
     pub shift: u32,
 }
 
-fn write_event(w: &mut impl Write, e: Event) -> std::io::Result<()> {
+/// Strings are null-terminated
+pub struct ProcessInfoData {
+    pub pid: u32,
+    pub hostname: [u8; 64],
+    pub cmdline: [u8; 256],
+    /// When the process started, in clock ticks after boot as in `/proc/<pid>/stat`, or 0 if
+    /// unknown. Tells the process apart from a later one with the same pid.
+    pub start_time: u64,
+}
+
+fn event_record(seq: u64, e: Event) -> RecordBuilder {
     match e {
         Event::Poll {
             start,
             end,
             clock_end,
             tid,
-        } => {
-            w.write_u32::<LittleEndian>(4 + 4 + 8 + 8 + 8 + 4)?; // size
-            w.write_u32::<LittleEndian>(0)?; // 0 for poll
-            w.write_u64::<LittleEndian>(start)?;
-            w.write_u64::<LittleEndian>(end)?;
-            w.write_u64::<LittleEndian>(clock_end)?;
-            w.write_u32::<LittleEndian>(tid)?;
-            Ok(())
-        }
+        } => RecordBuilder::new(0, seq) // 0 for poll
+            .u64(1, start)
+            .u64(2, end)
+            .u64(3, clock_end)
+            .u32(4, tid),
         Event::CalibrateTscToMonotonic {
             data:
                 CalibrationData {
@@ -47,32 +160,279 @@ fn write_event(w: &mut impl Write, e: Event) -> std::io::Result<()> {
                     mul,
                     shift,
                 },
-        } => {
-            w.write_u32::<LittleEndian>(4 + 4 + 8 + 8 + 8 + 4)?; // size
-            w.write_u32::<LittleEndian>(1)?; // 1 for calibrate
-            w.write_u64::<LittleEndian>(src_epoch)?;
-            w.write_u64::<LittleEndian>(ref_epoch)?;
-            w.write_u64::<LittleEndian>(mul)?;
-            w.write_u32::<LittleEndian>(shift)?;
-            Ok(())
+        } => RecordBuilder::new(1, seq) // 1 for calibrate
+            .u64(1, src_epoch)
+            .u64(2, ref_epoch)
+            .u64(3, mul)
+            .u32(4, shift),
+        Event::SessionStart {
+            session_id,
+            wall_time_ns,
+            pid,
+        } => RecordBuilder::new(2, seq) // 2 for session start
+            .u128(1, session_id)
+            .u64(2, wall_time_ns)
+            .u32(3, pid),
+        Event::ProcessInfo { data } => RecordBuilder::new(3, seq) // 3 for process info
+            .u32(1, data.pid)
+            .bytes(2, &data.hostname)
+            .bytes(3, &data.cmdline)
+            .u64(4, data.start_time),
+        Event::WallClockAnchor {
+            tsc,
+            monotonic_ns,
+            realtime_ns,
+        } => RecordBuilder::new(4, seq) // 4 for wall clock anchor
+            .u64(1, tsc)
+            .u64(2, monotonic_ns)
+            .u64(3, realtime_ns),
+        Event::WriterError { error_code } => RecordBuilder::new(5, seq) // 5 for writer error
+            .u32(1, error_code),
+        Event::ServicePollReady {
+            start,
+            end,
+            clock_end,
+            tid,
+        } => RecordBuilder::new(6, seq) // 6 for service poll ready
+            .u64(1, start)
+            .u64(2, end)
+            .u64(3, clock_end)
+            .u32(4, tid),
+        Event::Signal { tsc, tid } => RecordBuilder::new(7, seq) // 7 for signal
+            .u64(1, tsc)
+            .u32(2, tid),
+        Event::ThreadName { tid, name } => RecordBuilder::new(8, seq) // 8 for thread name
+            .u32(1, tid)
+            .bytes(2, &name),
+        Event::PollReady {
+            start,
+            end,
+            clock_end,
+            tid,
+        } => RecordBuilder::new(9, seq) // 9 for poll ready
+            .u64(1, start)
+            .u64(2, end)
+            .u64(3, clock_end)
+            .u32(4, tid),
+        Event::LabeledBlock {
+            start,
+            end,
+            clock_end,
+            tid,
+            label,
+        } => RecordBuilder::new(10, seq) // 10 for labeled block
+            .u64(1, start)
+            .u64(2, end)
+            .u64(3, clock_end)
+            .u32(4, tid)
+            .str(5, truncate_str(label, MAX_LABEL_LEN)),
+        Event::ExecutorEvent { kind, tid, tsc } => {
+            RecordBuilder::new(11, seq) // 11 for executor event
+                .u32(1, kind as u32)
+                .u32(2, tid)
+                .u64(3, tsc)
         }
+        Event::UserAnnotation {
+            timestamp_tsc,
+            tag,
+            message,
+        } => RecordBuilder::new(12, seq) // 12 for user annotation
+            .u64(1, timestamp_tsc)
+            .u32(2, tag)
+            .bytes(3, &message),
+        Event::EndOfSession {
+            session_id,
+            total_polls,
+            total_long_polls,
+        } => RecordBuilder::new(13, seq) // 13 for end of session
+            .u128(1, session_id)
+            .u64(2, total_polls)
+            .u64(3, total_long_polls),
+        Event::CpuInfo {
+            tsc_hz,
+            cpu_model,
+            cpu_flags,
+        } => RecordBuilder::new(14, seq) // 14 for CPU info
+            .u64(1, tsc_hz)
+            .bytes(2, &cpu_model)
+            .u64(3, cpu_flags),
+        Event::Flush => unreachable!("flushes are handled by the writers"),
+    }
+}
+
+/// What the writers receive: an event numbered by its sender, or one to number as it's
+/// written, from the senders of `start_writer_in_memory`
+pub(crate) trait SentEvent: Send + 'static {
+    /// The sequence number of the event, given `next_seq`, the one after the highest written
+    /// so far, and the event
+    fn numbered(self, next_seq: u64) -> (u64, Event);
+}
+
+impl SentEvent for Event {
+    fn numbered(self, next_seq: u64) -> (u64, Event) {
+        (next_seq, self)
+    }
+}
+
+impl SentEvent for (u64, Event) {
+    fn numbered(self, _next_seq: u64) -> (u64, Event) {
+        self
+    }
+}
+
+/// Writes `e` with its sequence number, and sets `*next_seq` past it. Returns whether it
+/// ended the session, after which the writer stops.
+fn write_event(
+    w: &mut impl Write,
+    next_seq: &mut u64,
+    state: &WriterState,
+    e: impl SentEvent,
+) -> std::io::Result<bool> {
+    let (seq, e) = e.numbered(*next_seq);
+    if let Event::Flush = e {
+        w.flush()?;
+        return Ok(false);
+    }
+    let end = matches!(e, Event::EndOfSession { .. });
+    event_record(seq, e).write_to(w)?;
+    *next_seq = (*next_seq).max(seq + 1);
+    state.events_written.fetch_add(1, Ordering::Relaxed);
+    Ok(end)
+}
+
+/// What a writer shares with the handles to it
+#[derive(Default)]
+pub(crate) struct WriterState {
+    /// Records written, some of which may still be buffered
+    pub(crate) events_written: AtomicU64,
+    /// Set once the writer has written an `EndOfSession` and flushed
+    session_ended: (Mutex<bool>, Condvar),
+}
+
+impl WriterState {
+    fn set_session_ended(&self) {
+        *self.session_ended.0.lock().unwrap() = true;
+        self.session_ended.1.notify_all();
+    }
+
+    /// Waits up to `timeout` for the writer to write an `EndOfSession`, returning whether it
+    /// did
+    pub(crate) fn wait_session_ended(&self, timeout: Duration) -> bool {
+        let ended = self.session_ended.0.lock().unwrap();
+        let (ended, _) = self
+            .session_ended
+            .1
+            .wait_timeout_while(ended, timeout, |ended| !*ended)
+            .unwrap();
+        *ended
+    }
+}
+
+/// Number of writers that exited because of an error
+pub(crate) static WRITER_ERRORS: AtomicU64 = AtomicU64::new(0);
+/// How many times a write that timed out is retried before giving up
+pub(crate) static TIMED_OUT_RETRIES: AtomicU32 = AtomicU32::new(3);
+/// How long the writers buffer events before flushing them, in milliseconds
+pub(crate) static FLUSH_INTERVAL_MS: AtomicU64 = AtomicU64::new(1000);
+
+/// `FLUSH_INTERVAL_MS`, read each time it's needed so that a new configuration applies to a
+/// writer that is already running. At least 1ms, since a timeout of 0 would flush after
+/// every event.
+fn flush_interval() -> Duration {
+    Duration::from_millis(FLUSH_INTERVAL_MS.load(Ordering::Relaxed).max(1))
+}
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+fn report_writer_error(e: &std::io::Error) {
+    WRITER_ERRORS.fetch_add(1, Ordering::Relaxed);
+    tracing::error!(message="performance writer error", error=?e);
+}
+
+/// Retries writes that fail with transient errors
+struct RetryingWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> RetryingWriter<W> {
+    fn retry<T>(&mut self, mut op: impl FnMut(&mut W) -> std::io::Result<T>) -> std::io::Result<T> {
+        let mut timeouts = 0;
+        loop {
+            match op(&mut self.inner) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(RETRY_DELAY),
+                Err(e)
+                    if e.kind() == ErrorKind::TimedOut
+                        && timeouts < TIMED_OUT_RETRIES.load(Ordering::Relaxed) =>
+                {
+                    timeouts += 1;
+                    std::thread::sleep(RETRY_DELAY);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for RetryingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.retry(|w| w.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.retry(|w| w.flush())
     }
 }
 
 pub fn writer_fn(
-    rx: std::sync::mpsc::Receiver<Event>,
+    rx: std::sync::mpsc::Receiver<impl SentEvent>,
     f: Box<dyn Write + Send>,
+    state: &WriterState,
+) -> std::io::Result<()> {
+    let mut w = BufWriter::new(RetryingWriter { inner: f });
+    let mut next_seq = 0;
+    let res = write_events(rx, &mut w, &mut next_seq, state);
+    if let Err(e) = &res {
+        // best effort, the file is probably not writable anymore. Numbered after the events
+        // written, since the ones sent after them won't be.
+        let error_code = e.raw_os_error().unwrap_or(0) as u32;
+        write_event(
+            &mut w,
+            &mut next_seq,
+            state,
+            Event::WriterError { error_code },
+        )
+        .and_then(|_| w.flush())
+        .ok();
+    }
+    res
+}
+
+fn write_events(
+    rx: std::sync::mpsc::Receiver<impl SentEvent>,
+    mut w: impl Write,
+    next_seq: &mut u64,
+    state: &WriterState,
 ) -> std::io::Result<()> {
-    let mut w = BufWriter::new(f);
     loop {
-        match rx.recv() {
-            Ok(e) => write_event(&mut w, e)?,
+        let end = match rx.recv() {
+            Ok(e) => write_event(&mut w, next_seq, state, e)?,
             Err(RecvError) => return Ok(()),
+        };
+        if end {
+            w.flush()?;
+            state.set_session_ended();
+            return Ok(());
         }
         let flush_start = Instant::now();
         loop {
-            match rx.recv_timeout(Duration::from_secs(1).saturating_sub(flush_start.elapsed())) {
-                Ok(e) => write_event(&mut w, e)?,
+            match rx.recv_timeout(flush_interval().saturating_sub(flush_start.elapsed())) {
+                Ok(e) => {
+                    if write_event(&mut w, next_seq, state, e)? {
+                        w.flush()?;
+                        state.set_session_ended();
+                        return Ok(());
+                    }
+                }
                 Err(e) => {
                     w.flush()?;
                     match e {
@@ -85,12 +445,575 @@ pub fn writer_fn(
     }
 }
 
-pub(crate) fn start_writer(f: Box<dyn Write + Send>) -> std::sync::mpsc::Sender<Event> {
+fn write_header(w: &mut dyn Write, compression: u8) -> std::io::Result<()> {
+    w.write_all(&PR_MAGIC)?;
+    w.write_all(&[compression])
+}
+
+/// Writes the header of an uncompressed PR file
+#[cfg(feature = "pr-format")]
+pub fn write_pr_header(w: &mut impl Write) -> std::io::Result<()> {
+    write_header(w, COMPRESSION_NONE)
+}
+
+/// Writes `e` as a record with the sequence number `seq`, like the performance writers do.
+/// [`Event::Flush`] writes nothing.
+#[cfg(feature = "pr-format")]
+pub fn write_record(w: &mut impl Write, seq: u64, e: Event) -> std::io::Result<()> {
+    if let Event::Flush = e {
+        return Ok(());
+    }
+    event_record(seq, e).write_to(w)
+}
+
+/// The sending half of a writer's channel
+enum Channel {
+    /// To a writer on a thread of its own
+    Std(std::sync::mpsc::Sender<(u64, Event)>),
+    /// To a writer running as a tokio task
+    #[cfg(feature = "tokio")]
+    Tokio(tokio::sync::mpsc::UnboundedSender<(u64, Event)>),
+}
+
+/// Numbers the events it sends to a writer, so that the ones that never make it show up as
+/// gaps in the sequence numbers. Senders on different threads race between taking a number
+/// and sending, so the writer can receive events slightly out of order.
+pub(crate) struct EventSender {
+    channel: Channel,
+    next_seq: AtomicU64,
+}
+
+impl EventSender {
+    fn new(channel: Channel) -> Self {
+        EventSender {
+            channel,
+            next_seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Sends `e` to the writer, failing if it has exited
+    pub fn send(&self, e: Event) -> Result<(), SendError<Event>> {
+        let seq = match e {
+            // not written, so it doesn't take a number
+            Event::Flush => 0,
+            _ => self.next_seq.fetch_add(1, Ordering::Relaxed),
+        };
+        match &self.channel {
+            Channel::Std(sender) => sender.send((seq, e)).map_err(|e| SendError(e.0 .1)),
+            #[cfg(feature = "tokio")]
+            Channel::Tokio(sender) => sender.send((seq, e)).map_err(|e| SendError(e.0 .1)),
+        }
+    }
+}
+
+/// A running writer
+pub(crate) struct Writer {
+    pub sender: EventSender,
+    pub state: Arc<WriterState>,
+    /// The writer's thread, if it has one of its own and it hasn't been joined, to find out
+    /// how it exited
+    pub thread: Mutex<Option<std::thread::JoinHandle<std::io::Result<()>>>>,
+}
+
+impl Writer {
+    /// Joins the writer's thread if it has exited, or if `exiting`, returning what it
+    /// returned, and resuming its panic if it panicked
+    pub fn join_exited(&self, exiting: bool) -> Option<std::io::Result<()>> {
+        let thread = self
+            .thread
+            .lock()
+            .unwrap()
+            .take_if(|thread| exiting || thread.is_finished())?;
+        Some(
+            thread
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+        )
+    }
+}
+
+fn spawn_writer(
+    run: impl FnOnce(std::sync::mpsc::Receiver<(u64, Event)>, &WriterState) -> std::io::Result<()>
+        + Send
+        + 'static,
+) -> Writer {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let state = Arc::new(WriterState::default());
+    let thread_state = state.clone();
+    let thread = std::thread::spawn(move || {
+        let res = run(rx, &thread_state);
+        if let Err(e) = &res {
+            report_writer_error(e);
+        }
+        res
+    });
+    Writer {
+        sender: EventSender::new(Channel::Std(tx)),
+        state,
+        thread: Mutex::new(Some(thread)),
+    }
+}
+
+pub(crate) fn start_writer(mut f: Box<dyn Write + Send>) -> Writer {
+    spawn_writer(move |rx, state| {
+        write_header(&mut f, COMPRESSION_NONE)?;
+        writer_fn(rx, f, state)
+    })
+}
+
+/// Like `start_writer`, but appends to the PR file at `path`, creating it if needed. Only a
+/// new file gets a header: the records of each run follow those of the previous runs, which
+/// the decoder tells apart by their session start events.
+pub(crate) fn start_writer_append(path: &Path) -> std::io::Result<Writer> {
+    let mut f = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    let mut header = vec![];
+    (&mut f)
+        .take(PR_MAGIC.len() as u64 + 1)
+        .read_to_end(&mut header)?;
+    match &header[..] {
+        [] => write_header(&mut f, COMPRESSION_NONE)?,
+        [magic @ .., COMPRESSION_NONE] if *magic == PR_MAGIC => {}
+        _ => {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "can only append to an uncompressed PR file",
+            ))
+        }
+    }
+    Ok(spawn_writer(move |rx, state| {
+        writer_fn(rx, Box::new(f), state)
+    }))
+}
+
+/// Like `start_writer`, but compresses everything after the header with zstd
+#[cfg(feature = "zstd")]
+pub(crate) fn start_writer_compressed(mut f: Box<dyn Write + Send>) -> Writer {
+    spawn_writer(move |rx, state| {
+        write_header(&mut f, COMPRESSION_ZSTD)?;
+        let encoder = zstd::Encoder::new(f, 0)?.auto_finish();
+        writer_fn(rx, Box::new(encoder), state)
+    })
+}
+
+/// Like `start_writer`, but writes to a `Ring` in `f`
+pub(crate) fn start_writer_ring(
+    f: &std::fs::File,
+    capacity_bytes: usize,
+) -> std::io::Result<Writer> {
+    let mut ring = Ring::new(f, capacity_bytes)?;
+    Ok(spawn_writer(move |rx, state| {
+        for (seq, e) in rx {
+            if let Event::Flush = e {
+                ring.flush()?;
+                continue;
+            }
+            let end = matches!(e, Event::EndOfSession { .. });
+            ring.push(&event_record(seq, e).finish());
+            state.events_written.fetch_add(1, Ordering::Relaxed);
+            if end {
+                ring.flush()?;
+                state.set_session_ended();
+                return Ok(());
+            }
+        }
+        ring.flush()
+    }))
+}
+
+/// Like `start_writer`, but sends each record as a UDP datagram to `addr`, without a header.
+///
+/// Datagrams can be lost or reordered, which the decoder notices from the sequence numbers.
+pub(crate) fn start_writer_udp(addr: SocketAddr) -> std::io::Result<Writer> {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(addr)?;
+    Ok(spawn_writer(move |rx, state| {
+        // every datagram is sent right away, there is nothing to flush
+        let events = rx.into_iter().filter(|(_, e)| !matches!(e, Event::Flush));
+        for (seq, e) in events {
+            let end = matches!(e, Event::EndOfSession { .. });
+            match socket.send(&event_record(seq, e).finish()) {
+                // the collector isn't up, or not anymore. Keep sending in case it comes back.
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {}
+                Err(e) => return Err(e),
+                Ok(_) => {
+                    state.events_written.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            if end {
+                state.set_session_ended();
+                return Ok(());
+            }
+        }
+        Ok(())
+    }))
+}
+
+/// A `Write` that appends to a buffer shared with the caller of `start_writer_in_memory`
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub(crate) fn start_writer_in_memory() -> (std::sync::mpsc::Sender<Event>, Arc<Mutex<Vec<u8>>>) {
+    let buf = Arc::new(Mutex::new(Vec::new()));
     let (tx, rx) = std::sync::mpsc::channel();
-    std::thread::spawn(|| {
-        if let Err(e) = writer_fn(rx, f) {
-            tracing::error!(message="performance writer error", error=?e);
+    let mut f: Box<dyn Write + Send> = Box::new(SharedBuffer(buf.clone()));
+    std::thread::spawn(move || {
+        let res = write_header(&mut f, COMPRESSION_NONE)
+            .and_then(|()| writer_fn(rx, f, &WriterState::default()));
+        if let Err(e) = res {
+            report_writer_error(&e);
+        }
+    });
+    (tx, buf)
+}
+
+/// Like `start_writer`, but writes using tokio's async file I/O from a tokio task. Must be
+/// called from within a tokio runtime.
+///
+/// The task writes what it has received whenever it runs out of events, rather than once a
+/// flush interval. It ends with the session, or with the runtime, which cancels it.
+#[cfg(feature = "tokio")]
+pub(crate) fn start_async_writer(f: tokio::fs::File) -> Writer {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let state = Arc::new(WriterState::default());
+    let task_state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = async_writer_fn(rx, f, &task_state).await {
+            report_writer_error(&e);
         }
     });
-    tx
+    Writer {
+        sender: EventSender::new(Channel::Tokio(tx)),
+        state,
+        thread: Mutex::new(None),
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn async_writer_fn(
+    mut rx: tokio::sync::mpsc::UnboundedReceiver<(u64, Event)>,
+    mut f: tokio::fs::File,
+    state: &WriterState,
+) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut buf = vec![];
+    write_header(&mut buf, COMPRESSION_NONE)?;
+    f.write_all(&buf).await?;
+    buf.clear();
+    let mut next_seq = 0;
+    while let Some(e) = rx.recv().await {
+        let mut end = write_event(&mut buf, &mut next_seq, state, e)?;
+        while !end {
+            match rx.try_recv() {
+                Ok(e) => end = write_event(&mut buf, &mut next_seq, state, e)?,
+                Err(_) => break,
+            }
+        }
+        let res = async {
+            f.write_all(&buf).await?;
+            f.flush().await
+        }
+        .await;
+        buf.clear();
+        if let Err(e) = res {
+            // best effort, the file is probably not writable anymore
+            let error_code = e.raw_os_error().unwrap_or(0) as u32;
+            write_event(
+                &mut buf,
+                &mut next_seq,
+                state,
+                Event::WriterError { error_code },
+            )?;
+            f.write_all(&buf).await.ok();
+            return Err(e);
+        }
+        if end {
+            state.set_session_ended();
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        flush_interval, start_writer, start_writer_in_memory, start_writer_udp, write_events,
+        Event, RetryingWriter, WriterState, COMPRESSION_NONE, FLUSH_INTERVAL_MS, PR_MAGIC,
+    };
+    use crate::pr_builder::RecordBuilder;
+    use std::{
+        io::{self, Write},
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    /// Fails with each of `errors` before succeeding
+    struct FlakyWriter {
+        errors: Vec<io::ErrorKind>,
+        written: Vec<u8>,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.errors.pop() {
+                Some(kind) => Err(kind.into()),
+                None => self.written.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retry() {
+        use io::ErrorKind::{Interrupted, TimedOut, WouldBlock};
+        let mut w = RetryingWriter {
+            inner: FlakyWriter {
+                errors: vec![Interrupted, WouldBlock, TimedOut, TimedOut],
+                written: vec![],
+            },
+        };
+        w.write_all(b"abc").unwrap();
+        assert_eq!(w.inner.written, b"abc");
+
+        let mut w = RetryingWriter {
+            inner: FlakyWriter {
+                errors: vec![TimedOut; 10],
+                written: vec![],
+            },
+        };
+        assert_eq!(w.write_all(b"abc").unwrap_err().kind(), TimedOut);
+    }
+
+    /// Counts its flushes
+    struct FlushCounter(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    // one test, since the interval is shared by all writers
+    #[test]
+    fn flush_interval_changes() {
+        FLUSH_INTERVAL_MS.store(0, Ordering::Relaxed);
+        assert_eq!(flush_interval(), Duration::from_millis(1));
+
+        FLUSH_INTERVAL_MS.store(5_000, Ordering::Relaxed);
+        let flushes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let writer = {
+            let flushes = FlushCounter(flushes.clone());
+            std::thread::spawn(move || {
+                write_events(rx, flushes, &mut 0, &WriterState::default()).unwrap()
+            })
+        };
+        let poll = || Event::Poll {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        };
+        tx.send(poll()).unwrap();
+        // the writer, already waiting to flush 5s after the first event, uses the new interval
+        // from the next one
+        std::thread::sleep(Duration::from_millis(100));
+        FLUSH_INTERVAL_MS.store(10, Ordering::Relaxed);
+        tx.send(poll()).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while flushes.load(Ordering::Relaxed) == 0 {
+            assert!(std::time::Instant::now() < deadline, "never flushed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(tx);
+        writer.join().unwrap();
+        FLUSH_INTERVAL_MS.store(1000, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn in_memory() {
+        let (tx, buf) = start_writer_in_memory();
+        tx.send(Event::Poll {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        })
+        .unwrap();
+        drop(tx);
+        // the writer thread flushes and drops its handle to the buffer once all senders are gone
+        for _ in 0..1000 {
+            if Arc::strong_count(&buf) == 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let mut expected = PR_MAGIC.to_vec();
+        expected.push(COMPRESSION_NONE);
+        expected.extend(
+            RecordBuilder::new(0, 0)
+                .u64(1, 1)
+                .u64(2, 2)
+                .u64(3, 3)
+                .u32(4, 4)
+                .finish(),
+        );
+        assert_eq!(*buf.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn flush() {
+        let (tx, buf) = start_writer_in_memory();
+        tx.send(Event::Poll {
+            start: 1,
+            end: 2,
+            clock_end: 3,
+            tid: 4,
+        })
+        .unwrap();
+        tx.send(Event::Flush).unwrap();
+        // the header is written right away, unbuffered
+        let header_len = PR_MAGIC.len() + 1;
+        // well before the flush interval is up
+        for _ in 0..100 {
+            if buf.lock().unwrap().len() > header_len {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let written = buf.lock().unwrap().len();
+        let record_len = RecordBuilder::new(0, 0)
+            .u64(1, 1)
+            .u64(2, 2)
+            .u64(3, 3)
+            .u32(4, 4)
+            .finish()
+            .len();
+        assert_eq!(written, header_len + record_len);
+    }
+
+    #[test]
+    fn join_after_end_of_session() {
+        let writer = start_writer(Box::new(io::sink()));
+        writer
+            .sender
+            .send(Event::Poll {
+                start: 1,
+                end: 2,
+                clock_end: 3,
+                tid: 4,
+            })
+            .unwrap();
+        writer
+            .sender
+            .send(Event::EndOfSession {
+                session_id: 5,
+                total_polls: 1,
+                total_long_polls: 1,
+            })
+            .unwrap();
+        assert!(writer.state.wait_session_ended(Duration::from_secs(10)));
+        writer.join_exited(true).unwrap().unwrap();
+        assert!(writer.join_exited(true).is_none(), "already joined");
+        assert_eq!(writer.state.events_written.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn udp() {
+        let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let tx = start_writer_udp(collector.local_addr().unwrap())
+            .unwrap()
+            .sender;
+        for start in [1, 2] {
+            tx.send(Event::Poll {
+                start,
+                end: 2,
+                clock_end: 3,
+                tid: 4,
+            })
+            .unwrap();
+            // isn't sent, and doesn't take a sequence number
+            tx.send(Event::Flush).unwrap();
+        }
+        let mut buf = [0; 1500];
+        for (seq, start) in [(0, 1), (1, 2)] {
+            let len = collector.recv(&mut buf).unwrap();
+            let expected = RecordBuilder::new(0, seq)
+                .u64(1, start)
+                .u64(2, 2)
+                .u64(3, 3)
+                .u32(4, 4)
+                .finish();
+            assert_eq!(buf[..len], expected);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_writer_ends_with_session() {
+        let path = std::env::temp_dir().join(format!("pollcatch-async-{}.pr", std::process::id()));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let writer = super::start_async_writer(tokio::fs::File::create(&path).await.unwrap());
+            for e in [
+                Event::Flush,
+                Event::EndOfSession {
+                    session_id: 5,
+                    total_polls: 1,
+                    total_long_polls: 1,
+                },
+            ] {
+                writer.sender.send(e).unwrap();
+            }
+            // the current-thread runtime only runs the task while this one waits
+            while writer.sender.send(Event::Flush).is_ok() {
+                tokio::task::yield_now().await;
+            }
+            assert!(writer.state.wait_session_ended(Duration::ZERO));
+        });
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let mut expected = PR_MAGIC.to_vec();
+        expected.push(COMPRESSION_NONE);
+        expected.extend(
+            RecordBuilder::new(13, 0)
+                .u128(1, 5)
+                .u64(2, 1)
+                .u64(3, 1)
+                .finish(),
+        );
+        assert_eq!(written, expected);
+    }
 }
@@ -0,0 +1,26 @@
+//! Checks that the writers that fail when one is already running do so before touching
+//! their output
+
+#[test]
+fn ring_leaves_file_alone() {
+    pollcatch::start_performance_writer(Box::new(std::io::sink()));
+    let path = std::env::temp_dir().join(format!("pollcatch-running-{}.pr", std::process::id()));
+    std::fs::write(&path, b"keep me").unwrap();
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let Err(err) = pollcatch::start_performance_writer_ring(&f, 4096) else {
+        panic!("a writer is already running");
+    };
+    let contents = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(contents, b"keep me");
+    let Err(err) = pollcatch::start_performance_writer_append(&path) else {
+        panic!("a writer is already running");
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    assert!(!path.exists(), "the file to append to isn't created");
+}
@@ -0,0 +1,35 @@
+//! Checks that the async writer doesn't keep its runtime from shutting down
+
+#![cfg(feature = "tokio")]
+
+use std::{sync::mpsc, time::Duration};
+
+#[test]
+fn runtime_drops() {
+    let path =
+        std::env::temp_dir().join(format!("pollcatch-async-writer-{}.pr", std::process::id()));
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let handle = runtime.block_on(async {
+        let f = tokio::fs::File::create(&path).await.unwrap();
+        pollcatch::start_async_writer(f)
+    });
+    // let the writer start waiting for events
+    std::thread::sleep(Duration::from_millis(100));
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        drop(runtime);
+        tx.send(()).unwrap();
+    });
+    let dropped = rx.recv_timeout(Duration::from_secs(10));
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert!(dropped.is_ok(), "dropping the runtime hung");
+    assert!(written.starts_with(b"PCPR"), "{:?}", written);
+    assert!(
+        handle.flush().is_err(),
+        "the writer is gone with its runtime"
+    );
+}
@@ -0,0 +1,36 @@
+//! Checks that poll timing is disabled in the child of a `fork`, and can be enabled again there
+
+// with `noop`, poll timing is never enabled
+#![cfg(all(unix, not(feature = "noop")))]
+
+#[test]
+fn child_enables_again() {
+    pollcatch::enable_poll_timing(Box::new(std::io::sink())).unwrap();
+    assert!(pollcatch::is_poll_timing_enabled());
+    // safety: the child only runs pollcatch code and exits without unwinding
+    match unsafe { libc::fork() } {
+        -1 => panic!("fork failed: {}", std::io::Error::last_os_error()),
+        0 => {
+            let disabled = !pollcatch::is_poll_timing_enabled();
+            let enabled_again = pollcatch::enable_poll_timing(Box::new(std::io::sink())).is_ok()
+                && pollcatch::is_poll_timing_enabled();
+            // safety: exiting without running the test harness's atexit handlers
+            unsafe { libc::_exit(if disabled && enabled_again { 0 } else { 1 }) }
+        }
+        child => {
+            let mut status = 0;
+            // safety: waiting for our own child
+            assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+            assert!(libc::WIFEXITED(status), "{}", status);
+            assert_eq!(
+                libc::WEXITSTATUS(status),
+                0,
+                "the child saw the wrong state"
+            );
+        }
+    }
+    assert!(
+        pollcatch::is_poll_timing_enabled(),
+        "the parent is unaffected"
+    );
+}
@@ -0,0 +1,23 @@
+//! Checks that poll timing stays disabled when its signal handler can't be installed
+
+// with `noop`, enabling poll timing does nothing and can't fail
+#![cfg(all(unix, not(feature = "noop")))]
+
+#[test]
+fn stays_disabled() {
+    // SIGKILL can't be caught
+    let config = pollcatch::PollTimingConfig::default()
+        .with_signal(libc::SIGKILL)
+        .with_sigaltstack(true);
+    let result = pollcatch::enable_poll_timing_with_config(config, Box::new(std::io::sink()));
+    assert!(matches!(
+        result,
+        Err(pollcatch::PollTimingError::SigactionFailed(_))
+    ));
+    assert!(!pollcatch::is_poll_timing_enabled());
+
+    // and can still be enabled
+    pollcatch::enable_poll_timing(Box::new(std::io::sink())).unwrap();
+    assert!(pollcatch::is_poll_timing_enabled());
+    pollcatch::disable_poll_timing().unwrap();
+}
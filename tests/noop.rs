@@ -0,0 +1,26 @@
+//! Checks that with the `noop` feature, `PollTimingFuture` is only the future it wraps
+
+#![cfg(feature = "noop")]
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use pollcatch::PollTimingFuture;
+
+#[test]
+fn same_size_as_inner() {
+    assert_eq!(
+        std::mem::size_of::<PollTimingFuture<[u8; 3]>>(),
+        std::mem::size_of::<[u8; 3]>()
+    );
+}
+
+#[tokio::test]
+async fn polls_inner() {
+    let counter = Arc::new(AtomicU64::new(0));
+    let n = PollTimingFuture::new_with_counter(async { 42 }, counter.clone()).await;
+    assert_eq!(n, 42);
+    assert_eq!(counter.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,41 @@
+//! Checks that subtracting the overhead of timing a poll from its duration doesn't make it
+//! shorter than it was
+
+// with `noop`, poll timing is never enabled
+#![cfg(all(unix, not(feature = "noop")))]
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+#[test]
+fn poll_not_shortened() {
+    let durations = Arc::new(Mutex::new(Vec::new()));
+    let recorded = durations.clone();
+    let config = pollcatch::PollTimingConfig::default()
+        .with_signal(libc::SIGUSR1)
+        .with_long_poll_callback(move |poll| recorded.lock().unwrap().push(poll.duration));
+    pollcatch::enable_poll_timing_with_config(config, Box::new(std::io::sink())).unwrap();
+    // short scopes, which the overhead is a large part of
+    let mut lengths = Vec::new();
+    for _ in 0..100 {
+        let _guard = pollcatch::PollTimingGuard::start();
+        let start = Instant::now();
+        // safety: pollcatch handles the signal
+        assert_eq!(unsafe { libc::raise(libc::SIGUSR1) }, 0);
+        lengths.push(start.elapsed());
+    }
+    pollcatch::disable_poll_timing().unwrap();
+
+    let durations = durations.lock().unwrap();
+    assert_eq!(durations.len(), lengths.len());
+    for (duration, length) in durations.iter().zip(&lengths) {
+        assert!(
+            duration >= length,
+            "a poll of at least {:?} was recorded as {:?}",
+            length,
+            duration
+        );
+    }
+}
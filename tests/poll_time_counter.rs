@@ -0,0 +1,51 @@
+//! Checks that `PollTimingFuture::new_with_counter` adds up the time of every poll
+
+// with `noop`, polls aren't timed, and off unix there's no clock to time them with
+#![cfg(all(unix, not(feature = "noop")))]
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use pollcatch::PollTimingFuture;
+
+/// Sleeps 10ms in each of its two polls
+struct SlowFuture {
+    polls: u32,
+}
+
+impl Future for SlowFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        std::thread::sleep(Duration::from_millis(10));
+        self.polls += 1;
+        if self.polls == 2 {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[test]
+fn counts_every_poll() {
+    let counter = Arc::new(AtomicU64::new(0));
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(PollTimingFuture::new_with_counter(
+        SlowFuture { polls: 0 },
+        counter.clone(),
+    ));
+    let poll_time = Duration::from_nanos(counter.load(Ordering::Relaxed));
+    assert!(poll_time >= Duration::from_millis(20), "{:?}", poll_time);
+}
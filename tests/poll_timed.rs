@@ -0,0 +1,18 @@
+//! Checks both forms of `poll_timed!`
+
+// with `noop`, labels aren't kept
+#![cfg(not(feature = "noop"))]
+
+#[tokio::test]
+async fn unlabeled() {
+    let future = pollcatch::poll_timed!(async { 1 });
+    assert_eq!(future.label(), None);
+    assert_eq!(future.await, 1);
+}
+
+#[tokio::test]
+async fn labeled() {
+    let future = pollcatch::poll_timed!(label = "fetch", async { 2 },);
+    assert_eq!(future.label(), Some("fetch"));
+    assert_eq!(future.await, 2);
+}
@@ -0,0 +1,48 @@
+//! Checks that enabling poll timing again after disabling it uses the new configuration
+
+// with `noop`, poll timing is never enabled
+#![cfg(all(unix, not(feature = "noop")))]
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Enables poll timing with a callback that counts the long polls in `calls`
+fn enable_counting(calls: &Arc<AtomicUsize>) {
+    let calls = calls.clone();
+    let config = pollcatch::PollTimingConfig::default()
+        .with_signal(libc::SIGUSR1)
+        .with_long_poll_callback(move |_| {
+            calls.fetch_add(1, Ordering::Relaxed);
+        });
+    pollcatch::enable_poll_timing_with_config(config, Box::new(std::io::sink())).unwrap();
+}
+
+/// A scope that the profiler samples
+fn sampled_scope() {
+    let _guard = pollcatch::PollTimingGuard::start();
+    // safety: pollcatch handles the signal
+    assert_eq!(unsafe { libc::raise(libc::SIGUSR1) }, 0);
+}
+
+#[test]
+fn replaces_callback() {
+    let first = Arc::new(AtomicUsize::new(0));
+    let second = Arc::new(AtomicUsize::new(0));
+
+    enable_counting(&first);
+    sampled_scope();
+    assert_eq!(first.load(Ordering::Relaxed), 1);
+    pollcatch::disable_poll_timing().unwrap();
+
+    enable_counting(&second);
+    sampled_scope();
+    assert_eq!(
+        first.load(Ordering::Relaxed),
+        1,
+        "the old callback is still called"
+    );
+    assert_eq!(second.load(Ordering::Relaxed), 1);
+    pollcatch::disable_poll_timing().unwrap();
+}
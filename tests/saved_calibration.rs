@@ -0,0 +1,34 @@
+//! Checks that a calibration saved with `save_calibration` can be loaded back and used
+
+// with `noop`, poll timing is never enabled, so there is no calibration to save
+#![cfg(all(unix, not(feature = "noop")))]
+
+#[test]
+fn save_load_round_trip() {
+    let dir = std::env::temp_dir();
+    let saved = dir.join(format!("pollcatch-saved-cal-{}", std::process::id()));
+    let resaved = dir.join(format!("pollcatch-resaved-cal-{}", std::process::id()));
+
+    pollcatch::enable_poll_timing(Box::new(std::io::sink())).unwrap();
+    pollcatch::save_calibration(&saved).unwrap();
+    pollcatch::disable_poll_timing().unwrap();
+
+    let calibration = pollcatch::load_calibration(&saved).unwrap();
+    let config = pollcatch::PollTimingConfig::default()
+        .with_saved_calibration(calibration)
+        .with_drift_check_interval(None);
+    pollcatch::enable_poll_timing_with_config(config, Box::new(std::io::sink())).unwrap();
+    pollcatch::save_calibration(&resaved).unwrap();
+    pollcatch::disable_poll_timing().unwrap();
+
+    assert_eq!(
+        std::fs::read(&saved).unwrap(),
+        std::fs::read(&resaved).unwrap(),
+        "the loaded calibration is the saved one"
+    );
+    std::fs::remove_file(&saved).unwrap();
+    std::fs::remove_file(&resaved).unwrap();
+
+    let err = pollcatch::load_calibration(&saved).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
@@ -0,0 +1,71 @@
+//! Checks that the signal handler calls the handler it replaced, with the right signature for
+//! both kinds of handlers
+
+// with `noop`, the handler is never replaced
+#![cfg(all(unix, not(feature = "noop")))]
+
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+static PLAIN_CALLS: AtomicUsize = AtomicUsize::new(0);
+/// The `si_signo` the `SA_SIGINFO` handler was passed
+static SIGINFO_SIGNO: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn plain_handler(_sig: libc::c_int) {
+    PLAIN_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+extern "C" fn siginfo_handler(
+    _sig: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _ucontext: *mut libc::c_void,
+) {
+    // safety: the kernel passes a valid siginfo to SA_SIGINFO handlers
+    if let Some(info) = unsafe { info.as_ref() } {
+        SIGINFO_SIGNO.store(info.si_signo, Ordering::Relaxed);
+    }
+}
+
+/// Installs `handler` for `signum` with `flags`
+fn install(signum: libc::c_int, handler: usize, flags: libc::c_int) {
+    // safety: both handlers only touch atomics
+    unsafe {
+        let mut act: libc::sigaction = std::mem::zeroed();
+        act.sa_sigaction = handler;
+        act.sa_flags = flags;
+        assert_eq!(libc::sigaction(signum, &act, std::ptr::null_mut()), 0);
+    }
+}
+
+fn enable_with_signal(signum: libc::c_int) {
+    let config = pollcatch::PollTimingConfig::default().with_signal(signum);
+    pollcatch::enable_poll_timing_with_config(config, Box::new(std::io::sink())).unwrap();
+}
+
+fn raise(signum: libc::c_int) {
+    // safety: pollcatch handles the signal, and the test handlers behind it are installed
+    assert_eq!(unsafe { libc::raise(signum) }, 0);
+}
+
+// one test, since the handlers are process-wide and tests run in parallel
+#[test]
+fn chains_to_previous_handlers() {
+    install(
+        libc::SIGUSR1,
+        plain_handler as extern "C" fn(libc::c_int) as usize,
+        0,
+    );
+    enable_with_signal(libc::SIGUSR1);
+    raise(libc::SIGUSR1);
+    assert_eq!(PLAIN_CALLS.load(Ordering::Relaxed), 1);
+    pollcatch::disable_poll_timing().unwrap();
+
+    install(
+        libc::SIGUSR2,
+        siginfo_handler as extern "C" fn(_, _, _) as usize,
+        libc::SA_SIGINFO,
+    );
+    enable_with_signal(libc::SIGUSR2);
+    raise(libc::SIGUSR2);
+    assert_eq!(SIGINFO_SIGNO.load(Ordering::Relaxed), libc::SIGUSR2);
+    pollcatch::disable_poll_timing().unwrap();
+}